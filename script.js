@@ -1,4 +1,4 @@
-import init, {MidiPlayer } from "./pkg/dynamic_piano_sheet.js";
+import init, {MidiPlayer, PanMode } from "./pkg/dynamic_piano_sheet.js";
 init().then((wasm) => {
   const canvas = document.getElementById('canvas');
   const ctx = canvas.getContext('2d');
@@ -45,6 +45,18 @@ init().then((wasm) => {
     midi_player.set_display_range(display_slider.valueAsNumber);
   });
   
+  const rate_slider = document.getElementById("rate-slider");
+  const rate_label = document.getElementById("rate-label");
+  rate_slider.addEventListener('input', (event) => {
+    midi_player.set_playback_rate(rate_slider.valueAsNumber);
+    rate_label.textContent = Math.round(midi_player.playback_rate() * 100) + "%";
+  });
+  // スピードトレーナーで速さが変わったら表示も合わせる
+  midi_player.set_loop_callback((count, rate) => {
+    rate_slider.value = rate;
+    rate_label.textContent = Math.round(rate * 100) + "%";
+  });
+
   const bar_slider = document.getElementById("bar-slider");
   bar_slider.addEventListener('input', (event) => {
     const bar_number = bar_slider.valueAsNumber;
@@ -72,6 +84,38 @@ init().then((wasm) => {
     load_midi(file);
   });
 
+  const piano_perspective_checkbox = document.getElementById("piano-perspective");
+  piano_perspective_checkbox.addEventListener('change', (event) => {
+    midi_player.set_pan_mode(piano_perspective_checkbox.checked ? PanMode.PianoPerspective : PanMode.Center);
+  });
+
+  const metronome_checkbox = document.getElementById("enable-metronome");
+  metronome_checkbox.addEventListener('change', (event) => {
+    midi_player.set_metronome_enabled(metronome_checkbox.checked);
+  });
+
+  const count_in_select = document.getElementById("count-in-beats");
+  count_in_select.addEventListener('change', (event) => {
+    midi_player.set_count_in_beats(parseInt(count_in_select.value));
+  });
+
+  const effects_preset_select = document.getElementById("effects-preset");
+  effects_preset_select.addEventListener('change', (event) => {
+    midi_player.set_master_effects_preset(effects_preset_select.value);
+  });
+
+  const soundfont_open = document.getElementById("soundfont-open");
+  soundfont_open.addEventListener('change', async (event) => {
+    const file = event.target.files[0];
+    if (!file)
+      return;
+    try{
+      midi_player.load_soundfont(new Uint8Array(await file.arrayBuffer()));
+    }catch(e){
+      alert(e);
+    }
+  });
+
   const play_button = document.getElementById("play-button");
   play_button.addEventListener('click', (event) => {
     if( midi_player.ready())
@@ -145,11 +189,7 @@ init().then((wasm) => {
   }
 
   let animationId = null;
-  let lastTime = 0;
-  const renderLoop = async (time) => {
-    if (!lastTime)
-      lastTime = time;
-    
+  const renderLoop = async () => {
     // MIDIファイルの読み込み要求があればここで処理、読み込み中に割り込みでrenderloopが回るとmidi_playerが例外を発生することがあるので
     if (requested_midi_file !== null) {
       const file = requested_midi_file;
@@ -158,14 +198,15 @@ init().then((wasm) => {
         bar_slider.max = midi_player.num_bars() - 1;
         loop_start_bar_input.max = midi_player.num_bars();
         loop_end_bar_input.max = midi_player.num_bars();
+        // ループは読み込んだ曲の小節で設定し直す
+        update_loop_settings();
       }).catch((err) => {
         alert("MIDIファイルの読み込みに失敗しました");
       });
     }
 
-    const deltaTime = time - lastTime;
-    lastTime = time;
-    midi_player.tick(deltaTime);
+    // 再生位置はAudioContextの時計から決まるのでフレームの間隔は渡さない
+    midi_player.tick();
     midi_player.render(ctx, 0, 0, canvas.width, canvas.height);
 
     bar_slider.value = midi_player.current_bar();
@@ -0,0 +1,523 @@
+use crate::bar::Bar;
+use crate::note::Note;
+use crate::tempo_map::TempoMap;
+use std::collections::HashMap;
+
+// ABC記譜法の読み込み
+// 繰り返し記号は展開せず、書かれている順にそのまま並べる
+
+const DEFAULT_VELOCITY: u8 = 80;
+// Z<n> の小節数の上限 (壊れた入力で延々と小節を作らないように)
+const MAX_MULTI_BAR_REST: f64 = 1000.0;
+
+struct Voice{
+    track: u8,
+    position: f64,
+    velocity: u8,
+    // 小節内の臨時記号 (ナチュラルのキー -> 変化量)
+    accidentals: HashMap<u8, i32>,
+    tied_notes: HashMap<u8, usize>,
+    // 付点リズム(> <)で次の音符に掛ける倍率
+    next_ratio: f64,
+    // 連符 (残り音符数, 倍率)
+    tuplet: Option<(u32, f64)>,
+    // 直前の音符 (開始位置, 長さ, ノート番号)
+    last_element: Option<(f64, f64, Vec<usize>)>,
+}
+
+impl Voice{
+    fn new(track: u8) -> Self{
+        Voice{
+            track,
+            position: 0.0,
+            velocity: DEFAULT_VELOCITY,
+            accidentals: HashMap::new(),
+            tied_notes: HashMap::new(),
+            next_ratio: 1.0,
+            tuplet: None,
+            last_element: None,
+        }
+    }
+}
+
+struct QuarterNote{
+    on: f64,
+    off: f64,
+    key: u8,
+    velocity: u8,
+    track: u8,
+}
+
+struct AbcParser{
+    // 単位音長 (四分音符単位)
+    unit_length: Option<f64>,
+    // 一小節の長さ (四分音符単位)
+    meter: f64,
+    time_signature: (u8, u8),
+    // 調号 (C,D,E,F,G,A,B の変化量)
+    key_signature: [i32; 7],
+    tempo_points: Vec<(f64, f64)>,
+    voices: Vec<(String, Voice)>,
+    current_voice: usize,
+    // (位置, 拍子)
+    bar_lines: Vec<(f64, (u8, u8))>,
+    notes: Vec<QuarterNote>,
+}
+
+fn parse_fraction(text: &str) -> Option<f64>{
+    let (num, den) = text.trim().split_once('/')?;
+    let num: f64 = num.trim().parse().ok()?;
+    let den: f64 = den.trim().parse().ok()?;
+    (den > 0.0).then_some(num / den)
+}
+
+fn letter_index(letter: char) -> Option<usize>{
+    "CDEFGAB".find(letter.to_ascii_uppercase())
+}
+
+fn parse_key_signature(text: &str) -> [i32; 7]{
+    const TONIC_FIFTHS: [i32; 7] = [0, 2, 4, -1, 1, 3, 5];
+    const SHARP_ORDER: [usize; 7] = [3, 0, 4, 1, 5, 2, 6]; // F C G D A E B
+
+    let text = text.trim();
+    let mut chars = text.chars().peekable();
+    let Some(tonic) = chars.next().and_then(letter_index) else{
+        return [0; 7];
+    };
+    let mut fifths = TONIC_FIFTHS[tonic];
+    match chars.peek(){
+        Some('#') => { fifths += 7; chars.next(); },
+        Some('b') => { fifths -= 7; chars.next(); },
+        _ => (),
+    }
+    let mode: String = chars.skip_while(|c| c.is_whitespace()).take_while(|c| c.is_alphabetic()).collect::<String>().to_lowercase();
+    fifths += match mode.get(..3).unwrap_or(mode.as_str()){
+        "m" | "min" | "aeo" => -3,
+        "dor" => -2,
+        "phr" => -4,
+        "lyd" => 1,
+        "mix" => -1,
+        "loc" => -5,
+        _ => 0,
+    };
+
+    let mut signature = [0; 7];
+    if fifths > 0{
+        for &index in SHARP_ORDER.iter().take(fifths.min(7) as usize){
+            signature[index] = 1;
+        }
+    }else{
+        for &index in SHARP_ORDER.iter().rev().take((-fifths).min(7) as usize){
+            signature[index] = -1;
+        }
+    }
+    signature
+}
+
+fn dynamics_to_velocity(name: &str) -> Option<u8>{
+    match name{
+        "pppp" => Some(20),
+        "ppp" => Some(30),
+        "pp" => Some(45),
+        "p" => Some(60),
+        "mp" => Some(75),
+        "mf" => Some(90),
+        "f" => Some(105),
+        "ff" => Some(120),
+        "fff" | "ffff" => Some(127),
+        _ => None,
+    }
+}
+
+impl AbcParser{
+    fn new() -> Self{
+        AbcParser{
+            unit_length: None,
+            meter: 4.0,
+            time_signature: (4, 4),
+            key_signature: [0; 7],
+            tempo_points: Vec::new(),
+            voices: Vec::new(),
+            current_voice: 0,
+            bar_lines: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    fn unit_length(&self) -> f64{
+        // 指定がなければ拍子から決める
+        self.unit_length.unwrap_or(if self.meter < 3.0 { 0.25 } else { 0.5 })
+    }
+
+    fn voice(&mut self) -> &mut Voice{
+        if self.voices.is_empty(){
+            self.voices.push((String::new(), Voice::new(0)));
+        }
+        &mut self.voices[self.current_voice].1
+    }
+
+    fn select_voice(&mut self, id: &str){
+        let id = id.split_whitespace().next().unwrap_or("").to_string();
+        self.current_voice = match self.voices.iter().position(|(name, _)| *name == id){
+            Some(index) => index,
+            None => {
+                // 声部指定より前に書かれた無名の声部はそのまま最初のトラックとして扱う
+                if self.voices.len() == 1 && self.voices[0].0.is_empty(){
+                    self.voices[0].0 = id;
+                    return;
+                }
+                let track = self.voices.len() as u8;
+                self.voices.push((id, Voice::new(track)));
+                self.voices.len() - 1
+            },
+        };
+    }
+
+    fn field(&mut self, name: char, value: &str){
+        let value = value.trim();
+        match name{
+            'L' => self.unit_length = parse_fraction(value).map(|l| l * 4.0),
+            'M' => {
+                let time_signature = match value{
+                    "C" => Some((4, 4)),
+                    "C|" => Some((2, 2)),
+                    _ => value.split_whitespace().next().and_then(|m| m.split_once('/')).and_then(|(num, den)| Some((num.parse().ok()?, den.parse().ok()?))),
+                };
+                if let Some((num, den)) = time_signature.filter(|&(num, den): &(u8, u8)| num > 0 && den > 0){
+                    self.time_signature = (num, den);
+                    self.meter = num as f64 * 4.0 / den as f64;
+                }
+            },
+            'K' => self.key_signature = parse_key_signature(value),
+            'Q' => {
+                let bpm = match value.rsplit_once('='){
+                    Some((beats, bpm)) => {
+                        let beat: f64 = beats.split_whitespace().filter_map(parse_fraction).sum();
+                        bpm.trim().parse::<f64>().ok().map(|bpm| bpm * if beat > 0.0 { beat * 4.0 } else { self.unit_length() })
+                    },
+                    None => value.parse::<f64>().ok().map(|bpm| bpm * self.unit_length()),
+                };
+                if let Some(bpm) = bpm.filter(|&bpm| bpm > 0.0){
+                    let position = self.voices.get(self.current_voice).map(|(_, v)| v.position).unwrap_or(0.0);
+                    self.tempo_points.push((position, bpm));
+                }
+            },
+            'V' => self.select_voice(value),
+            _ => (),
+        }
+    }
+
+    fn bar_line(&mut self){
+        let voice = self.voice();
+        voice.accidentals.clear();
+        let position = voice.position;
+        if voice.track == 0 && self.bar_lines.last().is_none_or(|&(last, _)| last < position){
+            self.bar_lines.push((position, self.time_signature));
+        }
+    }
+
+    // 音符1つ分を読む (臨時記号, 音名, オクターブ記号)
+    // 休符の場合はキーがNone
+    fn parse_pitch(&mut self, chars: &[char], i: &mut usize) -> Option<Option<u8>>{
+        let mut accidental: Option<i32> = None;
+        while let Some(&c) = chars.get(*i){
+            match c{
+                '^' => accidental = Some(accidental.unwrap_or(0) + 1),
+                '_' => accidental = Some(accidental.unwrap_or(0) - 1),
+                '=' => accidental = Some(0),
+                _ => break,
+            }
+            *i += 1;
+        }
+        let letter = *chars.get(*i)?;
+        if matches!(letter, 'z' | 'x'){
+            *i += 1;
+            return Some(None);
+        }
+        let index = letter_index(letter).filter(|_| letter.is_ascii_alphabetic())?;
+        *i += 1;
+
+        const NATURAL_KEYS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+        let mut key = 60 + NATURAL_KEYS[index] + if letter.is_ascii_lowercase() { 12 } else { 0 };
+        while let Some(&c) = chars.get(*i){
+            match c{
+                '\'' => key += 12,
+                ',' => key -= 12,
+                _ => break,
+            }
+            *i += 1;
+        }
+
+        let natural_key = key.clamp(0, 127) as u8;
+        let key_signature = self.key_signature[index];
+        let voice = self.voice();
+        let alter = match accidental{
+            Some(alter) => {
+                voice.accidentals.insert(natural_key, alter);
+                alter
+            },
+            None => voice.accidentals.get(&natural_key).copied().unwrap_or(key_signature),
+        };
+        Some(u8::try_from(key + alter).ok().filter(|&key| key < 128))
+    }
+
+    fn parse_length(chars: &[char], i: &mut usize) -> f64{
+        let read_number = |i: &mut usize| -> Option<f64>{
+            let begin = *i;
+            while chars.get(*i).is_some_and(|c| c.is_ascii_digit()){
+                *i += 1;
+            }
+            chars[begin..*i].iter().collect::<String>().parse().ok()
+        };
+        let num = read_number(i).unwrap_or(1.0);
+        let mut slashes = 0;
+        while chars.get(*i) == Some(&'/'){
+            slashes += 1;
+            *i += 1;
+        }
+        let den = match (slashes, read_number(i)){
+            (0, _) => 1.0,
+            (_, Some(den)) => den,
+            (n, None) => 2f64.powi(n),
+        };
+        num / den
+    }
+
+    fn add_element(&mut self, keys: &[u8], length: f64, tie: bool){
+        self.voice();
+        let unit_length = self.unit_length();
+        let voice = &mut self.voices[self.current_voice].1;
+        let mut duration = length * unit_length * voice.next_ratio;
+        voice.next_ratio = 1.0;
+        if let Some((remain, ratio)) = voice.tuplet{
+            duration *= ratio;
+            voice.tuplet = (remain > 1).then_some((remain - 1, ratio));
+        }
+
+        let onset = voice.position;
+        let previous_ties = std::mem::take(&mut voice.tied_notes);
+        let mut ids = Vec::new();
+        for &key in keys{
+            let id = match previous_ties.get(&key){
+                Some(&id) => {
+                    self.notes[id].off = onset + duration;
+                    id
+                },
+                None => {
+                    self.notes.push(QuarterNote{ on: onset, off: onset + duration, key, velocity: voice.velocity, track: voice.track });
+                    self.notes.len() - 1
+                },
+            };
+            if tie{
+                voice.tied_notes.insert(key, id);
+            }
+            ids.push(id);
+        }
+        voice.position += duration;
+        voice.last_element = Some((onset, duration, ids));
+    }
+
+    // 付点リズム: 直前の音符を伸ばして(縮めて)次の音符を縮める(伸ばす)
+    fn broken_rhythm(&mut self, count: i32, longer_first: bool){
+        let short = 0.5f64.powi(count);
+        self.voice();
+        let voice = &mut self.voices[self.current_voice].1;
+        let Some((onset, duration, ids)) = voice.last_element.take() else{
+            return;
+        };
+        let first_ratio = if longer_first { 2.0 - short } else { short };
+        let new_duration = duration * first_ratio;
+        for id in ids.iter(){
+            self.notes[*id].off = onset + new_duration;
+        }
+        voice.position = onset + new_duration;
+        voice.next_ratio = if longer_first { short } else { 2.0 - short };
+    }
+
+    fn parse_body_line(&mut self, line: &str){
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len(){
+            let c = chars[i];
+            match c{
+                '%' => break,
+                '"' => {
+                    i += 1 + chars[i + 1..].iter().position(|&c| c == '"').unwrap_or(chars.len());
+                },
+                '!' | '+' => {
+                    let end = chars[i + 1..].iter().position(|&d| d == c).map(|p| i + 1 + p).unwrap_or(chars.len());
+                    let decoration: String = chars[(i + 1).min(end)..end].iter().collect();
+                    if let Some(velocity) = dynamics_to_velocity(&decoration){
+                        self.voice().velocity = velocity;
+                    }
+                    i = end;
+                },
+                '{' => {
+                    // 装飾音は無視
+                    i += chars[i..].iter().position(|&c| c == '}').unwrap_or(chars.len());
+                },
+                '|' | ':' => {
+                    if c == ':' && !matches!(chars.get(i + 1), Some(':') | Some('|')){
+                        i += 1;
+                        continue;
+                    }
+                    while chars.get(i + 1).is_some_and(|c| matches!(c, '|' | ':' | ']' | '0'..='9')){
+                        i += 1;
+                    }
+                    self.bar_line();
+                },
+                '[' => {
+                    match (chars.get(i + 1), chars.get(i + 2)){
+                        (Some('|'), _) => {
+                            i += 1;
+                            self.bar_line();
+                        },
+                        (Some(d), _) if d.is_ascii_digit() => {
+                            i += 1;
+                        },
+                        (Some(&name), Some(':')) if name.is_ascii_alphabetic() => {
+                            // インラインフィールド [K:G] など
+                            let end = chars[i..].iter().position(|&c| c == ']').map(|p| i + p).unwrap_or(chars.len());
+                            let value: String = chars[i + 3..end].iter().collect();
+                            self.field(name, &value);
+                            i = end;
+                        },
+                        _ => {
+                            // 和音
+                            i += 1;
+                            let mut keys = Vec::new();
+                            let mut length = None;
+                            while i < chars.len() && chars[i] != ']'{
+                                match self.parse_pitch(&chars, &mut i){
+                                    Some(key) => {
+                                        let note_length = Self::parse_length(&chars, &mut i);
+                                        length.get_or_insert(note_length);
+                                        keys.extend(key);
+                                    },
+                                    None => i += 1,
+                                }
+                            }
+                            i += 1;
+                            let length = length.unwrap_or(1.0) * Self::parse_length(&chars, &mut i);
+                            let tie = chars.get(i) == Some(&'-');
+                            self.add_element(&keys, length, tie);
+                            continue;
+                        },
+                    }
+                },
+                '(' => {
+                    let mut j = i + 1;
+                    let p = Self::parse_length(&chars, &mut j);
+                    if j > i + 1 && p >= 2.0{
+                        let q = match p as u32{
+                            2 | 4 | 8 => 3.0,
+                            3 | 6 => 2.0,
+                            _ => if (self.meter * 2.0) % 3.0 == 0.0 && self.meter > 3.0 { 3.0 } else { 2.0 },
+                        };
+                        self.voice().tuplet = Some((p as u32, q / p));
+                        i = j;
+                        continue;
+                    }
+                },
+                '>' | '<' => {
+                    let count = chars[i..].iter().take_while(|&&d| d == c).count();
+                    self.broken_rhythm(count as i32, c == '>');
+                    i += count;
+                    continue;
+                },
+                'Z' => {
+                    // 複数小節休符
+                    i += 1;
+                    let bars = Self::parse_length(&chars, &mut i).clamp(1.0, MAX_MULTI_BAR_REST) as u32;
+                    let meter = self.meter;
+                    for n in 0..bars{
+                        self.voice().position += meter;
+                        if n + 1 < bars{
+                            self.bar_line();
+                        }
+                    }
+                    continue;
+                },
+                _ => {
+                    let begin = i;
+                    match self.parse_pitch(&chars, &mut i){
+                        Some(key) => {
+                            let length = Self::parse_length(&chars, &mut i);
+                            let tie = chars.get(i) == Some(&'-');
+                            let keys: Vec<u8> = key.into_iter().collect();
+                            self.add_element(&keys, length, tie);
+                            continue;
+                        },
+                        None => i = begin,
+                    }
+                },
+            }
+            i += 1;
+        }
+    }
+}
+
+pub fn parse_abc(text: &str) -> Result<(Vec<Bar>, Vec<Note>, u8), String>{
+    let mut parser = AbcParser::new();
+    let mut in_body = false;
+    let mut found_tune = false;
+
+    for line in text.lines(){
+        let trimmed = line.trim();
+        if trimmed.is_empty(){
+            // 1曲目だけ読む
+            if in_body{
+                break;
+            }
+            continue;
+        }
+        if trimmed.starts_with('%'){
+            continue;
+        }
+
+        let mut chars = trimmed.chars();
+        if let (Some(name), Some(':')) = (chars.next(), chars.next()) && name.is_ascii_alphabetic(){
+            if name == 'X'{
+                if found_tune{
+                    break;
+                }
+                found_tune = true;
+            }
+            parser.field(name, &trimmed[2..]);
+            if name == 'K'{
+                in_body = true;
+            }
+            continue;
+        }
+
+        if in_body{
+            parser.parse_body_line(trimmed);
+        }
+    }
+
+    if parser.voices.is_empty(){
+        return Err("ABCに音符が見つかりません".to_string());
+    }
+
+    let end_position = parser.voices.iter().map(|(_, voice)| voice.position).fold(0.0, f64::max);
+    let mut bar_lines = parser.bar_lines.clone();
+    if bar_lines.first().is_none_or(|&(first, _)| first > 0.0){
+        let time_signature = bar_lines.first().map(|&(_, time_signature)| time_signature).unwrap_or(parser.time_signature);
+        bar_lines.insert(0, (0.0, time_signature));
+    }
+    if bar_lines.last().is_some_and(|&(last, _)| last < end_position){
+        bar_lines.push((end_position, parser.time_signature));
+    }
+
+    let tempo_map = TempoMap::new(parser.tempo_points);
+    let bars = bar_lines.windows(2).enumerate().map(|(i, w)|{
+        let mut bar = Bar::new(tempo_map.to_sec(w[0].0), tempo_map.to_sec(w[1].0), i as u32);
+        bar.set_time_signature(w[0].1.0, w[0].1.1);
+        bar.set_tempo(tempo_map.bpm_at(w[0].0));
+        bar
+    }).collect();
+    parser.notes.sort_by(|a, b| a.on.total_cmp(&b.on));
+    let notes = parser.notes.iter().map(|note| Note::new(tempo_map.to_sec(note.on), tempo_map.to_sec(note.off), note.key, note.velocity, note.track)).collect();
+
+    Ok((bars, notes, parser.voices.len() as u8))
+}
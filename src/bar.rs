@@ -1,8 +1,15 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Bar{
     begin_time: f64,
     end_time: f64,
     number: u32,
+    numerator: u8,
+    denominator: u8,
+    tempo: f64,
 }
 
 impl Bar{
@@ -11,6 +18,9 @@ impl Bar{
             begin_time,
             end_time,
             number,
+            numerator: 4,
+            denominator: 4,
+            tempo: 120.0,
         }
     }
     pub fn begin_time(&self) -> f64{
@@ -25,4 +35,23 @@ impl Bar{
     pub fn number(&self) -> u32{
         self.number
     }
+    pub fn time_signature(&self) -> (u8, u8){
+        (self.numerator, self.denominator)
+    }
+    pub fn set_time_signature(&mut self, numerator: u8, denominator: u8){
+        self.numerator = numerator;
+        self.denominator = denominator;
+    }
+    // 小節の頭から beat 拍目 (0から) の時刻 (小節の中ではテンポは変わらないものとする)
+    pub fn beat_time(&self, beat: f64) -> f64{
+        let beats = self.numerator.max(1) as f64;
+        self.begin_time + (self.end_time - self.begin_time) * beat.clamp(0.0, beats) / beats
+    }
+    // 小節の頭のテンポ(BPM)
+    pub fn tempo(&self) -> f64{
+        self.tempo
+    }
+    pub fn set_tempo(&mut self, tempo: f64){
+        self.tempo = tempo;
+    }
 }
\ No newline at end of file
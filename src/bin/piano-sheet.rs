@@ -0,0 +1,221 @@
+// サーバー上で曲ライブラリをまとめてチェックするためのコマンドラインツール
+//
+//   piano-sheet inspect [--json] [--no-bars] <file.mid>...
+
+use dynamic_piano_sheet::{parse_midi_with_report, Bar, Note, ParseReport};
+use std::process::ExitCode;
+
+const USAGE: &str = "usage: piano-sheet inspect [--json] [--no-bars] <file.mid>...";
+
+struct Options{
+    json: bool,
+    bars: bool,
+    files: Vec<String>,
+}
+
+struct TrackSummary{
+    name: String,
+    num_notes: usize,
+    key_range: Option<(u8, u8)>,
+}
+
+fn key_range<'a>(notes: impl Iterator<Item = &'a Note>) -> Option<(u8, u8)>{
+    notes.fold(None, |range, note| match range{
+        None => Some((note.key(), note.key())),
+        Some((min, max)) => Some((min.min(note.key()), max.max(note.key()))),
+    })
+}
+
+fn summarize_tracks(notes: &[Note], num_tracks: u8, report: &ParseReport) -> Vec<TrackSummary>{
+    (0..num_tracks).map(|track|{
+        let track_notes = || notes.iter().filter(move |note| note.track() == track);
+        TrackSummary{
+            name: report.track_names.get(track as usize).cloned().unwrap_or_default(),
+            num_notes: track_notes().count(),
+            key_range: key_range(track_notes()),
+        }
+    }).collect()
+}
+
+fn format_duration(sec: f64) -> String{
+    format!("{}:{:04.1}", (sec / 60.0).floor() as u64, sec % 60.0)
+}
+
+fn format_range(range: Option<(u8, u8)>) -> String{
+    match range{
+        Some((min, max)) => format!("{}-{}", Note::midi_key_to_note_name(min), Note::midi_key_to_note_name(max)),
+        None => "-".to_string(),
+    }
+}
+
+fn print_text(path: &str, bars: &[Bar], notes: &[Note], num_tracks: u8, report: &ParseReport, options: &Options){
+    let duration = bars.last().map(|bar| bar.end_time()).unwrap_or(0.0);
+    println!("{}", path);
+    println!("  duration: {} ({:.3}s), {} bars, {} tracks, {} notes", format_duration(duration), duration, bars.len(), num_tracks, notes.len());
+    println!("  key range: {}", format_range(key_range(notes.iter())));
+
+    println!("  tracks:");
+    for (i, track) in summarize_tracks(notes, num_tracks, report).iter().enumerate(){
+        println!("    {:>2} {:<24} {:>6} notes  {}", i, format!("\"{}\"", track.name), track.num_notes, format_range(track.key_range));
+    }
+
+    println!("  tempos:");
+    for (time, bpm) in report.tempo_changes.iter(){
+        println!("    {:>9.3}s  {:.2} BPM", time, bpm);
+    }
+
+    if options.bars{
+        println!("  bars:");
+        for bar in bars.iter(){
+            let (numerator, denominator) = bar.time_signature();
+            println!("    {:>4}  {:>9.3}s - {:>9.3}s  {:>2}/{:<2}  {:.2} BPM", bar.number() + 1, bar.begin_time(), bar.end_time(), numerator, denominator, bar.tempo());
+        }
+    }
+
+    if !report.warnings.is_empty(){
+        println!("  warnings:");
+        for warning in report.warnings.iter(){
+            println!("    {}", warning);
+        }
+    }
+}
+
+fn json_string(s: &str) -> String{
+    let mut out = String::from("\"");
+    for c in s.chars(){
+        match c{
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// JSONには無限大やNaNがないので null にする
+fn json_number(x: f64) -> String{
+    if x.is_finite() { x.to_string() } else { "null".to_string() }
+}
+
+fn json_range(range: Option<(u8, u8)>) -> String{
+    match range{
+        Some((min, max)) => format!("{{\"min\":{},\"max\":{}}}", min, max),
+        None => "null".to_string(),
+    }
+}
+
+fn to_json(path: &str, bars: &[Bar], notes: &[Note], num_tracks: u8, report: &ParseReport, options: &Options) -> String{
+    let duration = bars.last().map(|bar| bar.end_time()).unwrap_or(0.0);
+    let tracks: Vec<String> = summarize_tracks(notes, num_tracks, report).iter().map(|track|
+        format!("{{\"name\":{},\"notes\":{},\"key_range\":{}}}", json_string(&track.name), track.num_notes, json_range(track.key_range))
+    ).collect();
+    let tempos: Vec<String> = report.tempo_changes.iter().map(|(time, bpm)| format!("{{\"time\":{},\"bpm\":{}}}", json_number(*time), json_number(*bpm))).collect();
+    let warnings: Vec<String> = report.warnings.iter().map(|warning| json_string(warning)).collect();
+    let mut json = format!(
+        "{{\"file\":{},\"ok\":true,\"duration\":{},\"num_bars\":{},\"num_notes\":{},\"key_range\":{},\"tracks\":[{}],\"tempos\":[{}],\"warnings\":[{}]",
+        json_string(path), json_number(duration), bars.len(), notes.len(), json_range(key_range(notes.iter())), tracks.join(","), tempos.join(","), warnings.join(","),
+    );
+    if options.bars{
+        let bars: Vec<String> = bars.iter().map(|bar|{
+            let (numerator, denominator) = bar.time_signature();
+            format!("{{\"number\":{},\"begin\":{},\"end\":{},\"time_signature\":[{},{}],\"bpm\":{}}}", bar.number() + 1, json_number(bar.begin_time()), json_number(bar.end_time()), numerator, denominator, json_number(bar.tempo()))
+        }).collect();
+        json.push_str(&format!(",\"bars\":[{}]", bars.join(",")));
+    }
+    json.push('}');
+    json
+}
+
+fn inspect(options: &Options) -> bool{
+    let mut all_ok = true;
+    for path in options.files.iter(){
+        let result = std::fs::read(path).map_err(|e| e.to_string()).and_then(|data| parse_midi_with_report(&data));
+        match result{
+            Ok((bars, notes, num_tracks, report)) => {
+                if options.json{
+                    println!("{}", to_json(path, &bars, &notes, num_tracks, &report, options));
+                }else{
+                    print_text(path, &bars, &notes, num_tracks, &report, options);
+                }
+            },
+            Err(e) => {
+                all_ok = false;
+                if options.json{
+                    println!("{{\"file\":{},\"ok\":false,\"error\":{}}}", json_string(path), json_string(&e));
+                }else{
+                    eprintln!("{}: {}", path, e);
+                }
+            },
+        }
+    }
+    all_ok
+}
+
+fn main() -> ExitCode{
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() != Some("inspect"){
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    }
+
+    let mut options = Options{
+        json: false,
+        bars: true,
+        files: Vec::new(),
+    };
+    for arg in args{
+        match arg.as_str(){
+            "--json" => options.json = true,
+            "--no-bars" => options.bars = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::SUCCESS;
+            },
+            _ if arg.starts_with("--") => {
+                eprintln!("unknown option: {}\n{}", arg, USAGE);
+                return ExitCode::from(2);
+            },
+            _ => options.files.push(arg),
+        }
+    }
+    if options.files.is_empty(){
+        eprintln!("{}", USAGE);
+        return ExitCode::from(2);
+    }
+
+    if inspect(&options) { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+}
+
+mod test{
+    #[test]
+    fn test_inspect_json(){
+        use super::{json_string, to_json, Options};
+        use dynamic_piano_sheet::{parse_midi_with_report, Bar, Note, ParseReport};
+
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+
+        let (bars, notes, num_tracks, report) = parse_midi_with_report(include_bytes!("../../tests/assets/test.mid")).unwrap();
+        let options = Options{ json: true, bars: false, files: Vec::new() };
+        let json = to_json("test.mid", &bars, &notes, num_tracks, &report, &options);
+        assert!(json.starts_with(r#"{"file":"test.mid","ok":true,"#));
+        assert!(json.contains(&format!(r#""num_notes":{}"#, notes.len())));
+        assert!(!json.contains(r#""bars":"#));
+        let with_bars = to_json("test.mid", &bars, &notes, num_tracks, &report, &Options{ bars: true, ..options });
+        assert_eq!(with_bars.matches(r#""number":"#).count(), bars.len());
+
+        // 無限大やNaNは null にして、JSONとして読めるようにする
+        let mut bar = Bar::new(0.0, 2.0, 0);
+        bar.set_tempo(f64::INFINITY);
+        let report = ParseReport{ track_names: vec!["ピアノ".to_string()], tempo_changes: vec![(0.0, f64::INFINITY), (1.0, f64::NAN)], warnings: vec!["警告".to_string()] };
+        let json = to_json("x.mid", &[bar], &[Note::new(0.0, 1.0, 60, 100, 0)], 1, &report, &Options{ json: true, bars: true, files: Vec::new() });
+        assert_eq!(json, concat!(
+            r#"{"file":"x.mid","ok":true,"duration":2,"num_bars":1,"num_notes":1,"key_range":{"min":60,"max":60},"#,
+            r#""tracks":[{"name":"ピアノ","notes":1,"key_range":{"min":60,"max":60}}],"#,
+            r#""tempos":[{"time":0,"bpm":null},{"time":1,"bpm":null}],"warnings":["警告"],"#,
+            r#""bars":[{"number":1,"begin":0,"end":2,"time_signature":[4,4],"bpm":null}]}"#,
+        ));
+    }
+}
@@ -0,0 +1,103 @@
+use crate::engine::AudioEngine;
+use crate::metronome::CountIn;
+use crate::scheduler::LoopWrap;
+
+// 練習用に遅くしたり速くしたりできる再生速度の範囲
+pub const MIN_PLAYBACK_RATE: f64 = 0.25;
+pub const MAX_PLAYBACK_RATE: f64 = 2.0;
+
+// 再生速度を範囲に収める (NaNや無限大は受け付けない)
+pub fn checked_rate(rate: f64) -> Option<f64>{
+    rate.is_finite().then(|| rate.clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE))
+}
+
+// 再生位置の基準になる時計 (秒)
+// ブラウザではAudioContextの時計、テストでは手で進める時計を使う
+pub trait Clock{
+    fn now(&self) -> f64;
+}
+
+impl<E: AudioEngine> Clock for E{
+    fn now(&self) -> f64{
+        self.current_time()
+    }
+}
+
+// 時計の時刻から曲の再生位置を求める
+// ループはスケジューラが折り返したところで折り返すので、表示と音がずれない
+pub struct Playhead{
+    song_time: f64,
+    clock_time: f64,
+    // 再生速度 (スケジューラと同じ値にする)
+    rate: f64,
+    // 表示している位置までに折り返した回数
+    loop_count: u32,
+    // 予備拍が終わって曲が始まる時刻 (予備拍の途中で止めたらここから再開する)
+    content_start: f64,
+}
+
+impl Playhead{
+    pub fn new() -> Self{
+        Playhead{
+            song_time: 0.0,
+            clock_time: 0.0,
+            rate: 1.0,
+            loop_count: 0,
+            content_start: 0.0,
+        }
+    }
+
+    // 時計の時刻 clock_time のときに曲の時刻 song_time にいることにする (再生開始やシーク)
+    pub fn reset(&mut self, song_time: f64, clock_time: f64){
+        self.reset_with_count_in(song_time, clock_time, None);
+    }
+
+    // 予備拍の分だけ手前から動かし始める
+    pub fn reset_with_count_in(&mut self, song_time: f64, clock_time: f64, count_in: Option<CountIn>){
+        self.song_time = song_time - count_in.map(|count_in| count_in.length()).unwrap_or(0.0);
+        self.clock_time = clock_time;
+        self.content_start = song_time;
+    }
+
+    pub fn content_start(&self) -> f64{
+        self.content_start
+    }
+
+    pub fn rate(&self) -> f64{
+        self.rate
+    }
+
+    pub fn loop_count(&self) -> u32{
+        self.loop_count
+    }
+
+    // ループやスピードトレーナーを変えたときに数え直す
+    pub fn restart_loop_count(&mut self, rate: f64){
+        self.loop_count = 0;
+        self.rate = rate;
+    }
+
+    // 基準点からの進み方が変わるので、再生中に変えるときは reset し直すこと
+    pub fn set_rate(&mut self, rate: f64){
+        self.rate = rate;
+    }
+
+    // スケジューラが折り返したところで基準点をループの始めに付け替える
+    pub fn follow(&mut self, wrap: &LoopWrap){
+        self.song_time = wrap.song_time;
+        self.clock_time = wrap.engine_time;
+        self.content_start = wrap.content_start;
+        self.rate = wrap.rate;
+        self.loop_count = wrap.loop_count;
+    }
+
+    pub fn position<C: Clock>(&self, clock: &C) -> f64{
+        self.song_time + (clock.now() - self.clock_time) * self.rate
+    }
+}
+
+impl Default for Playhead{
+    fn default() -> Self{
+        Self::new()
+    }
+}
@@ -0,0 +1,60 @@
+use crate::rectangle::Rectangle;
+use crate::render::{RenderBackend, TextStyle};
+use std::convert::Infallible;
+
+// 描画命令をそのまま記録しておくバックエンド
+// テストで何がどこに描かれたかを確認するのに使う
+#[derive(Clone, Debug, PartialEq)]
+pub enum DrawCommand{
+    FillStyle(String),
+    StrokeStyle(String),
+    TextStyle(TextStyle),
+    FillRect(Rectangle),
+    RoundRect(Rectangle, f64),
+    Line(f64, f64, f64, f64),
+    Text(String, f64, f64),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DisplayList{
+    commands: Vec<DrawCommand>,
+}
+
+impl DisplayList{
+    pub fn new() -> Self{
+        DisplayList::default()
+    }
+    pub fn commands(&self) -> &[DrawCommand]{
+        &self.commands
+    }
+}
+
+impl RenderBackend for DisplayList{
+    type Error = Infallible;
+
+    fn set_fill_style(&mut self, color: &str){
+        self.commands.push(DrawCommand::FillStyle(color.to_string()));
+    }
+    fn set_stroke_style(&mut self, color: &str){
+        self.commands.push(DrawCommand::StrokeStyle(color.to_string()));
+    }
+    fn set_text_style(&mut self, style: &TextStyle){
+        self.commands.push(DrawCommand::TextStyle(style.clone()));
+    }
+    fn fill_rect(&mut self, rect: &Rectangle) -> Result<(), Infallible>{
+        self.commands.push(DrawCommand::FillRect(rect.clone()));
+        Ok(())
+    }
+    fn round_rect(&mut self, rect: &Rectangle, radius: f64) -> Result<(), Infallible>{
+        self.commands.push(DrawCommand::RoundRect(rect.clone(), radius));
+        Ok(())
+    }
+    fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) -> Result<(), Infallible>{
+        self.commands.push(DrawCommand::Line(x1, y1, x2, y2));
+        Ok(())
+    }
+    fn text(&mut self, text: &str, x: f64, y: f64) -> Result<(), Infallible>{
+        self.commands.push(DrawCommand::Text(text.to_string(), x, y));
+        Ok(())
+    }
+}
@@ -0,0 +1,825 @@
+use crate::engine::{note_sounding_at, schedule_notes, AudioEngine};
+use crate::note::Note;
+use crate::patch::{SynthPatch, TrackPatches, VoiceModel, Waveform};
+use crate::piano::{self, Partial};
+use crate::polyphony::{Polyphony, VoiceState};
+use crate::song::Song;
+use crate::soundfont::{Preset, Zone};
+use crate::metronome::{click_polyphony, Click, Metronome, CLICK_LENGTH};
+use crate::mixer::Mixer;
+use crate::stereo::{pan_gains, StereoSettings};
+use crate::velocity::{apply_velocity_curve, TrackVelocityCurves, VelocityCurve};
+use crate::wav::encode_wav;
+use std::convert::Infallible;
+use std::rc::Rc;
+use crate::effects::{impulse_response, MasterEffects, EQ_HIGH_FREQ, EQ_LOW_FREQ, EQ_MID_FREQ, EQ_MID_Q, LIMITER_ATTACK, LIMITER_RATIO, LIMITER_RELEASE};
+use crate::synth::{fade_out, interpolate, vca_envelope, vca_off_time, vcf_envelope, SoundSource, VCA_FADE_OUT};
+use std::f64::consts::{PI, SQRT_2};
+
+// SoundSourceと同じ音をWeb Audioなしで作るためのDSP
+// VCO -> ローパスVCF -> ADSRのVCA -> コンプ の構成もパラメーターも揃えている
+
+// 不連続点のなまらせ (PolyBLEP)
+fn poly_blep(t: f64, dt: f64) -> f64{
+    if t < dt{
+        let t = t / dt;
+        t + t - t * t - 1.0
+    }else if t > 1.0 - dt{
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    }else{
+        0.0
+    }
+}
+
+// OscillatorNodeの代わり 鋸波と矩形波はPolyBLEPで帯域制限する
+#[derive(Default)]
+pub struct Oscillator{
+    phase: f64,
+}
+
+impl Oscillator{
+    pub fn process(&mut self, waveform: Waveform, freq: f64, sample_rate: f64) -> f64{
+        let dt = (freq / sample_rate).min(0.5);
+        let t = self.phase;
+        self.phase = (self.phase + dt) % 1.0;
+        match waveform{
+            Waveform::Sine => (2.0 * PI * t).sin(),
+            Waveform::Square => (if t < 0.5 { 1.0 } else { -1.0 }) + poly_blep(t, dt) - poly_blep((t + 0.5) % 1.0, dt),
+            Waveform::Sawtooth => 2.0 * t - 1.0 - poly_blep(t, dt),
+            Waveform::Triangle => 4.0 * ((t + 0.75) % 1.0 - 0.5).abs() - 1.0,
+        }
+    }
+}
+
+// 毎回同じになる白色雑音 (xorshift32)
+pub struct WhiteNoise{
+    state: u32,
+}
+
+impl WhiteNoise{
+    pub fn new(seed: u32) -> Self{
+        WhiteNoise{
+            state: seed.max(1),
+        }
+    }
+
+    // -1から1
+    pub fn sample(&mut self) -> f64{
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f64 / u32::MAX as f64 * 2.0 - 1.0
+    }
+}
+
+// Web AudioのBiquadFilterNodeと同じ式 lowpassのQはdB、bandpassとpeakingのQはそのままの値
+#[derive(Default)]
+pub struct BiquadFilter{
+    coefficients: [f64; 5],
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64,
+}
+
+impl BiquadFilter{
+    pub fn set_lowpass(&mut self, cutoff: f64, q_db: f64, sample_rate: f64){
+        let w0 = 2.0 * PI * (cutoff / sample_rate).clamp(0.0, 0.4999);
+        let alpha = w0.sin() / (2.0 * 10f64.powf(q_db / 20.0));
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        self.coefficients = [(1.0 - cos) / 2.0 / a0, (1.0 - cos) / a0, (1.0 - cos) / 2.0 / a0, -2.0 * cos / a0, (1.0 - alpha) / a0];
+    }
+
+    pub fn set_bandpass(&mut self, center: f64, q: f64, sample_rate: f64){
+        let w0 = 2.0 * PI * (center / sample_rate).clamp(0.0, 0.4999);
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        self.coefficients = [alpha / a0, 0.0, -alpha / a0, -2.0 * w0.cos() / a0, (1.0 - alpha) / a0];
+    }
+
+    // シェルフの傾きは Web Audio と同じく S = 1
+    pub fn set_lowshelf(&mut self, freq: f64, gain_db: f64, sample_rate: f64){
+        let (a, cos, alpha) = Self::shelf_parameters(freq, gain_db, sample_rate);
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+        self.set_coefficients(
+            [a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha), 2.0 * a * ((a - 1.0) - (a + 1.0) * cos), a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha)],
+            [(a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha, -2.0 * ((a - 1.0) + (a + 1.0) * cos), (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha],
+        );
+    }
+
+    pub fn set_highshelf(&mut self, freq: f64, gain_db: f64, sample_rate: f64){
+        let (a, cos, alpha) = Self::shelf_parameters(freq, gain_db, sample_rate);
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+        self.set_coefficients(
+            [a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha), -2.0 * a * ((a - 1.0) + (a + 1.0) * cos), a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha)],
+            [(a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha, 2.0 * ((a - 1.0) - (a + 1.0) * cos), (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha],
+        );
+    }
+
+    pub fn set_peaking(&mut self, freq: f64, q: f64, gain_db: f64, sample_rate: f64){
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * (freq / sample_rate).clamp(0.0, 0.4999);
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        self.set_coefficients([1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a], [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a]);
+    }
+
+    fn shelf_parameters(freq: f64, gain_db: f64, sample_rate: f64) -> (f64, f64, f64){
+        let a = 10f64.powf(gain_db / 40.0);
+        let w0 = 2.0 * PI * (freq / sample_rate).clamp(0.0, 0.4999);
+        (a, w0.cos(), w0.sin() / 2.0 * 2f64.sqrt())
+    }
+
+    fn set_coefficients(&mut self, b: [f64; 3], a: [f64; 3]){
+        self.coefficients = [b[0] / a[0], b[1] / a[0], b[2] / a[0], a[1] / a[0], a[2] / a[0]];
+    }
+
+    pub fn process(&mut self, x: f64) -> f64{
+        let [b0, b1, b2, a1, a2] = self.coefficients;
+        let y = b0 * x + b1 * self.x1 + b2 * self.x2 - a1 * self.y1 - a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+}
+
+// 折れ線のエンベロープ (時刻, 値)
+pub struct Envelope{
+    points: Vec<(f64, f64)>,
+}
+
+impl Envelope{
+    pub fn new(points: Vec<(f64, f64)>) -> Self{
+        Envelope{
+            points,
+        }
+    }
+
+    pub fn value(&self, time: f64) -> f64{
+        interpolate(&self.points, time)
+    }
+
+    pub fn end_time(&self) -> f64{
+        self.points.last().map(|point| point.0).unwrap_or(0.0)
+    }
+}
+
+// DynamicsCompressorNodeの近似 (メイクアップゲインも含める)
+pub struct Compressor{
+    threshold: f64,
+    knee: f64,
+    ratio: f64,
+    attack: f64,
+    release: f64,
+    makeup: f64,
+    reduction_db: f64,
+    sample_rate: f64,
+}
+
+impl Compressor{
+    pub fn new(threshold: f64, knee: f64, ratio: f64, attack: f64, release: f64, sample_rate: f64) -> Self{
+        let mut compressor = Compressor{
+            threshold,
+            knee,
+            ratio,
+            attack: attack.max(1e-4),
+            release: release.max(1e-4),
+            makeup: 1.0,
+            reduction_db: 0.0,
+            sample_rate,
+        };
+        compressor.makeup = 10f64.powf(-compressor.curve(0.0) * 0.6 / 20.0);
+        compressor
+    }
+
+    // 入力レベル(dB)に対する出力レベル(dB)
+    fn curve(&self, x: f64) -> f64{
+        let (threshold, knee, ratio) = (self.threshold, self.knee.max(0.0001), self.ratio.max(1.0));
+        if x < threshold{
+            x
+        }else if x < threshold + knee{
+            x + (1.0 / ratio - 1.0) * (x - threshold).powi(2) / (2.0 * knee)
+        }else{
+            threshold + knee / 2.0 + knee / (2.0 * ratio) + (x - threshold - knee) / ratio
+        }
+    }
+
+    // 入力の大きさ level に対してかける倍率
+    fn gain(&mut self, level: f64) -> f64{
+        let level_db = 20.0 * level.max(1e-9).log10();
+        let target = self.curve(level_db) - level_db;
+        let time = if target < self.reduction_db { self.attack } else { self.release };
+        let coefficient = (-1.0 / (time * self.sample_rate)).exp();
+        self.reduction_db = target + (self.reduction_db - target) * coefficient;
+        10f64.powf(self.reduction_db / 20.0) * self.makeup
+    }
+
+    // 左右で同じだけ圧縮する 真ん中の音がモノラルのときと同じ大きさに見えるようにする
+    pub fn process_stereo(&mut self, left: f64, right: f64) -> (f64, f64){
+        let gain = self.gain(left.abs().max(right.abs()) * SQRT_2);
+        (left * gain, right * gain)
+    }
+}
+
+// 長さが2のべき乗の複素数の高速フーリエ変換 (inverse なら逆変換で 1/n も掛ける)
+fn fft(re: &mut [f64], im: &mut [f64], inverse: bool){
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n{
+        let mut bit = n >> 1;
+        while j & bit != 0{
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j{
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n{
+        let angle = sign * 2.0 * PI / length as f64;
+        for start in (0..n).step_by(length){
+            for k in 0..length / 2{
+                let (w_im, w_re) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + length / 2);
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+        length <<= 1;
+    }
+    if inverse{
+        for (re, im) in re.iter_mut().zip(im.iter_mut()){
+            *re /= n as f64;
+            *im /= n as f64;
+        }
+    }
+}
+
+// ConvolverNodeの代わり インパルス応答の長さごとに区切ってFFTで畳み込んで足し合わせる
+pub struct Convolver{
+    block: usize,
+    response: (Vec<f64>, Vec<f64>),
+}
+
+impl Convolver{
+    pub fn new(impulse_response: &[f32]) -> Self{
+        let block = impulse_response.len().max(1).next_power_of_two();
+        let mut re: Vec<f64> = impulse_response.iter().map(|&x| x as f64).collect();
+        re.resize(block * 2, 0.0);
+        let mut im = vec![0.0; block * 2];
+        fft(&mut re, &mut im, false);
+        Convolver{
+            block,
+            response: (re, im),
+        }
+    }
+
+    // input と同じ長さの出力 (はみ出した残響は切る)
+    pub fn process(&self, input: &[f64]) -> Vec<f64>{
+        let mut output = vec![0.0; input.len()];
+        for start in (0..input.len()).step_by(self.block){
+            let chunk = &input[start..(start + self.block).min(input.len())];
+            if chunk.iter().all(|&x| x == 0.0){
+                continue;
+            }
+            let mut re = chunk.to_vec();
+            re.resize(self.block * 2, 0.0);
+            let mut im = vec![0.0; self.block * 2];
+            fft(&mut re, &mut im, false);
+            for i in 0..re.len(){
+                let (a_re, a_im) = (re[i], im[i]);
+                let (b_re, b_im) = (self.response.0[i], self.response.1[i]);
+                re[i] = a_re * b_re - a_im * b_im;
+                im[i] = a_re * b_im + a_im * b_re;
+            }
+            fft(&mut re, &mut im, true);
+            for (out, y) in output[start..].iter_mut().zip(re.iter()){
+                *out += y;
+            }
+        }
+        output
+    }
+}
+
+// MasterChainと同じ順に、左右のミックスに EQ -> リバーブ -> コンプ -> リミッター をかける
+pub fn apply_master_effects(left: &[f64], right: &[f64], effects: &MasterEffects, sample_rate: u32) -> Vec<(f64, f64)>{
+    let rate = sample_rate as f64;
+    // 0dBの帯域は素通しと同じなので飛ばす
+    let equalize = |input: &[f64]|{
+        let mut bands = Vec::new();
+        if effects.eq_low != 0.0{
+            let mut filter = BiquadFilter::default();
+            filter.set_lowshelf(EQ_LOW_FREQ, effects.eq_low, rate);
+            bands.push(filter);
+        }
+        if effects.eq_mid != 0.0{
+            let mut filter = BiquadFilter::default();
+            filter.set_peaking(EQ_MID_FREQ, EQ_MID_Q, effects.eq_mid, rate);
+            bands.push(filter);
+        }
+        if effects.eq_high != 0.0{
+            let mut filter = BiquadFilter::default();
+            filter.set_highshelf(EQ_HIGH_FREQ, effects.eq_high, rate);
+            bands.push(filter);
+        }
+        input.iter().map(|&x| bands.iter_mut().fold(x, |x, filter| filter.process(x))).collect::<Vec<f64>>()
+    };
+    let mut left = equalize(left);
+    let mut right = equalize(right);
+
+    if effects.has_reverb(){
+        let [left_response, right_response] = impulse_response(effects, sample_rate);
+        for (channel, response) in [(&mut left, left_response), (&mut right, right_response)]{
+            let wet = Convolver::new(&response).process(channel);
+            for (x, wet) in channel.iter_mut().zip(wet){
+                *x += wet * effects.reverb_mix;
+            }
+        }
+    }
+
+    let mut compressor = Compressor::new(effects.comp_threshold, effects.comp_knee, effects.comp_ratio, effects.comp_attack, effects.comp_release, rate);
+    let mut limiter = effects.limiter.then(|| Compressor::new(effects.limiter_threshold, 0.0, LIMITER_RATIO, LIMITER_ATTACK, LIMITER_RELEASE, rate));
+    left.iter().zip(right.iter()).map(|(&l, &r)|{
+        let (l, r) = compressor.process_stereo(l, r);
+        match limiter.as_mut(){
+            Some(limiter) => limiter.process_stereo(l, r),
+            None => (l, r),
+        }
+    }).collect()
+}
+
+// SoundFontのサンプルを読み進める (AudioBufferSourceNodeの代わり)
+struct SampleReader{
+    data: Rc<[f32]>,
+    zone: Zone,
+    gain: f64,
+    // zone.start からの位置 (鳴り終わったら None) と、出力の1サンプルごとに進む量
+    position: Option<f64>,
+    step: f64,
+}
+
+impl SampleReader{
+    fn process(&mut self) -> f64{
+        let Some(position) = self.position else{
+            return 0.0;
+        };
+        let index = position as usize;
+        // ループの終わりの次はループの始まりにつなぐ
+        let next = if self.zone.looping && self.zone.start + index + 1 >= self.zone.loop_end { self.zone.loop_start - self.zone.start } else { index + 1 };
+        let sample = |i: usize| if self.zone.start + i < self.zone.end { self.data[self.zone.start + i] as f64 } else { 0.0 };
+        let x = sample(index) + (sample(next) - sample(index)) * position.fract();
+        self.position = self.zone.wrap(position + self.step);
+        x * self.gain
+    }
+}
+
+// SoundSource 1音分
+pub struct Voice{
+    key: u8,
+    start_time: f64,
+    // ノートが鳴り始めた時刻 (途中から鳴らすときは start_time より前)
+    origin: f64,
+    released: bool,
+    waveform: Waveform,
+    resonance: f64,
+    cutoff: Envelope,
+    gain: Envelope,
+    // ユニゾンの数 (ピアノなら倍音の数) だけ
+    oscs: Vec<(Oscillator, Partial)>,
+    filter: BiquadFilter,
+    // ピアノのハンマーの音の大きさとフィルタ
+    hammer_gain: f64,
+    hammer_filter: BiquadFilter,
+    // SoundFontで鳴らすときはオシレーターとフィルタの代わりにこちらを使う
+    samples: Vec<SampleReader>,
+    // 左右の位置 (-1が左端、1が右端)
+    pan: f64,
+    // トラックの音量
+    level: f64,
+}
+
+impl Voice{
+    // playback_rate は再生速度で、遅くするほどエンベロープやピアノの減衰を伸ばす
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(patch: &SynthPatch, key: u8, velocity: u8, start_time: f64, end_time: f64, offset: f64, sample_rate: f64, playback_rate: f64) -> Self{
+        let patch = &patch.at_rate(playback_rate);
+        let base_gain = patch.velocity_to_gain(velocity);
+        let freq = SoundSource::midi_key_to_freq(key) as f64;
+        let gain = vca_envelope(patch, base_gain, start_time, end_time, offset);
+        let end_time = vca_off_time(patch, start_time, end_time, offset);
+
+        let (waveform, partials, hammer_gain) = match patch.voice{
+            VoiceModel::Subtractive => {
+                // 減衰しない倍音として扱う
+                let partials = (0..patch.num_oscillators()).map(|i| Partial{ freq: freq * 2f64.powf(patch.detune_cents(i) / 1200.0), amplitude: 1.0, decay: f64::INFINITY }).collect();
+                (patch.waveform, partials, 0.0)
+            },
+            VoiceModel::Piano => {
+                let hammer_gain = if offset <= 0.0 { piano::hammer_gain(velocity) } else { 0.0 };
+                (Waveform::Sine, piano::partials(key, velocity, freq, playback_rate), hammer_gain)
+            },
+        };
+        let mut hammer_filter = BiquadFilter::default();
+        hammer_filter.set_bandpass(piano::hammer_freq(freq), 1.0, sample_rate);
+
+        Voice{
+            key,
+            start_time,
+            origin: start_time - offset,
+            released: false,
+            waveform,
+            resonance: patch.resonance,
+            cutoff: Envelope::new(vcf_envelope(patch, velocity, freq, start_time, end_time, offset)),
+            gain: Envelope::new(gain),
+            oscs: partials.into_iter().map(|partial| (Oscillator::default(), partial)).collect(),
+            filter: BiquadFilter::default(),
+            hammer_gain,
+            hammer_filter,
+            samples: Vec::new(),
+            pan: 0.0,
+            level: 1.0,
+        }
+    }
+
+    // SoundSource::from_samplesと同じくゾーンのサンプルを混ぜて鳴らす
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_samples(preset: &Preset, zones: &[&Zone], note: &Note, start_time: f64, end_time: f64, offset: f64, sample_rate: f64, playback_rate: f64) -> Self{
+        let samples = zones.iter().map(|zone| SampleReader{
+            data: preset.samples.clone(),
+            zone: (*zone).clone(),
+            gain: zone.gain(note.velocity()) / zones.len() as f64,
+            position: zone.position(note.key(), offset),
+            step: zone.pitch_ratio(note.key()) * zone.sample_rate as f64 / sample_rate,
+        }).collect();
+
+        Voice{
+            key: note.key(),
+            start_time,
+            origin: start_time - offset,
+            released: false,
+            waveform: Waveform::Sine,
+            resonance: 0.0,
+            cutoff: Envelope::new(Vec::new()),
+            gain: Envelope::new(zones[0].vca_envelope(note.key(), 1.0, start_time, end_time, offset, playback_rate)),
+            oscs: Vec::new(),
+            filter: BiquadFilter::default(),
+            hammer_gain: 0.0,
+            hammer_filter: BiquadFilter::default(),
+            samples,
+            pan: 0.0,
+            level: 1.0,
+        }
+    }
+
+    pub fn set_pan(&mut self, pan: f64){
+        self.pan = pan;
+    }
+
+    pub fn set_level(&mut self, level: f64){
+        self.level = level;
+    }
+
+    // SoundSource::releaseと同じく time から短いフェードで止める
+    pub fn release(&mut self, time: f64){
+        if self.end_time() > time + VCA_FADE_OUT{
+            self.gain = Envelope::new(fade_out(&self.gain.points, time));
+        }
+        self.released = true;
+    }
+
+    // hammer_noise はエンジンのサンプリング周波数で作ったハンマーの音
+    pub fn process(&mut self, time: f64, sample_rate: f64, update_filter: bool, hammer_noise: &[f32]) -> f64{
+        if !self.samples.is_empty(){
+            let x: f64 = self.samples.iter_mut().map(|sample| sample.process()).sum();
+            return x * self.gain.value(time);
+        }
+        if update_filter{
+            self.filter.set_lowpass(self.cutoff.value(time), self.resonance, sample_rate);
+        }
+        let waveform = self.waveform;
+        let elapsed = time - self.origin;
+        let x: f64 = self.oscs.iter_mut().map(|(osc, partial)| osc.process(waveform, partial.freq, sample_rate) * piano::partial_amplitude(partial, elapsed)).sum();
+        let mut y = self.filter.process(x);
+
+        if self.hammer_gain > 0.0{
+            let index = ((time - self.start_time) * sample_rate).round();
+            let noise = if index >= 0.0 { hammer_noise.get(index as usize).copied().unwrap_or(0.0) } else { 0.0 };
+            y += self.hammer_filter.process(noise as f64) * self.hammer_gain;
+        }
+        y * self.gain.value(time)
+    }
+}
+
+impl VoiceState for Voice{
+    fn key(&self) -> u8{
+        self.key
+    }
+
+    fn start_time(&self) -> f64{
+        self.start_time
+    }
+
+    fn end_time(&self) -> f64{
+        self.gain.end_time()
+    }
+
+    fn released(&self) -> bool{
+        self.released
+    }
+
+    fn level(&self, time: f64) -> f64{
+        self.gain.value(time)
+    }
+}
+
+// フィルタの係数はこのサンプル数ごとに更新する
+const CONTROL_INTERVAL: usize = 16;
+
+// Web Audioの代わりにRustだけで音を作るエンジン
+// play_noteで予約した音をrenderでまとめて書き出す
+pub struct OfflineEngine{
+    sample_rate: u32,
+    voices: Vec<Voice>,
+    // メトロノームのクリック (ノートの同時発音数とは別に数える)
+    clicks: Vec<Voice>,
+    volume: f32,
+    current_time: f64,
+    polyphony: Polyphony,
+    patches: TrackPatches,
+    velocity_curves: TrackVelocityCurves,
+    stereo: StereoSettings,
+    mixer: Mixer,
+    metronome: Metronome,
+    effects: MasterEffects,
+    hammer_noise: Vec<f32>,
+    preset: Option<Preset>,
+    // 再生速度 (エンベロープを伸ばす)
+    playback_rate: f64,
+}
+
+impl OfflineEngine{
+    pub fn new(sample_rate: u32) -> Self{
+        OfflineEngine{
+            sample_rate,
+            voices: Vec::new(),
+            clicks: Vec::new(),
+            volume: 1.0,
+            current_time: 0.0,
+            polyphony: Polyphony::default(),
+            patches: TrackPatches::default(),
+            velocity_curves: TrackVelocityCurves::default(),
+            stereo: StereoSettings::default(),
+            mixer: Mixer::default(),
+            metronome: Metronome::default(),
+            effects: MasterEffects::default(),
+            hammer_noise: piano::hammer_noise(sample_rate),
+            preset: None,
+            playback_rate: 1.0,
+        }
+    }
+
+    // WebAudioEngine::set_soundfont_presetと同じく全部のトラックをSoundFontで鳴らす
+    pub fn set_soundfont_preset(&mut self, preset: Option<Preset>){
+        self.preset = preset;
+    }
+
+    pub fn patches(&self) -> &TrackPatches{
+        &self.patches
+    }
+
+    pub fn set_patches(&mut self, patches: TrackPatches){
+        self.patches = patches.clamped();
+    }
+
+    pub fn set_patch(&mut self, track: u8, patch: SynthPatch){
+        self.patches.set(track, patch.clamped());
+    }
+
+    pub fn velocity_curves(&self) -> &TrackVelocityCurves{
+        &self.velocity_curves
+    }
+
+    pub fn set_velocity_curves(&mut self, velocity_curves: TrackVelocityCurves){
+        self.velocity_curves = velocity_curves;
+    }
+
+    pub fn set_velocity_curve(&mut self, track: u8, curve: VelocityCurve){
+        self.velocity_curves.set(track, curve);
+    }
+
+    pub fn stereo(&self) -> &StereoSettings{
+        &self.stereo
+    }
+
+    pub fn set_stereo(&mut self, stereo: StereoSettings){
+        self.stereo = stereo;
+    }
+
+    pub fn mixer(&self) -> &Mixer{
+        &self.mixer
+    }
+
+    pub fn set_mixer(&mut self, mixer: Mixer){
+        self.mixer = mixer;
+    }
+
+    pub fn metronome(&self) -> Metronome{
+        self.metronome
+    }
+
+    pub fn set_metronome(&mut self, metronome: Metronome){
+        self.metronome = metronome;
+    }
+
+    pub fn effects(&self) -> &MasterEffects{
+        &self.effects
+    }
+
+    pub fn set_effects(&mut self, effects: MasterEffects){
+        self.effects = effects.clamped();
+    }
+
+    pub fn polyphony(&self) -> Polyphony{
+        self.polyphony
+    }
+
+    pub fn set_polyphony(&mut self, polyphony: Polyphony){
+        self.polyphony = polyphony;
+    }
+
+    pub fn sample_rate(&self) -> u32{
+        self.sample_rate
+    }
+
+    pub fn set_current_time(&mut self, time: f64){
+        self.current_time = time;
+    }
+
+    pub fn num_voices(&self) -> usize{
+        self.voices.len()
+    }
+
+    // エンジンの時刻 start_time から end_time までを左右交互のステレオで書き出す
+    pub fn render_stereo(&mut self, start_time: f64, end_time: f64) -> Vec<f32>{
+        let rate = self.sample_rate as f64;
+        let num_samples = ((end_time - start_time).max(0.0) * rate).ceil() as usize;
+        let mut left = vec![0.0f64; num_samples];
+        let mut right = vec![0.0f64; num_samples];
+
+        for voice in self.voices.iter_mut().chain(self.clicks.iter_mut()){
+            if voice.start_time() >= end_time || voice.end_time() < start_time{
+                continue;
+            }
+            let (left_gain, right_gain) = pan_gains(voice.pan);
+            let (left_gain, right_gain) = (left_gain * voice.level, right_gain * voice.level);
+            // フィルタの状態を合わせるため鳴り始めから計算する
+            let first = ((voice.start_time() - start_time) * rate).round() as i64;
+            let last = (((voice.end_time() - start_time) * rate).ceil() as i64).min(num_samples as i64);
+            for i in first..last{
+                let time = start_time + i as f64 / rate;
+                let sample = voice.process(time, rate, ((i - first) as usize).is_multiple_of(CONTROL_INTERVAL), &self.hammer_noise);
+                if i >= 0{
+                    left[i as usize] += sample * left_gain;
+                    right[i as usize] += sample * right_gain;
+                }
+            }
+        }
+
+        let volume = self.volume as f64;
+        apply_master_effects(&left, &right, &self.effects, self.sample_rate).into_iter().flat_map(|(l, r)| [(l * volume) as f32, (r * volume) as f32]).collect()
+    }
+
+    // モノラルにまとめたもの (真ん中に置いた音はそのままの大きさ)
+    pub fn render(&mut self, start_time: f64, end_time: f64) -> Vec<f32>{
+        self.render_stereo(start_time, end_time).chunks_exact(2).map(|lr| ((lr[0] as f64 + lr[1] as f64) / SQRT_2) as f32).collect()
+    }
+
+    // 曲の start_time から end_time までの音をモノラルのサンプル列にする
+    pub fn render_song(&mut self, song: &Song, start_time: f64, end_time: f64) -> Vec<f32>{
+        self.schedule_song(song, start_time, end_time);
+        self.render(0.0, end_time - start_time)
+    }
+
+    // 曲の start_time から end_time までの音を左右交互のステレオのサンプル列にする
+    pub fn render_song_stereo(&mut self, song: &Song, start_time: f64, end_time: f64) -> Vec<f32>{
+        self.schedule_song(song, start_time, end_time);
+        self.render_stereo(0.0, end_time - start_time)
+    }
+
+    fn schedule_song(&mut self, song: &Song, start_time: f64, end_time: f64){
+        // 範囲の前から鳴っている音も含める
+        let notes: Vec<Note> = song.notes().iter().filter(|note| note.on_time() < end_time && note_sounding_at(note, start_time.max(note.on_time()), self.patches.get(note.track()).release)).copied().collect();
+        let Ok(()) = schedule_notes(self, &notes, f64::NEG_INFINITY, end_time, start_time, 0.0);
+    }
+}
+
+impl AudioEngine for OfflineEngine{
+    type Error = Infallible;
+
+    fn current_time(&self) -> f64{
+        self.current_time
+    }
+
+    fn play_note(&mut self, note: &Note, start_time: f64, end_time: f64, offset: f64) -> Result<(), Infallible>{
+        let level = self.mixer.gain(note);
+        if level <= 0.0{
+            return Ok(());
+        }
+        let note = &apply_velocity_curve(note, &self.velocity_curves);
+        for i in self.polyphony.victims(&self.voices, note.key(), start_time){
+            self.voices[i].release(start_time);
+        }
+        let rate = self.sample_rate as f64;
+        let zones: Vec<&Zone> = self.preset.iter().flat_map(|preset| preset.zones_for(note.key(), note.velocity())).collect();
+        let mut voice = match self.preset.as_ref(){
+            Some(preset) if !zones.is_empty() => Voice::from_samples(preset, &zones, note, start_time, end_time, offset, rate, self.playback_rate),
+            _ => Voice::new(&self.patches.get(note.track()), note.key(), note.velocity(), start_time, end_time, offset, rate, self.playback_rate),
+        };
+        voice.set_pan(self.stereo.pan(note));
+        voice.set_level(level);
+        self.voices.push(voice);
+        Ok(())
+    }
+
+    fn play_click(&mut self, click: &Click, start_time: f64) -> Result<(), Infallible>{
+        let note = self.metronome.click_note(click);
+        for i in click_polyphony().victims(&self.clicks, note.key(), start_time){
+            self.clicks[i].release(start_time);
+        }
+        let mut voice = Voice::new(&self.metronome.sound.patch(), note.key(), note.velocity(), start_time, start_time + CLICK_LENGTH, 0.0, self.sample_rate as f64, 1.0);
+        voice.set_level(self.metronome.volume.max(0.0));
+        self.clicks.push(voice);
+        Ok(())
+    }
+
+    fn release_all(&mut self, time: f64){
+        for voices in [&mut self.voices, &mut self.clicks]{
+            voices.retain(|voice| voice.start_time() < time);
+            for voice in voices.iter_mut(){
+                voice.release(time);
+            }
+        }
+    }
+
+    fn cancel_scheduled(&mut self, time: f64){
+        self.voices.retain(|voice| voice.start_time() <= time);
+        self.clicks.retain(|voice| voice.start_time() <= time);
+    }
+
+    fn set_playback_rate(&mut self, rate: f64){
+        self.playback_rate = rate;
+    }
+
+    fn update(&mut self){
+        let current_time = self.current_time;
+        self.voices.retain(|voice| voice.end_time() > current_time);
+        self.clicks.retain(|voice| voice.end_time() > current_time);
+    }
+
+    fn volume(&self) -> f32{
+        self.volume
+    }
+
+    fn set_volume(&mut self, volume: f32){
+        self.volume = volume;
+    }
+}
+
+fn song_engine(sample_rate: u32, volume: f32, patches: &TrackPatches) -> OfflineEngine{
+    let mut engine = OfflineEngine::new(sample_rate);
+    engine.set_volume(volume);
+    engine.set_patches(patches.clone());
+    engine
+}
+
+// 曲の start_time から end_time までの音をモノラルのサンプル列にする (左右に振った音は真ん中にまとめる)
+pub fn render_song(song: &Song, start_time: f64, end_time: f64, sample_rate: u32, volume: f32, patches: &TrackPatches) -> Vec<f32>{
+    song_engine(sample_rate, volume, patches).render_song(song, start_time, end_time)
+}
+
+// render_song の左右交互のステレオ版
+pub fn render_song_stereo(song: &Song, start_time: f64, end_time: f64, sample_rate: u32, volume: f32, patches: &TrackPatches) -> Vec<f32>{
+    song_engine(sample_rate, volume, patches).render_song_stereo(song, start_time, end_time)
+}
+
+// 練習用の伴奏や音のリグレッションテスト用のWAV (モノラル)
+pub fn render_wav(song: &Song, start_time: f64, end_time: f64, sample_rate: u32) -> Vec<u8>{
+    encode_wav(&render_song(song, start_time, end_time, sample_rate, 1.0, &TrackPatches::default()), 1, sample_rate)
+}
+
+// ステレオのWAV
+pub fn render_wav_stereo(song: &Song, start_time: f64, end_time: f64, sample_rate: u32) -> Vec<u8>{
+    encode_wav(&render_song_stereo(song, start_time, end_time, sample_rate, 1.0, &TrackPatches::default()), 2, sample_rate)
+}
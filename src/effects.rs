@@ -0,0 +1,150 @@
+use crate::dsp::WhiteNoise;
+use crate::synth::{COMP_KNEE, COMP_RATIO, COMP_THRESHOLD};
+use wasm_bindgen::prelude::*;
+
+// 3バンドEQの低音と高音のシェルフの周波数、中音のピークの周波数とQ
+pub const EQ_LOW_FREQ: f64 = 250.0;
+pub const EQ_MID_FREQ: f64 = 1000.0;
+pub const EQ_MID_Q: f64 = 0.7;
+pub const EQ_HIGH_FREQ: f64 = 4000.0;
+// リミッターは最後に刺す速いコンプ
+pub const LIMITER_RATIO: f64 = 20.0;
+pub const LIMITER_ATTACK: f64 = 0.001;
+pub const LIMITER_RELEASE: f64 = 0.1;
+// 残響の時間は60dB下がるまでの時間
+const REVERB_DECAY_DB: f64 = 60.0;
+// インパルス応答が大きくなりすぎないように残響の長さ (秒) を抑える
+pub const MIN_REVERB_TIME: f64 = 0.01;
+pub const MAX_REVERB_TIME: f64 = 10.0;
+
+// 全部の音をまとめた後にかけるエフェクト
+// EQ -> リバーブ -> コンプ -> リミッター -> 音量 の順につなぐ
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MasterEffects{
+    // EQのそれぞれの帯域の増減 (dB)
+    pub eq_low: f64,
+    pub eq_mid: f64,
+    pub eq_high: f64,
+    // 残響の混ぜる量 (0で残響なし)、長さ (秒)、高い音の吸われ方 (0から1)
+    pub reverb_mix: f64,
+    pub reverb_time: f64,
+    pub reverb_damping: f64,
+    // DynamicsCompressorNodeと同じ (dB, 秒)
+    pub comp_threshold: f64,
+    pub comp_knee: f64,
+    pub comp_ratio: f64,
+    pub comp_attack: f64,
+    pub comp_release: f64,
+    // この大きさ (dB) を超えないように抑える
+    pub limiter: bool,
+    pub limiter_threshold: f64,
+}
+
+impl Default for MasterEffects{
+    fn default() -> Self{
+        MasterEffects{
+            eq_low: 0.0,
+            eq_mid: 0.0,
+            eq_high: 0.0,
+            reverb_mix: 0.0,
+            reverb_time: 1.5,
+            reverb_damping: 0.5,
+            comp_threshold: COMP_THRESHOLD as f64,
+            comp_knee: COMP_KNEE as f64,
+            comp_ratio: COMP_RATIO as f64,
+            comp_attack: 0.003,
+            comp_release: 0.25,
+            limiter: false,
+            limiter_threshold: -1.0,
+        }
+    }
+}
+
+pub const PRESET_NAMES: [&str; 3] = ["default", "dry practice", "concert hall"];
+
+#[wasm_bindgen]
+impl MasterEffects{
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> MasterEffects{
+        MasterEffects::default()
+    }
+
+    // default(今までの音), dry practice, concert hall
+    pub fn preset(name: &str) -> Option<MasterEffects>{
+        let default = MasterEffects::default();
+        match name{
+            "default" => Some(default),
+            // 残響なしで一音ずつはっきり聞こえるようにする
+            "dry practice" => Some(MasterEffects{
+                eq_low: -2.0,
+                eq_mid: 1.5,
+                comp_threshold: -24.0,
+                comp_knee: 10.0,
+                comp_ratio: 4.0,
+                limiter: true,
+                ..default
+            }),
+            // 広いホールで弾いているような長い残響
+            "concert hall" => Some(MasterEffects{
+                eq_low: 1.5,
+                eq_high: -1.5,
+                reverb_mix: 0.35,
+                reverb_time: 2.4,
+                reverb_damping: 0.6,
+                comp_threshold: -18.0,
+                comp_knee: 12.0,
+                comp_ratio: 3.0,
+                comp_release: 0.4,
+                limiter: true,
+                ..default
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn preset_names() -> Vec<String>{
+        PRESET_NAMES.iter().map(|name| name.to_string()).collect()
+    }
+}
+
+impl MasterEffects{
+    pub fn has_reverb(&self) -> bool{
+        self.reverb_mix > 0.0 && self.reverb_time > 0.0
+    }
+
+    // JSやJSONから来た値は残響の長さを MAX_REVERB_TIME までにする (NaNは残響なし)
+    pub fn clamped(self) -> MasterEffects{
+        let reverb_time = if self.reverb_time.is_nan() { 0.0 } else { self.reverb_time.clamp(0.0, MAX_REVERB_TIME) };
+        MasterEffects{ reverb_time, ..self }
+    }
+
+    fn impulse_length(&self) -> f64{
+        if self.reverb_time.is_nan() { MIN_REVERB_TIME } else { self.reverb_time.clamp(MIN_REVERB_TIME, MAX_REVERB_TIME) }
+    }
+}
+
+// 残響のインパルス応答 (左, 右)
+// 指数で減衰するノイズを、後ろほど強くローパスして高い音から先に消えるようにする
+// 左右で別のノイズにして広がりを出し、エネルギーが1になるように揃える
+pub fn impulse_response(effects: &MasterEffects, sample_rate: u32) -> [Vec<f32>; 2]{
+    let rate = sample_rate as f64;
+    let reverb_time = effects.impulse_length();
+    let length = (reverb_time * rate).ceil() as usize;
+    let decay = REVERB_DECAY_DB / 20.0 * 10f64.ln() / reverb_time;
+    let damping = effects.reverb_damping.clamp(0.0, 1.0);
+
+    [0x1234_5678, 0x8765_4321].map(|seed|{
+        let mut noise = WhiteNoise::new(seed);
+        let mut filtered = 0.0;
+        let samples: Vec<f64> = (0..length).map(|i|{
+            let progress = i as f64 / length as f64;
+            filtered += (noise.sample() - filtered) * (1.0 - 0.95 * damping * progress);
+            filtered * (-decay * i as f64 / rate).exp()
+        }).collect();
+        let energy: f64 = samples.iter().map(|x| x * x).sum();
+        let scale = 1.0 / energy.sqrt().max(1e-9);
+        samples.iter().map(|x| (x * scale) as f32).collect()
+    })
+}
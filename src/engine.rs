@@ -0,0 +1,305 @@
+use crate::metronome::{click_polyphony, Click, Metronome, CLICK_LENGTH};
+use crate::note::Note;
+use crate::mixer::Mixer;
+use crate::polyphony::{Polyphony, VoiceState};
+use crate::patch::{SynthPatch, TrackPatches};
+use crate::piano::hammer_noise;
+use crate::soundfont::Preset;
+use crate::stereo::StereoSettings;
+use crate::velocity::{apply_velocity_curve, TrackVelocityCurves, VelocityCurve};
+use crate::effects::MasterEffects;
+use crate::synth::{MasterChain, SampleBuffer, SoundSource, VoiceChain};
+use std::collections::HashMap;
+use wasm_bindgen::JsValue;
+use web_sys::{AudioBuffer, AudioContext, GainNode};
+
+// 音を鳴らす先の抽象化
+// ブラウザではWeb Audio、ネイティブやWAV書き出しではdspのオフラインエンジンを使う
+pub trait AudioEngine{
+    type Error;
+
+    // エンジンの時計 (秒)
+    fn current_time(&self) -> f64;
+    // エンジンの時刻で start_time から end_time まで鳴らす (リリースはその後)
+    // offset はノートが鳴り始めてからの経過時間で、0より大きいときは途中から短いアタックで鳴らす
+    // 音色とベロシティ曲線と左右の位置はノートのトラックで決まる
+    // ミキサーでミュートされたトラックのノートは鳴らさない
+    fn play_note(&mut self, note: &Note, start_time: f64, end_time: f64, offset: f64) -> Result<(), Self::Error>;
+    // メトロノームのクリックをエンジンの時刻 start_time に鳴らす (ミキサーや左右の位置は関係なく真ん中)
+    fn play_click(&mut self, click: &Click, start_time: f64) -> Result<(), Self::Error>;
+    // time の時点で鳴っている音を短いフェードで止めて、それより後に鳴り始める音は鳴らさない
+    fn release_all(&mut self, time: f64);
+    // time より後に鳴り始める予定の音をやめる (鳴っている音はそのまま)
+    fn cancel_scheduled(&mut self, time: f64);
+    // 鳴っている音を全部止める
+    fn stop_all(&mut self){
+        self.release_all(self.current_time());
+    }
+    // これから鳴らす音のエンベロープを再生速度 rate に合わせる (鳴らす時刻と長さはもう rate で割ってある)
+    fn set_playback_rate(&mut self, _rate: f64){}
+    // 鳴り終わった音の後片付け
+    fn update(&mut self);
+    fn volume(&self) -> f32;
+    fn set_volume(&mut self, volume: f32);
+}
+
+// 曲の時刻 [from_time, to_time) に鳴り始めるノートを、曲の時刻 song_time がエンジンの時刻 engine_time に対応するようにして鳴らす
+pub fn schedule_notes<E: AudioEngine>(engine: &mut E, notes: &[Note], from_time: f64, to_time: f64, song_time: f64, engine_time: f64) -> Result<(), E::Error>{
+    for note in notes.iter(){
+        if from_time <= note.on_time() && note.on_time() < to_time{
+            let start_time = engine_time + (note.on_time() - song_time);
+            let end_time = start_time + (note.off_time() - note.on_time());
+            engine.play_note(note, start_time, end_time, 0.0)?;
+        }
+    }
+    Ok(())
+}
+
+// リリースまで含めて time に音が残っているか
+pub fn note_sounding_at(note: &Note, time: f64, release: f64) -> bool{
+    note.on_time() <= time && time < note.off_time() + release
+}
+
+pub struct WebAudioEngine{
+    audio_context: AudioContext,
+    master: MasterChain,
+    master_volume: GainNode,
+    sound_sources: Vec<SoundSource>,
+    // メトロノームのクリック (ノートの同時発音数とは別に数える)
+    click_sources: Vec<SoundSource>,
+    // ピアノのハンマーの音 (全部の音で使い回す)
+    hammer_noise: AudioBuffer,
+    // 鳴り終わった音のVCFとVCA
+    free_chains: Vec<VoiceChain>,
+    polyphony: Polyphony,
+    patches: TrackPatches,
+    velocity_curves: TrackVelocityCurves,
+    stereo: StereoSettings,
+    mixer: Mixer,
+    metronome: Metronome,
+    // SoundFontのプリセットのゾーン (空なら全部のトラックを SynthPatch の音で鳴らす)
+    samples: Vec<SampleBuffer>,
+    // 再生速度 (エンベロープを伸ばす)
+    playback_rate: f64,
+}
+
+impl WebAudioEngine{
+    pub fn new() -> Result<WebAudioEngine, JsValue>{
+        let audio_context = AudioContext::new()?;
+
+        let master_volume = audio_context.create_gain()?;
+        master_volume.connect_with_audio_node(&audio_context.destination())?;
+
+        // 音が重なるとノイズが気になるので出力の手前にコンプ刺す (リバーブなどもここ)
+        let master = MasterChain::new(&audio_context, &master_volume)?;
+
+        let noise = hammer_noise(audio_context.sample_rate() as u32);
+        let hammer_noise = audio_context.create_buffer(1, noise.len() as u32, audio_context.sample_rate())?;
+        hammer_noise.copy_to_channel(&noise, 0)?;
+
+        Ok(WebAudioEngine{
+            audio_context,
+            master,
+            master_volume,
+            sound_sources: Vec::new(),
+            click_sources: Vec::new(),
+            hammer_noise,
+            free_chains: Vec::new(),
+            polyphony: Polyphony::default(),
+            patches: TrackPatches::default(),
+            velocity_curves: TrackVelocityCurves::default(),
+            stereo: StereoSettings::default(),
+            mixer: Mixer::default(),
+            metronome: Metronome::default(),
+            samples: Vec::new(),
+            playback_rate: 1.0,
+        })
+    }
+
+    pub fn polyphony(&self) -> Polyphony{
+        self.polyphony
+    }
+
+    pub fn set_polyphony(&mut self, polyphony: Polyphony){
+        self.polyphony = polyphony;
+        self.free_chains.truncate(polyphony.max_voices);
+    }
+
+    pub fn patches(&self) -> &TrackPatches{
+        &self.patches
+    }
+
+    pub fn set_patches(&mut self, patches: TrackPatches){
+        self.patches = patches.clamped();
+    }
+
+    pub fn set_patch(&mut self, track: u8, patch: SynthPatch){
+        self.patches.set(track, patch.clamped());
+    }
+
+    pub fn velocity_curves(&self) -> &TrackVelocityCurves{
+        &self.velocity_curves
+    }
+
+    pub fn set_velocity_curves(&mut self, velocity_curves: TrackVelocityCurves){
+        self.velocity_curves = velocity_curves;
+    }
+
+    pub fn set_velocity_curve(&mut self, track: u8, curve: VelocityCurve){
+        self.velocity_curves.set(track, curve);
+    }
+
+    pub fn stereo(&self) -> &StereoSettings{
+        &self.stereo
+    }
+
+    pub fn set_stereo(&mut self, stereo: StereoSettings){
+        self.stereo = stereo;
+    }
+
+    pub fn mixer(&self) -> &Mixer{
+        &self.mixer
+    }
+
+    // 鳴っている音にも新しい音量をかける (鳴らすかどうかは次に鳴らすノートから)
+    pub fn set_mixer(&mut self, mixer: Mixer){
+        self.mixer = mixer;
+        let now_time = self.audio_context.current_time();
+        for source in self.sound_sources.iter(){
+            // 音量を変えられなかった音は前の音量のまま鳴らす
+            source.change_level(self.mixer.gain(source.note()), now_time).ok();
+        }
+    }
+
+    pub fn metronome(&self) -> Metronome{
+        self.metronome
+    }
+
+    pub fn set_metronome(&mut self, metronome: Metronome){
+        self.metronome = metronome;
+    }
+
+    pub fn effects(&self) -> &MasterEffects{
+        self.master.effects()
+    }
+
+    pub fn set_effects(&mut self, effects: MasterEffects) -> Result<(), JsValue>{
+        self.master.set_effects(&self.audio_context, &effects.clamped())
+    }
+
+    // SoundFontのプリセットで全部のトラックを鳴らす (None なら SynthPatch の音に戻す)
+    pub fn set_soundfont_preset(&mut self, preset: Option<&Preset>) -> Result<(), JsValue>{
+        let mut samples = Vec::new();
+        if let Some(preset) = preset{
+            // 同じサンプルを使うゾーンはバッファを共有する
+            let mut buffers = HashMap::new();
+            for zone in preset.zones.iter(){
+                let sample = match buffers.get(&(zone.start, zone.end)){
+                    Some(buffer) => SampleBuffer{ zone: zone.clone(), buffer: Clone::clone(buffer) },
+                    None => SampleBuffer::new(&self.audio_context, preset, zone)?,
+                };
+                buffers.insert((zone.start, zone.end), sample.buffer.clone());
+                samples.push(sample);
+            }
+        }
+        self.samples = samples;
+        Ok(())
+    }
+
+    pub fn has_soundfont(&self) -> bool{
+        !self.samples.is_empty()
+    }
+
+    pub fn num_voices(&self) -> usize{
+        self.sound_sources.len()
+    }
+}
+
+impl AudioEngine for WebAudioEngine{
+    type Error = JsValue;
+
+    fn current_time(&self) -> f64{
+        self.audio_context.current_time()
+    }
+
+    fn play_note(&mut self, note: &Note, start_time: f64, end_time: f64, offset: f64) -> Result<(), JsValue>{
+        // 音量が0でも鳴らしておけば、後から音量を上げたときに聞こえる
+        if !self.mixer.audible(note){
+            return Ok(());
+        }
+        let level = self.mixer.gain(note);
+        let note = &apply_velocity_curve(note, &self.velocity_curves);
+        for i in self.polyphony.victims(&self.sound_sources, note.key(), start_time){
+            self.sound_sources[i].release(start_time)?;
+        }
+        let chain = match self.free_chains.pop(){
+            Some(chain) => chain,
+            None => VoiceChain::new(&self.audio_context, self.master.input(), &self.hammer_noise)?,
+        };
+        // SoundFontに音域がなければ SynthPatch の音で鳴らす
+        let samples: Vec<&SampleBuffer> = self.samples.iter().filter(|sample| sample.zone.contains(note.key(), note.velocity())).collect();
+        let source = if samples.is_empty(){
+            SoundSource::new(&self.audio_context, chain, &self.patches.get(note.track()), note, start_time, end_time, offset, self.playback_rate)?
+        }else{
+            SoundSource::from_samples(&self.audio_context, chain, &samples, note, start_time, end_time, offset, self.playback_rate)?
+        };
+        source.set_pan(self.stereo.pan(note))?;
+        source.set_level(level)?;
+        self.sound_sources.push(source);
+        Ok(())
+    }
+
+    fn play_click(&mut self, click: &Click, start_time: f64) -> Result<(), JsValue>{
+        let note = self.metronome.click_note(click);
+        for i in click_polyphony().victims(&self.click_sources, note.key(), start_time){
+            self.click_sources[i].release(start_time)?;
+        }
+        let chain = match self.free_chains.pop(){
+            Some(chain) => chain,
+            None => VoiceChain::new(&self.audio_context, self.master.input(), &self.hammer_noise)?,
+        };
+        let source = SoundSource::new(&self.audio_context, chain, &self.metronome.sound.patch(), &note, start_time, start_time + CLICK_LENGTH, 0.0, 1.0)?;
+        source.set_pan(0.0)?;
+        source.set_level(self.metronome.volume.max(0.0))?;
+        self.click_sources.push(source);
+        Ok(())
+    }
+
+    fn release_all(&mut self, time: f64){
+        // フェードを予約できなかった音はその場で切断する
+        self.sound_sources.retain_mut(|source| source.release(time).is_ok());
+        self.click_sources.retain_mut(|source| source.release(time).is_ok());
+    }
+
+    fn cancel_scheduled(&mut self, time: f64){
+        for sources in [&mut self.sound_sources, &mut self.click_sources]{
+            sources.retain_mut(|source| source.start_time() <= time || source.release(time).is_ok());
+        }
+    }
+
+    fn set_playback_rate(&mut self, rate: f64){
+        self.playback_rate = rate;
+    }
+
+    fn update(&mut self){
+        let now_time = self.audio_context.current_time();
+        let (finished, sound_sources): (Vec<SoundSource>, Vec<SoundSource>) = std::mem::take(&mut self.sound_sources).into_iter().partition(|source| source.finished(now_time));
+        self.sound_sources = sound_sources;
+        let (finished_clicks, click_sources): (Vec<SoundSource>, Vec<SoundSource>) = std::mem::take(&mut self.click_sources).into_iter().partition(|source| source.finished(now_time));
+        self.click_sources = click_sources;
+        for source in finished.into_iter().chain(finished_clicks){
+            let chain = source.into_chain();
+            if self.free_chains.len() < self.polyphony.max_voices{
+                self.free_chains.push(chain);
+            }
+        }
+    }
+
+    fn volume(&self) -> f32{
+        self.master_volume.gain().value()
+    }
+
+    fn set_volume(&mut self, volume: f32){
+        self.master_volume.gain().set_value(volume);
+    }
+}
@@ -3,16 +3,72 @@ mod rectangle;
 mod note;
 mod bar;
 mod synth;
-use synth::SoundSource;
-use bar::Bar;
-use note::Note;
-use rectangle::Rectangle;
+mod tempo_map;
+mod musicxml;
+mod mxl;
+mod abc;
+mod loader;
+mod song;
+mod settings;
+mod render;
+mod display_list;
+mod svg;
+mod pdf;
+mod sheet;
+mod raster;
+mod engine;
+mod dsp;
+mod effects;
+mod metronome;
+mod mixer;
+mod patch;
+mod piano;
+mod soundfont;
+mod polyphony;
+mod stereo;
+mod track;
+mod velocity;
+mod scheduler;
+mod section;
+mod trainer;
+mod clock;
+mod wav;
+mod video;
+pub use bar::Bar;
+pub use note::Note;
+pub use rectangle::Rectangle;
+pub use render::{render_scene, RenderBackend, RenderOptions, TextStyle, Canvas2dBackend};
+pub use display_list::{DisplayList, DrawCommand};
+pub use song::Song;
+pub use settings::PlayerSettings;
+pub use svg::{render_svg, render_svg_with_options, SvgBackend};
+pub use pdf::PdfBackend;
+pub use raster::RasterBackend;
+pub use wav::encode_wav;
+pub use engine::{AudioEngine, WebAudioEngine};
+pub use effects::MasterEffects;
+pub use metronome::{ClickSound, Metronome};
+pub use mixer::{Mixer, MixerStrip};
+pub use patch::{SynthPatch, TrackPatches, VoiceModel, Waveform};
+pub use polyphony::{Polyphony, VoiceStealing};
+pub use section::{PracticeSection, PracticeSections};
+pub use stereo::{PanMode, StereoSettings};
+pub use track::PerTrack;
+pub use trainer::SpeedTrainer;
+pub use velocity::{TrackVelocityCurves, VelocityCurve};
+pub use dsp::{render_song, render_song_stereo, render_wav, render_wav_stereo, OfflineEngine};
+pub use video::{render_frame, render_frames, render_video_audio, render_video_audio_stereo, Frames, VideoSettings};
+pub use sheet::{render_sheet_page, render_sheet_pdf, render_sheet_svg, SheetLayout};
 use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
-use js_sys::{Uint8Array,};
+use js_sys::{Function, Uint8Array,};
 
-use web_sys::{CanvasRenderingContext2d, File, AudioContext, DynamicsCompressorNode, GainNode};
+use web_sys::{CanvasRenderingContext2d, File, Response};
+use scheduler::Scheduler;
+use soundfont::SoundFont;
+use clock::{checked_rate, Clock, Playhead};
+use metronome::CountIn;
 use midly::{Format, Smf, Timing, TrackEventKind, MidiMessage, MetaMessage};
 
 fn bpm_to_tempo(bpm: f64) -> f64{
@@ -20,24 +76,41 @@ fn bpm_to_tempo(bpm: f64) -> f64{
 }
 
 fn calc_sec_per_tick(ticks_per_beat: u16, tempo: f64) -> f64{
-    tempo as f64 * 0.000001 / ticks_per_beat as f64
+    tempo * 0.000001 / ticks_per_beat as f64
+}
+
+fn tempo_to_bpm(tempo: f64) -> f64{
+    60000000.0 / tempo
+}
+
+// 読み込みには成功したが曲データとしておかしいところなどの付加情報
+#[derive(Clone, Debug, Default)]
+pub struct ParseReport{
+    pub track_names: Vec<String>,
+    // (時刻, BPM)
+    pub tempo_changes: Vec<(f64, f64)>,
+    pub warnings: Vec<String>,
 }
 
 pub fn parse_midi(data: &[u8]) -> Result<(Vec<Bar>, Vec<Note>, u8), String>{
+    parse_midi_with_report(data).map(|(bars, notes, num_tracks, _)| (bars, notes, num_tracks))
+}
+
+pub fn parse_midi_with_report(data: &[u8]) -> Result<(Vec<Bar>, Vec<Note>, u8, ParseReport), String>{
     let smf = match Smf::parse(data){
         Ok(smf) => smf,
         Err(e) => {
             log!("Error parsing MIDI file: {:?}", e);
-            return Err(format!("Failed to parse MIDI file"));
+            return Err("Failed to parse MIDI file".to_string());
         }
     };
 
     if smf.header.format != Format::Parallel{
-        return Err(format!("Parallelだけサポート").into());
+        return Err("Parallelだけサポート".to_string());
     }
 
     let ticks_per_beat = match smf.header.timing {
-        Timing::Timecode(_, _) => return Err(format!("タイムコードは未サポート")),
+        Timing::Timecode(_, _) => return Err("タイムコードは未サポート".to_string()),
         Timing::Metrical(res) => res.as_int(),
     };
 
@@ -64,15 +137,27 @@ pub fn parse_midi(data: &[u8]) -> Result<(Vec<Bar>, Vec<Note>, u8), String>{
     let mut bars: Vec<Bar> = Vec::new();
     let mut notes: Vec<Note> = Vec::new();
     let mut playing_notes: HashMap<(u8, u8), usize> = HashMap::new();
+    let mut time_signature: (u8, u8) = (4, 4);
+    let mut bpm = 120.0;
+    let mut report = ParseReport{
+        track_names: vec![String::new(); smf.tracks.len()],
+        tempo_changes: vec![(0.0, bpm)],
+        warnings: Vec::new(),
+    };
+    let mut orphan_note_offs = 0;
+    let mut zero_tempos = 0;
 
-    while track_states.iter().any(|&state| state.ended == false) {
+    while track_states.iter().any(|&state| !state.ended) {
         
         // 小節情報
-        if remain_bar_ticks <= 0{
+        if remain_bar_ticks == 0{
             if let Some(bar) = bars.last_mut(){
                 bar.set_end_time(current_time);
             }
-            bars.push(Bar::new(current_time, -1.0, bars.len() as u32));
+            let mut bar = Bar::new(current_time, -1.0, bars.len() as u32);
+            bar.set_time_signature(time_signature.0, time_signature.1);
+            bar.set_tempo(bpm);
+            bars.push(bar);
 
             remain_bar_ticks = ticks_per_bar;
         }
@@ -99,21 +184,25 @@ pub fn parse_midi(data: &[u8]) -> Result<(Vec<Bar>, Vec<Note>, u8), String>{
                                 let hash_key = (channel.as_int(), key.as_int());
                                 if vel > 0 {
                                     let note_id = notes.len();
-                                    notes.push(Note::new(current_time, -1.0, key.as_int(), vel.as_int(), i as u8));
-                                    if let Some(_) = playing_notes.insert(hash_key, note_id){
-                                        return Err(format!("Error NoteOnが重複しました。"));
+                                    let mut note = Note::new(current_time, -1.0, key.as_int(), vel.as_int(), i as u8);
+                                    note.set_channel(channel.as_int());
+                                    notes.push(note);
+                                    if playing_notes.insert(hash_key, note_id).is_some(){
+                                        return Err("Error NoteOnが重複しました。".to_string());
                                     }
                                 }else{
                                     // vel0はNoteOff扱い?
-                                    if let Some(id) = playing_notes.remove(&hash_key){
-                                        notes[id].set_off_time(current_time);
+                                    match playing_notes.remove(&hash_key){
+                                        Some(id) => notes[id].set_off_time(current_time),
+                                        None => orphan_note_offs += 1,
                                     }
                                 }
                             },
                             MidiMessage::NoteOff { key, .. } =>{
                                 let hash_key = (channel.as_int(), key.as_int());
-                                if let Some(id) = playing_notes.remove(&hash_key){
-                                    notes[id].set_off_time(current_time);
+                                match playing_notes.remove(&hash_key){
+                                    Some(id) => notes[id].set_off_time(current_time),
+                                    None => orphan_note_offs += 1,
                                 }
                             },
                             _ => (),
@@ -121,15 +210,34 @@ pub fn parse_midi(data: &[u8]) -> Result<(Vec<Bar>, Vec<Note>, u8), String>{
                     }
                     TrackEventKind::Meta(message) =>{
                         match message{
+                            // テンポ0は時間が進まなくなるので無視する
+                            MetaMessage::Tempo(tempo) if tempo.as_int() == 0 => zero_tempos += 1,
                             MetaMessage::Tempo(tempo) => {
                                 sec_per_tick = calc_sec_per_tick(ticks_per_beat, tempo.as_int() as f64);
+                                bpm = tempo_to_bpm(tempo.as_int() as f64);
+                                if let Some(bar) = bars.last_mut() && bar.begin_time() == current_time{
+                                    bar.set_tempo(bpm);
+                                }
+                                match report.tempo_changes.last_mut(){
+                                    Some(last) if last.0 == current_time => last.1 = bpm,
+                                    _ => report.tempo_changes.push((current_time, bpm)),
+                                }
                             },
                             MetaMessage::EndOfTrack =>{
                                 track_state.ended = true;
                             },
                             MetaMessage::TimeSignature(num, denom, _ , _) =>{
-                                ticks_per_bar = ticks_per_beat as usize / (2u32.pow(denom as u32) / 4) as usize * num as usize;   
+                                // denom は2の何乗かで、2分音符 (1) や全音符 (0) の拍でも0で割らないようにする
+                                let beat_ticks = if denom <= 2 { ticks_per_beat as usize * (4 >> denom) } else { (ticks_per_beat as usize).checked_shr(denom as u32 - 2).unwrap_or(0) };
+                                ticks_per_bar = (beat_ticks * num as usize).max(1);
                                 remain_bar_ticks = ticks_per_bar;
+                                time_signature = (num, 2u8.saturating_pow(denom as u32));
+                                if let Some(bar) = bars.last_mut(){
+                                    bar.set_time_signature(time_signature.0, time_signature.1);
+                                }
+                            },
+                            MetaMessage::TrackName(name) =>{
+                                report.track_names[i] = String::from_utf8_lossy(name).into_owned();
                             },
                             _ => (),
                         }
@@ -151,166 +259,504 @@ pub fn parse_midi(data: &[u8]) -> Result<(Vec<Bar>, Vec<Note>, u8), String>{
         bar.set_end_time(current_time + remain_bar_ticks as f64 * sec_per_tick);
     }
 
-    Ok((bars, notes, smf.tracks.len() as u8))
-}
-
-fn calc_key_area(rect: &Rectangle, min_key: u8, max_key: u8) -> Vec<Rectangle>{
-    // キーボードの１オクターブ分の鍵盤の比率位置テーブルを作成、黒鍵は白鍵にかぶさる上に幅や位置が等幅ではないので定義して使うことにした
-    const OCTAVE_SIZE_RATIO_TABLE:[(f64, f64); 12] = [(0.0, 0.1428), (0.0951, 0.1666),(0.1428, 0.2857),(0.2618, 0.3333),(0.2857, 0.4285),(0.4285, 0.5714),(0.5237, 0.5952),(0.5714, 0.7142),(0.6784, 0.7499),(0.7142, 0.8571),(0.8332, 0.9047),(0.8571, 1.0)];
-
-    let min_octave = min_key / 12;
-    let min_note = min_key % 12;
-    let _max_octave = max_key / 12;
-    let max_note = max_key % 12;
-    
-    let base_offset_ratio = min_octave as f64 + OCTAVE_SIZE_RATIO_TABLE[min_note as usize].0;
-    let octave_width = {
-        let min_aligned = min_key + 12 - (min_key % 12);
-        let max_aligned = max_key - (max_key % 12);
-        let total_octave_ratio = (max_aligned - min_aligned) as f64 / 12.0 + (1.0 - OCTAVE_SIZE_RATIO_TABLE[min_note as usize].0) + OCTAVE_SIZE_RATIO_TABLE[max_note as usize].1;
-        rect.width() / total_octave_ratio
-    };
-
-    let mut ret: Vec<Rectangle> = Vec::new();
-    for key in min_key..=max_key{
-        let note_index = (key % 12) as usize;
-        let octave = (key / 12) as f64;
-        let left = rect.left() + (octave + OCTAVE_SIZE_RATIO_TABLE[note_index].0 - base_offset_ratio) * octave_width;
-        let right = rect.left() + (octave + OCTAVE_SIZE_RATIO_TABLE[note_index].1 - base_offset_ratio) * octave_width;
-        ret.push(Rectangle::new(left, rect.top(), right - left, rect.height()));
+    // NoteOffが来なかったノートは曲の最後で止める (終わりの時刻が決まらないと鳴らせず描けもしないので)
+    let unterminated = notes.iter_mut().filter(|note| note.off_time() < note.on_time()).map(|note| note.set_off_time(current_time)).count();
+    if unterminated > 0{
+        report.warnings.push(format!("NoteOffがないノートが{}個あります", unterminated));
+    }
+    if zero_tempos > 0{
+        report.warnings.push(format!("テンポが0のイベントが{}個あります (無視しました)", zero_tempos));
+    }
+    if orphan_note_offs > 0{
+        report.warnings.push(format!("対応するNoteOnがないNoteOffが{}個あります", orphan_note_offs));
     }
-    ret
+    let out_of_range = notes.iter().filter(|note| !(21..=108).contains(&note.key())).count();
+    if out_of_range > 0{
+        report.warnings.push(format!("ピアノの音域外のノートが{}個あります", out_of_range));
+    }
+    if notes.is_empty(){
+        report.warnings.push("ノートがありません".to_string());
+    }
+
+    Ok((bars, notes, smf.tracks.len() as u8, report))
 }
 
 #[wasm_bindgen]
 pub struct MidiPlayer{
-    audio_context: AudioContext,
-    comp: DynamicsCompressorNode,
-    master_volume: GainNode,
-    sound_sources: Vec<SoundSource>,
-    bars: Vec<Bar>,
-    notes: Vec<Note>,
+    engine: WebAudioEngine,
+    scheduler: Scheduler,
+    playhead: Playhead,
+    song: Song,
     current_time: f64,
     playing: bool,
     display_range_sec: f64,
-    num_tracks: u8,
-    loop_start_bar: usize,
-    loop_end_bar: usize,
+    // ループする曲の時刻 (A - B)
+    loop_points: Option<(f64, f64)>,
+    sections: PracticeSections,
+    // 読み込んだSF2と鳴らしているプリセットの番号
+    soundfont: Option<(SoundFont, usize)>,
+    dim_muted_tracks: bool,
+    // ループを折り返すたびに (回数, 再生速度) で呼ぶ
+    loop_callback: Option<Function>,
+    notified_loop_count: u32,
+    // 再生を始める前とループの始めに戻ったときに鳴らす予備拍の数 (0ならなし)
+    count_in_beats: u8,
 }
 
 #[wasm_bindgen]
 impl MidiPlayer{
     pub fn new() -> Result<MidiPlayer, JsValue>{
         utils::set_panic_hook();
-        let audio_context = AudioContext::new()?;
-
-        let master_volume = audio_context.create_gain()?;
-        master_volume.connect_with_audio_node(&audio_context.destination())?;
-
-        // 音が重なるとノイズが気になるので出力の手前にコンプ刺す
-        let comp = audio_context.create_dynamics_compressor()?;
-        comp.threshold().set_value(-20.0);
-        comp.knee().set_value(15.0);
-        comp.ratio().set_value(20.0); 
-        comp.connect_with_audio_node(&master_volume)?;
+        let engine = WebAudioEngine::new()?;
 
         Ok(MidiPlayer{
-            audio_context: audio_context,
-            comp: comp,
-            master_volume: master_volume,
-            bars: Vec::new(),
-            notes: Vec::new(),
+            engine,
+            scheduler: Scheduler::new(0.1),
+            playhead: Playhead::new(),
+            song: Song::default(),
             current_time: 0.0,
-            sound_sources: Vec::new(),
             playing: false,
             display_range_sec: 3.0,
-            num_tracks: 0,
-            loop_start_bar: 0,
-            loop_end_bar: 0,
+            loop_points: None,
+            sections: PracticeSections::default(),
+            soundfont: None,
+            dim_muted_tracks: true,
+            loop_callback: None,
+            notified_loop_count: 0,
+            count_in_beats: 0,
         })
     }
 
     pub async fn load_midi(&mut self, file: &File) -> Result<(), JsValue>{
         let buffer = JsFuture::from(file.array_buffer()).await?;
         let bin = Uint8Array::new(&buffer).to_vec();
-        let parse_result = match parse_midi(&bin){
-            Ok(parse_result) => {
-                parse_result
+        self.load_bytes(&bin, &file.name())
+    }
+
+    // 中身を見てSMF, RMID, MusicXML, mxl, ABCのどれかとして読み込む
+    pub fn load_bytes(&mut self, data: &[u8], name_hint: &str) -> Result<(), JsValue>{
+        let song = match loader::load_song(data, name_hint){
+            Ok(song) => {
+                song
             },
             Err(e) => {
-                return Err(JsValue::from_str(&format!("Error parsing MIDI file: {}", e)));
+                return Err(JsValue::from_str(&format!("Error parsing file: {}", e)));
             }
         };
 
         self.playing = false;
         self.current_time = 0.0;
-        self.sound_sources.clear();
-        self.bars = parse_result.0;
-        self.notes = parse_result.1;
-        self.num_tracks = parse_result.2;
+        self.engine.stop_all();
+        self.song = song;
+        // ループと練習の区間は前の曲のもの
+        self.sections = PracticeSections::default();
+        self.clear_loop();
+        self.update_clicks();
 
         Ok(())
     }
 
+    pub async fn load_url(&mut self, url: &str) -> Result<(), JsValue>{
+        let window = web_sys::window().ok_or_else(|| JsValue::from_str("windowがありません"))?;
+        let response: Response = JsFuture::from(window.fetch_with_str(url)).await?.dyn_into()?;
+        if !response.ok(){
+            return Err(JsValue::from_str(&format!("Error fetching {}: {}", url, response.status())));
+        }
+        let buffer = JsFuture::from(response.array_buffer()?).await?;
+        let bin = Uint8Array::new(&buffer).to_vec();
+        // クエリやフラグメントは拡張子の判定に邪魔なので落とす
+        let name_hint = url.split(['?', '#']).next().unwrap_or(url);
+        self.load_bytes(&bin, name_hint)
+    }
+
+    // 予備拍の間は曲の始めより手前にいるので0で止める
     pub fn current_playback_time(&self) -> f64{
-        self.current_time
+        self.current_time.max(0.0)
     }
 
     pub fn song_length(&self) -> f64{
-        match self.bars.last() {
+        match self.song.bars().last() {
             Some(bar) => bar.end_time(),
             None => 0.0,
         }
     }
 
     pub fn play(&mut self){
-        if self.ready() == false{
+        if !self.ready(){
             return;
         }
         self.playing = true;
+        // 予備拍の分だけ手前から画面を動かし始める
+        let now = self.engine.now();
+        let count_in = CountIn::at(&self.song, self.current_time, self.count_in_beats);
+        self.scheduler.reset_with_count_in(self.song.notes(), self.current_time, now, count_in);
+        self.playhead.reset_with_count_in(self.current_time, now, count_in);
     }
 
     pub fn stop(&mut self){
+        // 予備拍の途中で止めたら、次は予備拍のあとの位置から始める
+        self.current_time = self.resume_time();
         self.playing = false;
-        self.sound_sources.clear();
+        self.engine.stop_all();
     }
 
     pub fn ready(&self) -> bool{
-        self.notes.len() > 0 && self.bars.len() > 0
+        !self.song.notes().is_empty() && !self.song.bars().is_empty()
+    }
+
+    // start_bar の頭から end_bar の終わりまでをループする (end_bar が start_bar 以前ならループしない)
+    pub fn set_loop_bars(&mut self, start_bar: usize, end_bar: usize){
+        let bars = self.song.bars();
+        if end_bar <= start_bar || end_bar >= bars.len(){
+            self.clear_loop();
+            return;
+        }
+        self.set_loop_points(bars[start_bar].begin_time(), bars[end_bar].end_time());
+    }
+
+    // 曲の時刻 start_time から end_time までをループする
+    pub fn set_loop_points(&mut self, start_time: f64, end_time: f64){
+        let song_length = self.song_length();
+        let (start_time, end_time) = (start_time.clamp(0.0, song_length), end_time.clamp(0.0, song_length));
+        self.loop_points = if start_time < end_time { Some((start_time, end_time)) } else { None };
+        self.sections.activate(None).ok();
+        self.update_loop_count_in();
+        self.restart_loop_count();
+    }
+
+    // 小節 (0から) と拍 (0から、小数も可) でループの位置を決める
+    pub fn set_loop_beats(&mut self, start_bar: usize, start_beat: f64, end_bar: usize, end_beat: f64) -> Result<(), JsValue>{
+        let start_time = self.beat_time(start_bar, start_beat)?;
+        let end_time = self.beat_time(end_bar, end_beat)?;
+        self.set_loop_points(start_time, end_time);
+        Ok(())
+    }
+
+    pub fn clear_loop(&mut self){
+        self.loop_points = None;
+        self.sections.activate(None).ok();
+        self.update_loop_count_in();
+        self.restart_loop_count();
     }
 
-    pub fn set_loop_bars(&mut self, start_bar: usize, end_bar: usize){   
-        self.loop_start_bar = start_bar;
-        self.loop_end_bar = end_bar;
+    pub fn loop_start(&self) -> Option<f64>{
+        self.loop_points.map(|(start_time, _)| start_time)
+    }
+
+    pub fn loop_end(&self) -> Option<f64>{
+        self.loop_points.map(|(_, end_time)| end_time)
+    }
+
+    // 保存している練習の区間の名前
+    pub fn practice_sections(&self) -> Vec<String>{
+        self.sections.sections().iter().map(|section| section.name.clone()).collect()
+    }
+
+    // 区間を追加して番号を返す
+    pub fn add_practice_section(&mut self, name: &str, start_time: f64, end_time: f64) -> Result<usize, JsValue>{
+        self.sections.add(name, start_time, end_time).map_err(|e| JsValue::from_str(&e))
+    }
+
+    // 今のループを区間として保存する
+    pub fn save_loop_as_practice_section(&mut self, name: &str) -> Result<usize, JsValue>{
+        let (start_time, end_time) = self.loop_points.ok_or_else(|| JsValue::from_str("ループが設定されていません"))?;
+        let index = self.add_practice_section(name, start_time, end_time)?;
+        self.sections.activate(Some(index)).map_err(|e| JsValue::from_str(&e))?;
+        Ok(index)
+    }
+
+    pub fn rename_practice_section(&mut self, index: usize, name: &str) -> Result<(), JsValue>{
+        self.sections.rename(index, name).map_err(|e| JsValue::from_str(&e))
+    }
+
+    // ループしている区間を消したときはループもやめる
+    pub fn remove_practice_section(&mut self, index: usize) -> Result<(), JsValue>{
+        let active = self.sections.active() == Some(index);
+        self.sections.remove(index).map_err(|e| JsValue::from_str(&e))?;
+        if active{
+            self.clear_loop();
+        }
+        Ok(())
+    }
+
+    // 区間をループする
+    pub fn activate_practice_section(&mut self, index: usize) -> Result<(), JsValue>{
+        let section = self.sections.get(index).ok_or_else(|| JsValue::from_str(&format!("区間がありません: {}", index)))?;
+        self.set_loop_points(section.start_time, section.end_time);
+        self.sections.activate(Some(index)).map_err(|e| JsValue::from_str(&e))
+    }
+
+    pub fn active_practice_section(&self) -> Option<usize>{
+        self.sections.active()
     }
 
     pub fn num_bars(&self) -> usize{
-        self.bars.len()
+        self.song.bars().len()
     }
 
     pub fn volume(&self) -> f32{
-        self.master_volume.gain().value()
+        self.engine.volume()
     }
 
     pub fn set_volume(&mut self, volume: f32){
-        self.master_volume.gain().set_value(volume);
+        self.engine.set_volume(volume);
+    }
+
+    // トラックの音色
+    pub fn track_patch(&self, track: u8) -> SynthPatch{
+        self.engine.patches().get(track)
+    }
+
+    pub fn set_track_patch(&mut self, track: u8, patch: SynthPatch){
+        self.engine.set_patch(track, patch);
+    }
+
+    // トラックのベロシティ曲線の名前 (linear, log, exp, fixed, custom)
+    pub fn track_velocity_curve(&self, track: u8) -> String{
+        self.engine.velocity_curves().get(track).name().to_string()
+    }
+
+    // linear, log, exp のどれか
+    pub fn set_track_velocity_curve(&mut self, track: u8, name: &str) -> Result<(), JsValue>{
+        let curve = VelocityCurve::from_name(name).ok_or_else(|| JsValue::from_str(&format!("ベロシティ曲線がありません: {}", name)))?;
+        self.engine.set_velocity_curve(track, curve);
+        Ok(())
+    }
+
+    // 強さに関係なく velocity で鳴らす
+    pub fn set_track_fixed_velocity(&mut self, track: u8, velocity: u8){
+        self.engine.set_velocity_curve(track, VelocityCurve::Fixed(velocity.min(127)));
+    }
+
+    // 0から127までの強さを table[強さ] に変換する
+    pub fn set_track_velocity_table(&mut self, track: u8, table: &[u8]) -> Result<(), JsValue>{
+        let curve = VelocityCurve::custom(table).map_err(|e| JsValue::from_str(&e))?;
+        self.engine.set_velocity_curve(track, curve);
+        Ok(())
+    }
+
+    pub fn velocity_curve_names() -> Vec<String>{
+        velocity::CURVE_NAMES.iter().map(|name| name.to_string()).collect()
+    }
+
+    // 左右の位置の決め方
+    pub fn pan_mode(&self) -> PanMode{
+        self.engine.stereo().mode
+    }
+
+    pub fn set_pan_mode(&mut self, mode: PanMode){
+        self.engine.set_stereo(StereoSettings{ mode, ..self.engine.stereo().clone() });
+    }
+
+    // 左右の広がり (0でモノラル、1で最大)
+    pub fn stereo_width(&self) -> f64{
+        self.engine.stereo().width
+    }
+
+    pub fn set_stereo_width(&mut self, width: f64){
+        self.engine.set_stereo(StereoSettings{ width: width.clamp(0.0, 1.0), ..self.engine.stereo().clone() });
+    }
+
+    // トラックを置く位置 (-1が左端、1が右端、undefinedなら pan_mode に従う)
+    pub fn track_pan(&self, track: u8) -> Option<f64>{
+        self.engine.stereo().track_pans.get(track)
+    }
+
+    pub fn set_track_pan(&mut self, track: u8, pan: Option<f64>){
+        let mut stereo = self.engine.stereo().clone();
+        stereo.track_pans.set(track, pan.map(|pan| pan.clamp(-1.0, 1.0)));
+        self.engine.set_stereo(stereo);
+    }
+
+    // トラックのミュート、ソロ、音量 (1でそのまま)
+    pub fn track_muted(&self, track: u8) -> bool{
+        self.engine.mixer().tracks.get(track).mute
+    }
+
+    pub fn set_track_muted(&mut self, track: u8, mute: bool){
+        self.update_track_strip(track, |strip| strip.mute = mute);
+    }
+
+    pub fn track_solo(&self, track: u8) -> bool{
+        self.engine.mixer().tracks.get(track).solo
+    }
+
+    pub fn set_track_solo(&mut self, track: u8, solo: bool){
+        self.update_track_strip(track, |strip| strip.solo = solo);
+    }
+
+    pub fn track_volume(&self, track: u8) -> f64{
+        self.engine.mixer().tracks.get(track).volume
+    }
+
+    pub fn set_track_volume(&mut self, track: u8, volume: f64){
+        self.update_track_strip(track, |strip| strip.volume = volume.max(0.0));
+    }
+
+    // MIDIチャンネル (0から15) のミュート、ソロ、音量
+    pub fn channel_muted(&self, channel: u8) -> bool{
+        self.engine.mixer().channels.get(channel).mute
+    }
+
+    pub fn set_channel_muted(&mut self, channel: u8, mute: bool){
+        self.update_channel_strip(channel, |strip| strip.mute = mute);
+    }
+
+    pub fn channel_solo(&self, channel: u8) -> bool{
+        self.engine.mixer().channels.get(channel).solo
+    }
+
+    pub fn set_channel_solo(&mut self, channel: u8, solo: bool){
+        self.update_channel_strip(channel, |strip| strip.solo = solo);
+    }
+
+    pub fn channel_volume(&self, channel: u8) -> f64{
+        self.engine.mixer().channels.get(channel).volume
+    }
+
+    pub fn set_channel_volume(&mut self, channel: u8, volume: f64){
+        self.update_channel_strip(channel, |strip| strip.volume = volume.max(0.0));
+    }
+
+    // トラックに入っているノートのMIDIチャンネル
+    pub fn track_channels(&self, track: u8) -> Vec<u8>{
+        let mut channels: Vec<u8> = self.song.notes().iter().filter(|note| note.track() == track).map(|note| note.channel()).collect();
+        channels.sort();
+        channels.dedup();
+        channels
+    }
+
+    // ミュートされたトラックのノートを暗く表示するか
+    pub fn dim_muted_tracks(&self) -> bool{
+        self.dim_muted_tracks
+    }
+
+    pub fn set_dim_muted_tracks(&mut self, dim: bool){
+        self.dim_muted_tracks = dim;
+    }
+
+    // 小節と拍に合わせて鳴らすメトロノーム
+    pub fn metronome(&self) -> Metronome{
+        self.engine.metronome()
+    }
+
+    pub fn set_metronome(&mut self, metronome: Metronome){
+        self.engine.set_metronome(metronome);
+        self.update_clicks();
+    }
+
+    pub fn set_metronome_enabled(&mut self, enabled: bool){
+        self.set_metronome(Metronome{ enabled, ..self.metronome() });
+    }
+
+    // 1拍をいくつに割って鳴らすか
+    pub fn set_metronome_subdivision(&mut self, subdivision: u8){
+        self.set_metronome(Metronome{ subdivision: subdivision.max(1), ..self.metronome() });
+    }
+
+    pub fn set_metronome_volume(&mut self, volume: f64){
+        self.set_metronome(Metronome{ volume: volume.max(0.0), ..self.metronome() });
+    }
+
+    pub fn set_metronome_sound(&mut self, sound: ClickSound){
+        self.set_metronome(Metronome{ sound, ..self.metronome() });
+    }
+
+    // 再生を始める前とループの始めに戻ったときに、その位置のテンポと拍子でメトロノームの音を beats 拍鳴らす
+    pub fn count_in_beats(&self) -> u8{
+        self.count_in_beats
+    }
+
+    pub fn set_count_in_beats(&mut self, beats: u8){
+        self.count_in_beats = beats;
+        self.update_loop_count_in();
+    }
+
+    // 全体にかけるEQ、リバーブ、コンプ、リミッター
+    pub fn master_effects(&self) -> MasterEffects{
+        *self.engine.effects()
+    }
+
+    pub fn set_master_effects(&mut self, effects: MasterEffects) -> Result<(), JsValue>{
+        self.engine.set_effects(effects)
+    }
+
+    // MasterEffects::preset_names() のどれか
+    pub fn set_master_effects_preset(&mut self, name: &str) -> Result<(), JsValue>{
+        let effects = MasterEffects::preset(name).ok_or_else(|| JsValue::from_str(&format!("エフェクトのプリセットがありません: {}", name)))?;
+        self.engine.set_effects(effects)
+    }
+
+    // SF2を読み込んで、全部のトラックをそのサンプルで鳴らす (音域にないノートはトラックの音色で鳴らす)
+    pub fn load_soundfont(&mut self, data: &[u8]) -> Result<(), JsValue>{
+        let soundfont = SoundFont::parse(data).map_err(|e| JsValue::from_str(&format!("Error parsing soundfont: {}", e)))?;
+        let preset = soundfont.default_preset();
+        self.engine.set_soundfont_preset(soundfont.presets().get(preset))?;
+        self.soundfont = Some((soundfont, preset));
+        Ok(())
+    }
+
+    // 組み込みの音に戻す
+    pub fn unload_soundfont(&mut self) -> Result<(), JsValue>{
+        self.soundfont = None;
+        self.engine.set_soundfont_preset(None)
+    }
+
+    // 読み込んだSF2のプリセット名 ("バンク:プログラム 名前")
+    pub fn soundfont_presets(&self) -> Vec<String>{
+        self.soundfont.iter().flat_map(|(soundfont, _)| soundfont.presets().iter()).map(|preset| format!("{}:{} {}", preset.bank, preset.program, preset.name)).collect()
+    }
+
+    pub fn soundfont_preset(&self) -> Option<usize>{
+        self.soundfont.as_ref().map(|(_, preset)| *preset)
+    }
+
+    pub fn set_soundfont_preset(&mut self, index: usize) -> Result<(), JsValue>{
+        let Some((soundfont, preset)) = self.soundfont.as_mut() else{
+            return Err(JsValue::from_str("soundfontが読み込まれていません"));
+        };
+        let Some(new_preset) = soundfont.presets().get(index) else{
+            return Err(JsValue::from_str(&format!("プリセットがありません: {}", index)));
+        };
+        self.engine.set_soundfont_preset(Some(new_preset))?;
+        *preset = index;
+        Ok(())
+    }
+
+    // 同時に鳴らす音の数の上限
+    pub fn max_polyphony(&self) -> usize{
+        self.engine.polyphony().max_voices
+    }
+
+    pub fn set_max_polyphony(&mut self, max_voices: usize){
+        let polyphony = Polyphony{ max_voices: max_voices.max(1), ..self.engine.polyphony() };
+        self.engine.set_polyphony(polyphony);
+    }
+
+    pub fn voice_stealing(&self) -> VoiceStealing{
+        self.engine.polyphony().stealing
+    }
+
+    pub fn set_voice_stealing(&mut self, stealing: VoiceStealing){
+        let polyphony = Polyphony{ stealing, ..self.engine.polyphony() };
+        self.engine.set_polyphony(polyphony);
     }
 
     pub fn current_bar(&self) -> usize{
-        if self.bars.len() == 0{
+        if self.song.bars().is_empty(){
             return 0;
         }
 
-        if self.current_time < self.bars[0].begin_time(){
+        if self.current_time < self.song.bars()[0].begin_time(){
             return 0;
         }
 
-        for bar in self.bars.iter(){
+        for bar in self.song.bars().iter(){
             if bar.begin_time() <= self.current_time && self.current_time < bar.end_time(){
                 return bar.number() as usize;
             }
         }
-        self.bars.len()
+        self.song.bars().len()
     }
 
     pub fn set_display_range(&mut self, range_sec: f64){
@@ -318,224 +764,400 @@ impl MidiPlayer{
     }
 
     pub fn seek_bar(&mut self, bar: usize, clear_sounds:bool){
-        let bar = &self.bars[bar.clamp(0, self.bars.len() - 1)];
+        let bar = &self.song.bars()[bar.clamp(0, self.song.bars().len() - 1)];
         self.seek_time(bar.begin_time(), clear_sounds);
     }
 
+    // clear_sounds が false なら鳴っている音は残す (先読みで予約しただけの音は取り消す)
     pub fn seek_time(&mut self, time: f64, clear_sounds:bool){
         if clear_sounds {
-            self.sound_sources.clear();
+            self.engine.stop_all();
         }
         self.current_time = time.clamp(0.0, self.song_length());
+        let now = self.engine.now();
+        self.scheduler.seek(&mut self.engine, self.song.notes(), self.current_time, now);
+        self.playhead.reset(self.current_time, now);
     }
 
-    pub fn skip(&mut self, delta: f64, clear_sounds:bool){
-        self.seek_time(self.current_time + delta, clear_sounds);
+    fn update_track_strip(&mut self, track: u8, update: impl FnOnce(&mut MixerStrip)){
+        let mut mixer = self.engine.mixer().clone();
+        let mut strip = mixer.tracks.get(track);
+        update(&mut strip);
+        mixer.tracks.set(track, strip);
+        self.set_mixer(mixer);
     }
 
-    pub fn tick(&mut self, delta_time: f64) -> Result<(),JsValue>{
-        if self.playing == false{
-            return Ok(());
-        }
-        
-        let delta_sec = delta_time / 1000.0;// ms -> s
+    fn update_channel_strip(&mut self, channel: u8, update: impl FnOnce(&mut MixerStrip)){
+        let mut mixer = self.engine.mixer().clone();
+        let mut strip = mixer.channels.get(channel);
+        update(&mut strip);
+        mixer.channels.set(channel, strip);
+        self.set_mixer(mixer);
+    }
 
-        for sound_source in self.sound_sources.iter_mut(){
-            sound_source.tick(delta_sec);
-        }
-        self.sound_sources.retain(|source| !source.finished());
+    // 曲の小節からクリックを作り直す
+    fn update_clicks(&mut self){
+        let metronome = self.metronome();
+        let clicks = if metronome.enabled { metronome::clicks(&self.song, metronome.subdivision) } else { Vec::new() };
+        self.scheduler.set_clicks(clicks);
+    }
 
-        for note in self.notes.iter(){
-            if self.current_time <= note.on_time() && note.on_time() < self.current_time + delta_sec{
-                let start_time = self.audio_context.current_time() + (note.on_time() - self.current_time);
-                let end_time = start_time + (note.off_time() - note.on_time());
-                self.sound_sources.push(SoundSource::new(&self.audio_context, &self.comp, note.key(), note.velocity(), start_time, end_time)?);
-            }
-        }
+    fn update_loop_count_in(&mut self){
+        let count_in = self.loop_points.and_then(|(start_time, _)| CountIn::at(&self.song, start_time, self.count_in_beats));
+        self.scheduler.set_loop_count_in(count_in);
+    }
 
-        self.current_time += delta_sec; 
-        
-        if self.loop_end_bar > self.loop_start_bar {
-            if self.current_time >= self.bars[self.loop_end_bar].end_time(){
-                let loop_start_time = self.bars[self.loop_start_bar].begin_time() - (self.current_time - self.bars[self.loop_end_bar].end_time());
-                self.seek_time(loop_start_time, true);
-            }
+    // 先読みで折り返した分も数え直すので、再生中なら今の位置から鳴らし直す
+    fn apply_speed_trainer(&mut self, trainer: Option<SpeedTrainer>){
+        self.scheduler.set_speed_trainer(trainer);
+        self.playhead.restart_loop_count(self.scheduler.rate());
+        self.notified_loop_count = 0;
+        if self.playing{
+            self.engine.stop_all();
+            self.reset_clock();
         }
+    }
 
+    // 回数を0に戻して、練習中なら最初の速さから始める (一時停止から再開しただけなら数え直さない)
+    fn restart_loop_count(&mut self){
+        let trainer = self.speed_trainer();
+        self.apply_speed_trainer(trainer);
+    }
 
-        if self.current_time >= self.song_length() {
-            self.playing = false;
-            self.current_time = self.song_length() - 0.0001;
+    // 音量だけのときは鳴っている音の音量を変えるだけにする (スライダーを動かしている間も音が途切れない)
+    // ミュートとソロは先読みして予約したノートを鳴らすかどうかが変わるので、
+    // 再生中なら鳴っている音を止めて今の位置から新しいミキサーで鳴らし直す
+    fn set_mixer(&mut self, mixer: Mixer){
+        let same_routing = self.engine.mixer().same_routing(&mixer);
+        self.engine.set_mixer(mixer);
+        if self.playing && !same_routing{
+            self.engine.stop_all();
+            self.reset_clock();
         }
+    }
+
+    // 予備拍の途中なら予備拍のあとの位置
+    fn resume_time(&self) -> f64{
+        if self.playing { self.current_time.max(self.playhead.content_start()) } else { self.current_time }
+    }
+
+    // 今の再生位置をエンジンの今の時刻に合わせ直す (予備拍の途中なら残りの予備拍は飛ばす)
+    fn reset_clock(&mut self){
+        self.current_time = self.resume_time();
+        let now = self.engine.now();
+        self.scheduler.reset(self.song.notes(), self.current_time, now);
+        self.playhead.reset(self.current_time, now);
+    }
+
+    // 再生速度 (1で元の速さ、0.5で半分の速さ) 音の高さは変わらない
+    pub fn playback_rate(&self) -> f64{
+        self.playhead.rate()
+    }
 
+    pub fn set_playback_rate(&mut self, rate: f64) -> Result<(), JsValue>{
+        let rate = checked_rate(rate).ok_or_else(|| JsValue::from_str(&format!("再生速度が数ではありません: {}", rate)))?;
+        self.scheduler.set_rate(rate);
+        self.playhead.set_rate(rate);
+        // 先読みで予約した音は前の速さなので、今の位置から鳴らし直す
+        if self.playing{
+            self.engine.stop_all();
+            self.reset_clock();
+        }
         Ok(())
     }
 
-    pub fn render(&self, context: &CanvasRenderingContext2d, left: f64, top: f64, width: f64, height: f64) -> Result<(), JsValue>{
-        let keybord_height = height * 0.1;
-        let min_key: u8 = 21;
-        let max_key: u8 = 108;
-        let rect = Rectangle::new(left, top, width, height);
-        let key_areas = calc_key_area(&rect, min_key, max_key);
-        
-        // 背景
-        context.set_fill_style_str("black");
-        context.fill_rect(rect.left(), rect.top(), rect.width(), rect.height());
-
-        // オクターブ分割線
-        context.set_stroke_style_str("gray");
-        for key in min_key..=max_key{
-            if key % 12 == 0{
-                let area = &key_areas[(key - min_key) as usize];
-                context.begin_path();
-                context.move_to(area.left(), area.top());
-                context.line_to(area.left(), area.bottom());
-                context.stroke();
-            }
+    // ループするたびに再生速度を step ずつ target_rate まで変えていく (limit 回弾いたらループを抜ける)
+    pub fn set_speed_trainer(&mut self, start_rate: f64, step: f64, target_rate: f64, limit: Option<u32>) -> Result<(), JsValue>{
+        if ![start_rate, step, target_rate].iter().all(|value| value.is_finite()){
+            return Err(JsValue::from_str("スピードトレーナーの速さが数ではありません"));
         }
-        
-        let display_start_sec = self.current_time;
-        let display_end_sec = display_start_sec + self.display_range_sec;
-        let pixel_per_sec = rect.height() / self.display_range_sec;
-        let current_time_pos = rect.height() - keybord_height;
-
-        // 小節線描画
-        context.set_stroke_style_str("gray");
-        context.set_fill_style_str("gray");
-        context.set_text_align("right");
-        context.set_text_baseline("bottom");
-        context.set_font("32px sans-serif");
-        for bar in self.bars.iter(){
-            if bar.begin_time() > display_end_sec || bar.end_time() < display_start_sec {
-                continue;
-            }
-            let bar_pos = current_time_pos - (bar.begin_time() - self.current_time) * pixel_per_sec;
-            context.begin_path();
-            context.move_to(rect.left(), bar_pos);
-            context.line_to(rect.right(), bar_pos);
-            context.stroke();
-            context.fill_text(&(bar.number() + 1).to_string(), rect.right() - 2.0, bar_pos - 2.0)?;
-            if bar.number() == self.bars.len() as u32 - 1{
-                // 最後の小節線も描画
-                let end_bar_pos = current_time_pos - (bar.end_time() - self.current_time) * pixel_per_sec;
-                context.begin_path();
-                context.move_to(rect.left(), end_bar_pos);
-                context.line_to(rect.right(), end_bar_pos);
-                context.stroke();
-                context.fill_text("おわり", rect.right() - 2.0, end_bar_pos - 2.0)?;
-            }
+        self.apply_speed_trainer(Some(SpeedTrainer{ start_rate, step, target_rate, limit }));
+        Ok(())
+    }
+
+    // 今の速さのままふつうのループに戻す
+    pub fn clear_speed_trainer(&mut self){
+        self.apply_speed_trainer(None);
+    }
+
+    pub fn speed_trainer(&self) -> Option<SpeedTrainer>{
+        self.scheduler.loop_counter().trainer()
+    }
+
+    // 再生を始めてからループを折り返した回数
+    pub fn loop_count(&self) -> u32{
+        self.playhead.loop_count()
+    }
+
+    // ループを折り返すたびに callback(回数, 再生速度) を呼ぶ
+    pub fn set_loop_callback(&mut self, callback: Option<Function>){
+        self.loop_callback = callback;
+    }
+
+    // 音の予約をどれだけ先までしておくか (秒)
+    pub fn lookahead(&self) -> f64{
+        self.scheduler.lookahead()
+    }
+
+    pub fn set_lookahead(&mut self, lookahead_sec: f64){
+        self.scheduler.set_lookahead(lookahead_sec);
+    }
+
+    // シークやループで飛んだ先で鳴っている途中の音を鳴らし直すか
+    pub fn retrigger_held_notes(&self) -> bool{
+        self.scheduler.retrigger()
+    }
+
+    pub fn set_retrigger_held_notes(&mut self, retrigger: bool){
+        self.scheduler.set_retrigger(retrigger);
+    }
+
+    pub fn skip(&mut self, delta: f64, clear_sounds:bool){
+        self.seek_time(self.resume_time() + delta, clear_sounds);
+    }
+
+    // 再生位置はAudioContextの時計から求めるので、フレームの間隔は渡さなくてよい
+    pub fn tick(&mut self) -> Result<(),JsValue>{
+        self.engine.update();
+        if !self.playing{
+            return Ok(());
         }
-        
-        const TRACK_FILL_COLORS: [&str; 4] = ["#4682B4", "#E66101", "#009E73", "#7B4173"];
-        const TRACK_STROKE_COLORS: [&str; 4] = ["#266294", "#C64101", "#007E53", "#5B2153"];
-
-        // ノート描画
-        let diplay_notes: Vec<&Note> = self.notes.iter().filter(|note| note.on_time() <= display_end_sec && display_start_sec <= note.off_time() && min_key <= note.key() && note.key() <= max_key).collect();
-
-        for track_no in 0..self.num_tracks{
-            let color_index = (track_no as usize % TRACK_FILL_COLORS.len()) as usize;
-            context.set_stroke_style_str(TRACK_STROKE_COLORS[color_index]);
-            context.set_fill_style_str(TRACK_FILL_COLORS[color_index]);
-            for note in diplay_notes.iter(){
-                if note.track() != track_no{
-                    continue;
-                }
-                
-                let area = &key_areas[(note.key() - min_key) as usize];
-                let note_top = current_time_pos - (note.off_time() - self.current_time) * pixel_per_sec;
-                let note_height = current_time_pos - (note.on_time() - self.current_time) * pixel_per_sec - note_top;
-                let note_left = area.left();
-                let note_width = area.width();
-
-                context.begin_path();
-                context.round_rect_with_f64(note_left, note_top, note_width, note_height, 4.0)?;
-                context.fill();
-                context.stroke();
-            }
+
+        let engine_time = self.engine.now();
+        let loop_range = self.loop_range();
+        let song_length = self.song_length();
+        self.scheduler.schedule(&mut self.engine, self.song.notes(), engine_time, loop_range, song_length)?;
+
+        // ループの折り返しはスケジューラが予約したところに合わせる
+        while let Some(wrap) = self.scheduler.take_wrap(engine_time){
+            self.playhead.follow(&wrap);
         }
-        
-        let playing_diplay_notes: Vec<&Note> = self.notes.iter().filter(|note| note.on_time() <= self.current_time && self.current_time <= note.off_time() && min_key <= note.key() && note.key() <= max_key).collect();
-
-        // 白鍵
-        let white_note_height = keybord_height;
-        context.set_stroke_style_str("gray");
-        context.set_fill_style_str("white");
-        for key in min_key..=max_key{
-            match key % 12{
-                0 | 2 | 4 | 5 | 7 | 9 | 11 => {
-                    let area = &key_areas[(key - min_key) as usize];
-                    let top = area.bottom() - white_note_height;
-                    context.fill_rect(area.left(), top, area.width(), white_note_height);
-                    context.begin_path();
-                    context.move_to(area.left(), top);
-                    context.line_to(area.left(), area.bottom());
-                    context.stroke();
-                },
-                _ => (),
+        self.current_time = self.playhead.position(&self.engine);
+        let loop_count = self.loop_count();
+        if self.notified_loop_count != loop_count{
+            self.notified_loop_count = loop_count;
+            if let Some(callback) = self.loop_callback.as_ref(){
+                callback.call2(&JsValue::NULL, &JsValue::from(loop_count), &JsValue::from(self.playback_rate()))?;
             }
         }
 
-        // 再生している白鍵
-        for track_no in 0..self.num_tracks{
-            let color_index = (track_no as usize % TRACK_FILL_COLORS.len()) as usize;
-            context.set_stroke_style_str(TRACK_STROKE_COLORS[color_index]);
-            context.set_fill_style_str(TRACK_FILL_COLORS[color_index]);
-            for note in playing_diplay_notes.iter(){
-                if note.track() != track_no{
-                    continue;
-                }
-                match note.key() % 12{
-                0 | 2 | 4 | 5 | 7 | 9 | 11 => {
-                    let area = &key_areas[(note.key() - min_key) as usize];
-                    let top = area.bottom() - white_note_height;
-                    context.fill_rect(area.left(), top, area.width(), white_note_height);
-                    context.begin_path();
-                    context.move_to(area.left(), top);
-                    context.line_to(area.left(), area.bottom());
-                    context.stroke();
-                },
-                _ => (),
-            }
-            }
+        if self.current_time >= song_length {
+            self.playing = false;
+            self.current_time = song_length - 0.0001;
         }
 
-        // 黒鍵
-        let black_note_height = keybord_height * 0.6;
-        context.set_fill_style_str("black");
-        for key in min_key..=max_key{
-            match key % 12{
-                1 | 3 | 6 | 8 | 10 => {
-                    let area = &key_areas[(key - min_key) as usize];
-                    context.fill_rect(area.left(), area.bottom() - white_note_height, area.width(), black_note_height);
-                },
-                _ => (),
-            }
-        }
+        Ok(())
+    }
 
-        // 再生している黒鍵
-        for track_no in 0..self.num_tracks{
-            let color_index = (track_no as usize % TRACK_FILL_COLORS.len()) as usize;
-            context.set_stroke_style_str(TRACK_STROKE_COLORS[color_index]);
-            context.set_fill_style_str(TRACK_FILL_COLORS[color_index]);
-            for note in playing_diplay_notes.iter(){
-                if note.track() != track_no{
-                    continue;
-                }
-                match note.key() % 12{
-                1 | 3 | 6 | 8 | 10 => {
-                    let area = &key_areas[(note.key() - min_key) as usize];
-                    context.fill_rect(area.left(), area.bottom() - white_note_height, area.width(), black_note_height);
-                },
-                _ => (),
-            }
-            }
+    fn loop_range(&self) -> Option<(f64, f64)>{
+        self.loop_points
+    }
+
+    fn beat_time(&self, bar: usize, beat: f64) -> Result<f64, JsValue>{
+        let bar = self.song.bars().get(bar).ok_or_else(|| JsValue::from_str(&format!("小節がありません: {}", bar)))?;
+        Ok(bar.beat_time(beat))
+    }
+
+    fn render_options(&self) -> RenderOptions{
+        RenderOptions{
+            current_time: self.current_time,
+            display_range_sec: self.display_range_sec,
+            velocity_curves: self.engine.velocity_curves().clone(),
+            mixer: self.engine.mixer().clone(),
+            dim_muted: self.dim_muted_tracks,
+            beat_flash: self.metronome().enabled,
         }
+    }
+
+    // 指定した時刻の画面をSVGで取得する
+    pub fn render_svg(&self, time: f64, width: f64, height: f64) -> String{
+        render_svg_with_options(&self.song, &RenderOptions{ current_time: time, ..self.render_options() }, width, height)
+    }
+
+    // 曲の start_time から end_time までをモノラルのWAVにする
+    pub fn render_wav(&self, start_time: f64, end_time: f64, sample_rate: u32) -> Vec<u8>{
+        encode_wav(&self.offline_engine(sample_rate).render_song(&self.song, start_time, end_time), 1, sample_rate)
+    }
+
+    // 左右の位置も含めたステレオのWAVにする
+    pub fn render_wav_stereo(&self, start_time: f64, end_time: f64, sample_rate: u32) -> Vec<u8>{
+        encode_wav(&self.offline_engine(sample_rate).render_song_stereo(&self.song, start_time, end_time), 2, sample_rate)
+    }
+
+    // 今の音色や設定で鳴らすオフラインのエンジン
+    fn offline_engine(&self, sample_rate: u32) -> OfflineEngine{
+        let mut engine = OfflineEngine::new(sample_rate);
+        engine.set_volume(self.volume());
+        engine.set_patches(self.engine.patches().clone());
+        engine.set_velocity_curves(self.engine.velocity_curves().clone());
+        engine.set_stereo(self.engine.stereo().clone());
+        engine.set_mixer(self.engine.mixer().clone());
+        engine.set_effects(*self.engine.effects());
+        engine.set_soundfont_preset(self.soundfont.as_ref().and_then(|(soundfont, preset)| soundfont.presets().get(*preset)).cloned());
+        engine
+    }
+
+    // 曲全体を印刷用のページにしたもの
+    pub fn export_sheet_svg(&self, bars_per_system: usize) -> Vec<String>{
+        let layout = SheetLayout{ bars_per_system: bars_per_system.max(1), ..Default::default() };
+        render_sheet_svg(&self.song, &layout)
+    }
+
+    pub fn export_sheet_pdf(&self, bars_per_system: usize) -> Vec<u8>{
+        let layout = SheetLayout{ bars_per_system: bars_per_system.max(1), ..Default::default() };
+        render_sheet_pdf(&self.song, &layout)
+    }
+
+    pub fn render(&self, context: &CanvasRenderingContext2d, left: f64, top: f64, width: f64, height: f64) -> Result<(), JsValue>{
+        let mut backend = Canvas2dBackend::new(context);
+        render_scene(&mut backend, &self.song, &self.render_options(), &Rectangle::new(left, top, width, height))
+    }
+}
+
+// IndexedDBへのキャッシュやサーバーとのやり取り用に曲と設定をまとめてJSONにする
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SavedSession{
+    song: Song,
+    #[serde(default)]
+    settings: PlayerSettings,
+    #[serde(default)]
+    current_time: f64,
+}
+
+#[cfg(feature = "serde")]
+#[wasm_bindgen]
+impl MidiPlayer{
+    pub fn export_json(&self) -> Result<String, JsValue>{
+        let session = SavedSession{
+            song: self.song.clone(),
+            settings: self.settings(),
+            current_time: self.current_time,
+        };
+        serde_json::to_string(&session).map_err(|e| JsValue::from_str(&format!("Error exporting JSON: {}", e)))
+    }
+
+    pub fn import_json(&mut self, json: &str) -> Result<(), JsValue>{
+        let session: SavedSession = serde_json::from_str(json).map_err(|e| JsValue::from_str(&format!("Error importing JSON: {}", e)))?;
+
+        self.playing = false;
+        self.engine.stop_all();
+        self.song = session.song.sorted();
+        self.apply_settings(&session.settings)?;
+        self.seek_time(session.current_time, true);
 
         Ok(())
     }
 }
 
+impl MidiPlayer{
+    pub fn settings(&self) -> PlayerSettings{
+        PlayerSettings{
+            display_range_sec: self.display_range_sec,
+            volume: self.volume(),
+            loop_start_bar: None,
+            loop_end_bar: None,
+            loop_points: self.loop_points,
+            sections: self.sections.clone(),
+            patches: self.engine.patches().clone(),
+            velocity_curves: self.engine.velocity_curves().clone(),
+            stereo: self.engine.stereo().clone(),
+            mixer: self.engine.mixer().clone(),
+            effects: *self.engine.effects(),
+            playback_rate: self.playback_rate(),
+            speed_trainer: self.speed_trainer(),
+            metronome: self.metronome(),
+            count_in_beats: self.count_in_beats,
+        }
+    }
+
+    pub fn apply_settings(&mut self, settings: &PlayerSettings) -> Result<(), JsValue>{
+        self.set_display_range(settings.display_range_sec);
+        self.set_volume(settings.volume);
+        match (settings.loop_points, settings.loop_start_bar, settings.loop_end_bar){
+            (Some((start_time, end_time)), _, _) => self.set_loop_points(start_time, end_time),
+            (None, Some(start_bar), Some(end_bar)) => {
+                let num_bars = self.song.bars().len();
+                let clamp_bar = |bar: usize| bar.min(num_bars.saturating_sub(1));
+                self.set_loop_bars(clamp_bar(start_bar), clamp_bar(end_bar));
+            },
+            _ => self.clear_loop(),
+        }
+        self.sections = settings.sections.clone();
+        self.engine.set_patches(settings.patches.clone());
+        self.engine.set_velocity_curves(settings.velocity_curves.clone());
+        self.engine.set_stereo(settings.stereo.clone());
+        self.set_mixer(settings.mixer.clone());
+        self.set_playback_rate(settings.playback_rate)?;
+        self.apply_speed_trainer(settings.speed_trainer);
+        self.set_metronome(settings.metronome);
+        self.set_count_in_beats(settings.count_in_beats);
+        self.engine.set_effects(settings.effects)
+    }
+}
+
+#[cfg(test)]
 mod test{
+    use super::metronome::{Accent, Click};
+    use super::{AudioEngine, Note};
+    use std::convert::Infallible;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    struct Played{
+        key: u8,
+        start_time: f64,
+        end_time: f64,
+        offset: f64,
+        // その音を鳴らしたときの再生速度
+        rate: f64,
+    }
+
+    // 時計は手で進めて、鳴らしたものを記録するだけのエンジン
+    #[derive(Default)]
+    struct RecordingEngine{
+        time: f64,
+        rate: f64,
+        played: Vec<Played>,
+        clicks: Vec<(Accent, f64)>,
+        released: Vec<f64>,
+    }
+
+    impl RecordingEngine{
+        // (鍵盤, 開始, 途中から鳴らす経過時間)
+        fn starts(&self) -> Vec<(u8, f64, f64)>{
+            self.played.iter().map(|played| (played.key, played.start_time, played.offset)).collect()
+        }
+        fn keys(&self) -> Vec<u8>{
+            self.played.iter().map(|played| played.key).collect()
+        }
+    }
+
+    impl AudioEngine for RecordingEngine{
+        type Error = Infallible;
+        fn current_time(&self) -> f64{ self.time }
+        fn play_note(&mut self, note: &Note, start_time: f64, end_time: f64, offset: f64) -> Result<(), Infallible>{
+            self.played.push(Played{ key: note.key(), start_time, end_time, offset, rate: self.rate });
+            Ok(())
+        }
+        fn play_click(&mut self, click: &Click, start_time: f64) -> Result<(), Infallible>{
+            self.clicks.push((click.accent, start_time));
+            Ok(())
+        }
+        fn release_all(&mut self, time: f64){
+            self.released.push(time);
+        }
+        fn cancel_scheduled(&mut self, time: f64){
+            self.played.retain(|played| played.start_time <= time);
+            self.clicks.retain(|&(_, start_time)| start_time <= time);
+        }
+        fn set_playback_rate(&mut self, rate: f64){
+            self.rate = rate;
+        }
+        fn update(&mut self){}
+        fn volume(&self) -> f32{ 1.0 }
+        fn set_volume(&mut self, _volume: f32){}
+    }
+
     #[test]
     fn test_parse_midi(){
         let data = include_bytes!("../tests/assets/test.mid");
@@ -544,14 +1166,1198 @@ mod test{
         
         let midi = result.unwrap();
 
-        assert!(midi.1.len() > 0);
+        assert!(!midi.1.is_empty());
         for note in &midi.1{
             println!("{:?}", note);
         }
 
-        assert!(midi.0.len() > 0);
+        assert!(!midi.0.is_empty());
         for bar in &midi.0{
             println!("{:?}", bar);
         }
     }
+
+    #[test]
+    fn test_parse_report(){
+        // フォーマット1、480ティックで4分音符
+        let smf = |tracks: &[&[u8]]|{
+            let mut data = b"MThd".to_vec();
+            data.extend(6u32.to_be_bytes());
+            data.extend(1u16.to_be_bytes());
+            data.extend((tracks.len() as u16).to_be_bytes());
+            data.extend(480u16.to_be_bytes());
+            for track in tracks.iter(){
+                data.extend(b"MTrk");
+                data.extend((track.len() as u32).to_be_bytes());
+                data.extend(*track);
+            }
+            data
+        };
+        // 3/2拍子で、120BPMから4分音符1つ後に100BPM
+        let conductor: &[u8] = &[
+            0x00, 0xFF, 0x03, 0x04, b'C', b'o', b'n', b'd',
+            0x00, 0xFF, 0x58, 0x04, 0x03, 0x01, 0x18, 0x08,
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            0x83, 0x60, 0xFF, 0x51, 0x03, 0x09, 0x27, 0xC0,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        // C4を弾いて、対応するNoteOnがないE4のNoteOffと、NoteOffがないG4
+        let piano: &[u8] = &[
+            0x00, 0xFF, 0x03, 0x05, b'P', b'i', b'a', b'n', b'o',
+            0x00, 0x90, 0x3C, 0x64,
+            0x83, 0x60, 0x80, 0x3C, 0x00,
+            0x00, 0x80, 0x40, 0x00,
+            0x00, 0x90, 0x43, 0x64,
+            0x83, 0x60, 0xFF, 0x2F, 0x00,
+        ];
+        let (bars, notes, num_tracks, report) = super::parse_midi_with_report(&smf(&[conductor, piano])).unwrap();
+        assert_eq!(num_tracks, 2);
+        assert_eq!(report.track_names, vec!["Cond", "Piano"]);
+        assert_eq!(report.tempo_changes.len(), 2);
+        assert!((report.tempo_changes[1].0 - 0.5).abs() < 0.01 && (report.tempo_changes[1].1 - 100.0).abs() < 1e-9);
+        assert_eq!(report.warnings.len(), 2);
+        // 2分音符が3つの小節は4分音符6つ分 (0.5秒 + 100BPMで5拍)
+        assert_eq!(bars.len(), 1);
+        assert_eq!(bars[0].time_signature(), (3, 2));
+        assert!((bars[0].end_time() - 3.5).abs() < 0.01);
+        // NoteOffがないノートは曲の最後 (0.5秒 + 100BPMで1拍) で止める
+        let open = notes.iter().find(|note| note.key() == 0x43).unwrap();
+        assert!((open.off_time() - 1.1).abs() < 0.01);
+
+        // テンポ0は無視して警告にする
+        let zero_tempo: &[u8] = &[
+            0x00, 0xFF, 0x51, 0x03, 0x00, 0x00, 0x00,
+            0x00, 0x90, 0x3C, 0x64,
+            0x83, 0x60, 0x80, 0x3C, 0x00,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let (_, notes, _, report) = super::parse_midi_with_report(&smf(&[zero_tempo])).unwrap();
+        assert_eq!(report.tempo_changes, vec![(0.0, 120.0)]);
+        assert_eq!(report.warnings.len(), 1);
+        assert!((notes[0].off_time() - 0.5).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_parse_song_formats(){
+        use super::loader::{detect_format, parse_song, SongFormat};
+
+        let smf = include_bytes!("../tests/assets/test.mid");
+        assert_eq!(detect_format(smf, ""), Some(SongFormat::Smf));
+
+        // SMFをRIFF RMIDで包む
+        let mut rmid: Vec<u8> = Vec::new();
+        rmid.extend(b"RIFF");
+        rmid.extend(((smf.len() + 12) as u32).to_le_bytes());
+        rmid.extend(b"RMIDdata");
+        rmid.extend((smf.len() as u32).to_le_bytes());
+        rmid.extend(smf);
+        assert_eq!(detect_format(&rmid, "song.rmi"), Some(SongFormat::Rmid));
+        assert_eq!(parse_song(&rmid, "song.rmi").unwrap().1.len(), super::parse_midi(smf).unwrap().1.len());
+
+        let abc = "X:1\nT:Scale\nM:4/4\nL:1/4\nQ:1/4=60\nK:G\nGABc|d2 [GBd]2|]\n";
+        assert_eq!(detect_format(abc.as_bytes(), ""), Some(SongFormat::Abc));
+        let (bars, notes, num_tracks) = parse_song(abc.as_bytes(), "").unwrap();
+        assert_eq!(num_tracks, 1);
+        assert_eq!(bars.len(), 2);
+        assert_eq!(notes.len(), 8);
+        assert_eq!(notes[2].key(), 71);
+        assert_eq!(notes[4].key(), 74);
+        assert!((notes[4].off_time() - 6.0).abs() < 1e-9);
+
+        let xml = r#"<?xml version="1.0"?>
+<score-partwise><part id="P1">
+<measure number="1"><attributes><divisions>2</divisions><time><beats>3</beats><beat-type>4</beat-type></time></attributes>
+<sound tempo="90"/>
+<note><pitch><step>F</step><alter>1</alter><octave>4</octave></pitch><duration>4</duration><tie type="start"/></note>
+<note><pitch><step>F</step><alter>1</alter><octave>4</octave></pitch><duration>2</duration><tie type="stop"/></note>
+</measure>
+<measure number="2"><note><rest/><duration>6</duration></note></measure>
+</part></score-partwise>"#;
+        assert_eq!(detect_format(xml.as_bytes(), ""), Some(SongFormat::MusicXml));
+        let (bars, notes, _) = parse_song(xml.as_bytes(), "").unwrap();
+        assert_eq!(bars.len(), 2);
+        assert!((bars[1].begin_time() - 2.0).abs() < 1e-9);
+        assert_eq!(notes.len(), 1);
+        assert_eq!(notes[0].key(), 66);
+        assert!((notes[0].off_time() - 2.0).abs() < 1e-9);
+        // 長さや拍子が求まらない値はエラーにする
+        for (from, to) in [("<divisions>2</divisions>", "<divisions>0</divisions>"), ("<divisions>2</divisions>", "<divisions>NaN</divisions>"), ("<beat-type>4</beat-type>", "<beat-type>0</beat-type>"), ("<beat-type>4</beat-type>", "<beat-type>256</beat-type>"), ("<beats>3</beats>", "<beats>-3</beats>")]{
+            assert!(parse_song(xml.replace(from, to).as_bytes(), "").is_err());
+        }
+
+        // score-timewise は読めないので判定しない
+        let timewise = r#"<?xml version="1.0"?><score-timewise><measure number="1"></measure></score-timewise>"#;
+        assert_eq!(detect_format(timewise.as_bytes(), ""), None);
+
+        // サイズが壊れたRMIDチャンク
+        let mut broken = rmid[..20].to_vec();
+        broken[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse_song(&broken, "song.rmi").is_err());
+
+        // 巨大な複数小節休符は上限で打ち切る
+        let abc = "X:1\nM:4/4\nL:1/4\nK:C\nZ99999999999|C4|]\n";
+        let (bars, notes, _) = parse_song(abc.as_bytes(), "").unwrap();
+        assert!(bars.len() <= 1002);
+        assert_eq!(notes.len(), 1);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_song_json(){
+        let (bars, notes, num_tracks) = super::parse_midi(include_bytes!("../tests/assets/test.mid")).unwrap();
+        let song = super::Song::new(bars, notes, num_tracks);
+        let json = song.to_json().unwrap();
+        let restored = super::Song::from_json(&json).unwrap();
+        assert_eq!(restored.num_tracks(), song.num_tracks());
+        assert_eq!(restored.bars().len(), song.bars().len());
+        assert_eq!(restored.notes().len(), song.notes().len());
+        assert_eq!(restored.notes()[0].key(), song.notes()[0].key());
+        assert_eq!(restored.notes()[0].off_time(), song.notes()[0].off_time());
+
+        // 小節のループは古いセッションにあったときだけ読み、書き出さない
+        use super::PlayerSettings;
+        let json = serde_json::to_string(&PlayerSettings::default()).unwrap();
+        assert!(!json.contains("loop_start_bar") && !json.contains("loop_end_bar"));
+        let restored: PlayerSettings = serde_json::from_str(&json).unwrap();
+        assert_eq!((restored.loop_start_bar, restored.loop_end_bar, restored.loop_points), (None, None, None));
+        let legacy: PlayerSettings = serde_json::from_str(r#"{"loop_start_bar":1,"loop_end_bar":2}"#).unwrap();
+        assert_eq!((legacy.loop_start_bar, legacy.loop_end_bar), (Some(1), Some(2)));
+    }
+
+    #[test]
+    fn test_render_svg(){
+        let (bars, notes, num_tracks) = super::parse_midi(include_bytes!("../tests/assets/test.mid")).unwrap();
+        let song = super::Song::new(bars, notes, num_tracks);
+        let svg = super::render_svg(&song, 0.0, 3.0, 800.0, 600.0);
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        // 背景 + 52白鍵 + 36黒鍵 + 表示範囲のノート + 押されている鍵盤
+        let display_notes = song.notes().iter().filter(|note| note.on_time() <= 3.0).count();
+        let playing_notes = song.notes().iter().filter(|note| note.on_time() <= 0.0).count();
+        assert_eq!(svg.matches("<rect").count(), 1 + 52 + 36 + display_notes + playing_notes);
+        assert!(svg.contains(">1</text>"));
+
+        // 拍のランプも画面と同じように描ける
+        let options = super::RenderOptions{ beat_flash: true, ..Default::default() };
+        let svg = super::render_svg_with_options(&song, &options, 800.0, 600.0);
+        assert_eq!(svg.matches("<rect").count(), 1 + 52 + 36 + display_notes + playing_notes + 1);
+    }
+
+    #[test]
+    fn test_render_display_list(){
+        use super::{render_scene, DisplayList, DrawCommand, Rectangle, RenderOptions};
+
+        let (bars, notes, num_tracks) = super::parse_midi(include_bytes!("../tests/assets/test.mid")).unwrap();
+        let song = super::Song::new(bars, notes, num_tracks);
+        let mut display_list = DisplayList::new();
+        let rect = Rectangle::new(0.0, 0.0, 880.0, 600.0);
+        let options = RenderOptions{ current_time: 0.0, display_range_sec: 3.0, ..Default::default() };
+        let Ok(()) = render_scene(&mut display_list, &song, &options, &rect);
+        let commands = display_list.commands();
+
+        assert_eq!(commands[0], DrawCommand::FillStyle("black".to_string()));
+        assert_eq!(commands[1], DrawCommand::FillRect(rect.clone()));
+
+        // 最初のノートは現在位置(鍵盤の上端)から始まる
+        let first_note = &song.notes()[0];
+        let note_rect = commands.iter().find_map(|command| match command{
+            DrawCommand::RoundRect(rect, _) => Some(rect.clone()),
+            _ => None,
+        }).unwrap();
+        assert!((note_rect.bottom() - 540.0).abs() < 1e-9);
+        assert!((note_rect.height() - (first_note.off_time() - first_note.on_time()) * 200.0).abs() < 1e-9);
+
+        // 同じ色を続けて設定し直さない
+        let fill_styles: Vec<&DrawCommand> = commands.iter().filter(|command| matches!(command, DrawCommand::FillStyle(_))).collect();
+        assert!(fill_styles.windows(2).all(|pair| pair[0] != pair[1]));
+        let num_notes = commands.iter().filter(|command| matches!(command, DrawCommand::RoundRect(..))).count();
+        assert!(fill_styles.len() < num_notes);
+
+        // 表示範囲の小節番号
+        let texts: Vec<&str> = commands.iter().filter_map(|command| match command{
+            DrawCommand::Text(text, _, _) => Some(text.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(texts, vec!["1", "2", "3", "おわり"]);
+    }
+
+    #[test]
+    fn test_render_sheet(){
+        use super::{render_sheet_pdf, render_sheet_svg, SheetLayout};
+
+        let (bars, notes, num_tracks) = super::parse_midi(include_bytes!("../tests/assets/test.mid")).unwrap();
+        let song = super::Song::new(bars, notes, num_tracks);
+        let layout = SheetLayout{ bars_per_system: 1, systems_per_page: 2, ..Default::default() };
+        assert_eq!(layout.num_pages(&song), 2);
+
+        let pages = render_sheet_svg(&song, &layout);
+        assert_eq!(pages.len(), 2);
+        assert!(pages[1].contains(">2 / 2</text>"));
+
+        let pdf = render_sheet_pdf(&song, &layout);
+        assert!(pdf.starts_with(b"%PDF-1.4"));
+        assert!(pdf.ends_with(b"%%EOF\n"));
+        assert_eq!(String::from_utf8_lossy(&pdf).matches("/Type /Page ").count(), 2);
+    }
+
+    #[test]
+    fn test_render_video(){
+        use super::{render_frames, render_video_audio, render_video_audio_stereo, VideoSettings};
+
+        let (bars, notes, num_tracks) = super::parse_midi(include_bytes!("../tests/assets/test.mid")).unwrap();
+        let song = super::Song::new(bars, notes, num_tracks);
+        let settings = VideoSettings{ width: 160, height: 90, fps: 10.0, start_time: 0.0, end_time: 1.0, sample_rate: 8000, ..VideoSettings::for_song(&song) };
+
+        let frames: Vec<Vec<u8>> = render_frames(&song, &settings).collect();
+        assert_eq!(frames.len(), 10);
+        assert!(frames.iter().all(|frame| frame.len() == 160 * 90 * 4));
+        // 同じ時刻なら同じ絵になる
+        assert_eq!(frames[3], super::render_frame(&song, 0.3, settings.display_range_sec, 160, 90));
+        // 左上は背景の黒
+        assert_eq!(&frames[0][..4], &[0, 0, 0, 255]);
+        // 大きさ0のフレームでも描ける
+        assert!(super::render_frame(&song, 0.3, settings.display_range_sec, 0, 0).is_empty());
+
+        let wav = render_video_audio(&song, &settings);
+        assert!(wav.starts_with(b"RIFF"));
+        assert_eq!(wav.len(), 44 + 8000 * 2);
+        assert!(wav[44..].chunks(2).any(|sample| sample != [0, 0]));
+        assert_eq!(render_video_audio_stereo(&song, &settings).len(), 44 + 8000 * 4);
+
+        // 拍のランプやミュートの暗さも画面と同じ設定で描く
+        let flash = VideoSettings{ beat_flash: true, ..settings.clone() };
+        assert_ne!(render_frames(&song, &flash).next(), render_frames(&song, &settings).next());
+        let mut muted = VideoSettings{ mixer: super::Mixer::default(), ..settings.clone() };
+        muted.mixer.tracks.set(0, super::MixerStrip{ mute: true, ..Default::default() });
+        let undimmed = VideoSettings{ dim_muted: false, ..muted.clone() };
+        assert_ne!(render_frames(&song, &muted).nth(3), render_frames(&song, &undimmed).nth(3));
+        assert_eq!(render_frames(&song, &undimmed).nth(3), Some(frames[3].clone()));
+    }
+
+    #[test]
+    fn test_offline_engine(){
+        use super::{AudioEngine, Note, OfflineEngine};
+
+        let mut engine = OfflineEngine::new(8000);
+        let Ok(()) = engine.play_note(&Note::new(0.0, 0.5, 69, 127, 0), 0.5, 1.0, 0.0);
+        let samples = engine.render(0.0, 3.0);
+        assert_eq!(samples.len(), 24000);
+        assert!(samples[..4000].iter().all(|&x| x == 0.0));
+        assert!(samples[4000..12000].iter().any(|&x| x.abs() > 0.1));
+        // リリースが終わったら無音
+        assert!(samples[22000..].iter().all(|&x| x.abs() < 0.01));
+
+        engine.set_current_time(3.0);
+        engine.update();
+        assert_eq!(engine.num_voices(), 0);
+
+        // 止めたときは短いフェードで無音になる
+        let Ok(()) = engine.play_note(&Note::new(0.0, 2.0, 69, 127, 0), 3.0, 5.0, 0.0);
+        let Ok(()) = engine.play_note(&Note::new(0.0, 1.0, 72, 127, 0), 4.0, 5.0, 0.0);
+        engine.release_all(3.5);
+        assert_eq!(engine.num_voices(), 1);
+        let samples = engine.render(3.0, 4.0);
+        assert!(samples[..4000].iter().any(|&x| x.abs() > 0.1));
+        assert!(samples[4300..].iter().all(|&x| x == 0.0));
+
+        // トラックごとの音色 pluckはすぐに減衰する
+        use super::SynthPatch;
+        assert!(SynthPatch::preset_names().iter().all(|name| SynthPatch::preset(name).is_some()));
+        let mut engine = OfflineEngine::new(8000);
+        engine.set_patch(1, SynthPatch::preset("pluck").unwrap());
+        let Ok(()) = engine.play_note(&Note::new(0.0, 2.0, 69, 127, 0), 0.0, 2.0, 0.0);
+        let Ok(()) = engine.play_note(&Note::new(0.0, 2.0, 69, 127, 1), 3.0, 5.0, 0.0);
+        let samples = engine.render(0.0, 6.0);
+        assert!(samples[12000..16000].iter().any(|&x| x.abs() > 0.1));
+        assert!(samples[36000..40000].iter().all(|&x| x.abs() < 0.01));
+
+        // JSから来たNaNや無限大、負の値は鳴らせる範囲にする
+        let broken = SynthPatch{ attack: f64::NAN, decay: -1.0, release: f64::INFINITY, cutoff_ratio: f64::NAN, resonance: f64::NEG_INFINITY, detune: f64::NAN, ..SynthPatch::default() };
+        let patch = broken.clamped();
+        assert_eq!((patch.attack, patch.decay, patch.release), (SynthPatch::default().attack, 0.0, 20.0));
+        assert_eq!((patch.cutoff_ratio, patch.resonance, patch.detune), (SynthPatch::default().cutoff_ratio, 0.0, 0.0));
+        assert_eq!(SynthPatch::preset("pad").unwrap().clamped(), SynthPatch::preset("pad").unwrap());
+        let mut patches = super::TrackPatches::default();
+        patches.set(2, broken);
+        let mut engine = OfflineEngine::new(8000);
+        engine.set_patches(patches);
+        assert_eq!(engine.patches().get(2), patch);
+        let Ok(()) = engine.play_note(&Note::new(0.0, 0.5, 69, 127, 2), 0.0, 0.5, 0.0);
+        assert!(engine.render(0.0, 1.0).iter().all(|x| x.is_finite()));
+
+        // ピアノは押さえていても減衰して、高い音ほど早く消え、離すとすぐ止まる
+        use super::piano::{decay_time, inharmonicity};
+        assert!(decay_time(30) > decay_time(90) && inharmonicity(30) < inharmonicity(90));
+        let rms = |samples: &[f32]| (samples.iter().map(|&x| (x * x) as f64).sum::<f64>() / samples.len() as f64).sqrt();
+        let mut engine = OfflineEngine::new(8000);
+        engine.set_patch(0, SynthPatch::preset("piano").unwrap());
+        let Ok(()) = engine.play_note(&Note::new(0.0, 3.0, 84, 100, 0), 0.0, 3.0, 0.0);
+        let samples = engine.render(0.0, 4.0);
+        assert!(rms(&samples[800..1600]) > 4.0 * rms(&samples[20000..20800]));
+        assert!(rms(&samples[20000..20800]) > 0.0);
+        assert!(samples[26400..].iter().all(|&x| x == 0.0));
+
+        // 半分の速さでは倍の時間をかけて同じように減衰する
+        use super::piano::partials;
+        assert!(partials(84, 100, 1000.0, 1.0).iter().zip(partials(84, 100, 1000.0, 0.5)).all(|(a, b)| (b.decay - 2.0 * a.decay).abs() < 1e-9));
+        let mut slow = OfflineEngine::new(8000);
+        slow.set_patch(0, SynthPatch::preset("piano").unwrap());
+        slow.set_playback_rate(0.5);
+        let Ok(()) = slow.play_note(&Note::new(0.0, 3.0, 84, 100, 0), 0.0, 6.0, 0.0);
+        let slow_samples = slow.render(0.0, 8.0);
+        assert!((rms(&slow_samples[40000..41600]) / rms(&samples[20000..20800]) - 1.0).abs() < 0.2);
+
+        let (bars, notes, num_tracks) = super::parse_midi(include_bytes!("../tests/assets/test.mid")).unwrap();
+        let song = super::Song::new(bars, notes, num_tracks);
+        // render_wav は今までどおりモノラルで、ステレオは render_wav_stereo
+        let wav = super::render_wav(&song, 1.0, 2.0, 8000);
+        assert_eq!(wav.len(), 44 + 8000 * 2);
+        let wav = super::render_wav_stereo(&song, 1.0, 2.0, 8000);
+        assert_eq!(wav.len(), 44 + 8000 * 4);
+        let mono = super::render_song(&song, 1.0, 2.0, 8000, 1.0, &Default::default());
+        let stereo = super::render_song_stereo(&song, 1.0, 2.0, 8000, 1.0, &Default::default());
+        assert_eq!(stereo.len(), mono.len() * 2);
+        assert!(mono.iter().zip(stereo.chunks_exact(2)).all(|(m, lr)| (m - (lr[0] + lr[1]) / std::f32::consts::SQRT_2).abs() < 1e-5));
+    }
+
+    #[test]
+    fn test_soundfont(){
+        use super::soundfont::SoundFont;
+        use super::{AudioEngine, Note, OfflineEngine};
+
+        let chunk = |id: &[u8], body: &[u8]|{
+            let mut chunk = id.to_vec();
+            chunk.extend((body.len() as u32).to_le_bytes());
+            chunk.extend(body);
+            if body.len() % 2 == 1{
+                chunk.push(0);
+            }
+            chunk
+        };
+        let list = |list_type: &[u8], chunks: &[Vec<u8>]|{
+            let mut body = list_type.to_vec();
+            body.extend(chunks.concat());
+            chunk(b"LIST", &body)
+        };
+        let record = |name: &str, fields: &[&[u8]], size: usize|{
+            let mut record = name.as_bytes().to_vec();
+            record.resize(20, 0);
+            record.extend(fields.concat());
+            record.resize(size, 0);
+            record
+        };
+        let generator = |op: u16, amount: i16| [op.to_le_bytes(), amount.to_le_bytes()].concat();
+
+        // 20サンプル周期の波で、3周期分をループする
+        let smpl: Vec<u8> = (0..100).map(|i| ((i as f64 * std::f64::consts::PI / 10.0).sin() * 16000.0) as i16).flat_map(i16::to_le_bytes).collect();
+        let shdr = [
+            record("sine", &[&0u32.to_le_bytes(), &100u32.to_le_bytes(), &20u32.to_le_bytes(), &80u32.to_le_bytes(), &8000u32.to_le_bytes(), &[60, 0], &0u16.to_le_bytes(), &1u16.to_le_bytes()], 46),
+            record("EOS", &[], 46),
+        ].concat();
+        let pdta = list(b"pdta", &[
+            chunk(b"phdr", &[record("Piano", &[&0u16.to_le_bytes(), &0u16.to_le_bytes(), &0u16.to_le_bytes()], 38), record("EOP", &[&0u16.to_le_bytes(), &0u16.to_le_bytes(), &1u16.to_le_bytes()], 38)].concat()),
+            chunk(b"pbag", &[0, 0, 0, 0, 2, 0, 0, 0]),
+            chunk(b"pmod", &[0; 10]),
+            // 72より上はSoundFontにない
+            chunk(b"pgen", &[generator(43, 72 << 8), generator(41, 0), generator(0, 0)].concat()),
+            chunk(b"inst", &[record("Sine", &[&0u16.to_le_bytes()], 22), record("EOI", &[&1u16.to_le_bytes()], 22)].concat()),
+            chunk(b"ibag", &[0, 0, 0, 0, 3, 0, 0, 0]),
+            chunk(b"imod", &[0; 10]),
+            // ループあり、リリース0.5秒
+            chunk(b"igen", &[generator(54, 1), generator(38, -1200), generator(53, 0), generator(0, 0)].concat()),
+            chunk(b"shdr", &shdr),
+        ]);
+        let mut body = b"sfbk".to_vec();
+        body.extend(list(b"INFO", &[chunk(b"ifil", &[2, 0, 1, 0])]));
+        body.extend(list(b"sdta", &[chunk(b"smpl", &smpl)]));
+        body.extend(pdta);
+        let sf2 = chunk(b"RIFF", &body);
+
+        assert!(SoundFont::parse(b"RIFF\0\0\0\0WAVE").is_err());
+        // チャンクの大きさが壊れていても落ちない
+        let mut broken = sf2.clone();
+        broken[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SoundFont::parse(&broken).is_err());
+        broken[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SoundFont::parse(&broken).is_err());
+        let soundfont = SoundFont::parse(&sf2).unwrap();
+        assert_eq!(soundfont.presets().len(), 1);
+        let preset = &soundfont.presets()[soundfont.default_preset()];
+        assert_eq!(preset.name, "Piano");
+        assert_eq!(preset.zones_for(60, 100).count(), 1);
+        assert_eq!(preset.zones_for(84, 100).count(), 0);
+        let zone = preset.zones_for(60, 100).next().unwrap();
+        assert!((zone.pitch_ratio(72) - 2.0).abs() < 1e-9);
+        // ループの中を回り続ける
+        assert!(zone.position(60, 1.0).is_some_and(|position| (20.0..80.0).contains(&position)));
+
+        let rms = |samples: &[f32]| (samples.iter().map(|&x| (x * x) as f64).sum::<f64>() / samples.len() as f64).sqrt();
+        let mut engine = OfflineEngine::new(8000);
+        engine.set_soundfont_preset(Some(preset.clone()));
+        let Ok(()) = engine.play_note(&Note::new(0.0, 1.0, 60, 127, 0), 0.0, 1.0, 0.0);
+        let samples = engine.render(0.0, 2.0);
+        // サンプルの長さより長く伸ばせて、離すとリリースで消える
+        assert!(rms(&samples[4000..4800]) > 0.1);
+        assert!(samples[12100..].iter().all(|&x| x.abs() < 0.01));
+
+        // 音域の外は組み込みの音で鳴らす
+        let Ok(()) = engine.play_note(&Note::new(0.0, 1.0, 84, 127, 0), 2.0, 3.0, 0.0);
+        let samples = engine.render(2.0, 3.0);
+        assert!(rms(&samples[2000..4000]) > 0.05);
+
+        // 半分の速さではリリースも倍になる
+        let envelope = zone.vca_envelope(60, 1.0, 0.0, 1.0, 0.0, 1.0);
+        let slow = zone.vca_envelope(60, 1.0, 0.0, 2.0, 0.0, 0.5);
+        assert!((envelope.last().unwrap().0 - 1.5).abs() < 1e-9);
+        assert!((slow.last().unwrap().0 - 3.0).abs() < 1e-9);
+        let render = |playback_rate: f64|{
+            let mut engine = OfflineEngine::new(8000);
+            engine.set_soundfont_preset(Some(preset.clone()));
+            engine.set_playback_rate(playback_rate);
+            let Ok(()) = engine.play_note(&Note::new(0.0, 1.0, 60, 127, 0), 0.0, 1.0 / playback_rate, 0.0);
+            engine.render(0.0, 4.0)
+        };
+        let (samples, slow_samples) = (render(1.0), render(0.5));
+        // 離してから同じ割合だけ進んだところで同じくらいの大きさ
+        assert!((rms(&slow_samples[18400..20000]) / rms(&samples[9200..10000]) - 1.0).abs() < 0.2);
+        assert!(slow_samples[18400..20000].iter().any(|&x| x.abs() > 0.01));
+        assert!(slow_samples[24200..].iter().all(|&x| x.abs() < 0.01));
+    }
+
+    #[test]
+    fn test_velocity_curve(){
+        use super::{AudioEngine, Note, OfflineEngine, VelocityCurve};
+
+        assert_eq!(VelocityCurve::Linear.apply(40), 40);
+        assert!(VelocityCurve::Logarithmic.apply(20) > 40 && VelocityCurve::Exponential.apply(100) < 80);
+        for curve in [VelocityCurve::Logarithmic, VelocityCurve::Exponential]{
+            assert_eq!((curve.apply(0), curve.apply(127)), (0, 127));
+            assert!(curve.apply(1) >= 1);
+            assert!((1..127).all(|v| curve.apply(v) <= curve.apply(v + 1)));
+        }
+        assert_eq!(VelocityCurve::Fixed(90).apply(10), 90);
+        assert!(VelocityCurve::custom(&[64; 127]).is_err());
+        assert_eq!(VelocityCurve::custom(&[200; 128]).unwrap().apply(5), 127);
+        // 変換表が足りなくても落ちずにそのままの強さにする
+        assert_eq!(VelocityCurve::Custom(vec![10; 4]).apply(100), 100);
+        #[cfg(feature = "serde")]
+        {
+            let table = serde_json::to_string(&VelocityCurve::custom(&[64; 128]).unwrap()).unwrap();
+            assert_eq!(serde_json::from_str::<VelocityCurve>(&table).unwrap().apply(5), 64);
+            assert!(serde_json::from_str::<VelocityCurve>(r#"{"Custom":[1,2,3]}"#).is_err());
+            assert_eq!(serde_json::from_str::<VelocityCurve>(r#""Logarithmic""#).unwrap(), VelocityCurve::Logarithmic);
+        }
+
+        // 弱く録音されたトラックも log なら聞こえる大きさになる
+        let peak = |curve: VelocityCurve|{
+            let mut engine = OfflineEngine::new(8000);
+            engine.set_velocity_curve(1, curve);
+            let Ok(()) = engine.play_note(&Note::new(0.0, 1.0, 69, 20, 1), 0.0, 1.0, 0.0);
+            engine.render(0.0, 1.0).iter().fold(0.0f32, |peak, x| peak.max(x.abs()))
+        };
+        assert!(peak(VelocityCurve::Logarithmic) > 1.5 * peak(VelocityCurve::Linear));
+
+        // 画面のノートも曲線をかけた強さで明るくなる
+        use super::{render_scene, DisplayList, DrawCommand, Rectangle, RenderOptions, Song};
+        let song = Song::new(Vec::new(), vec![Note::new(0.5, 1.0, 60, 20, 0)], 1);
+        let fill = |options: &RenderOptions|{
+            let mut display_list = DisplayList::new();
+            let Ok(()) = render_scene(&mut display_list, &song, options, &Rectangle::new(0.0, 0.0, 800.0, 600.0));
+            // ノートの直前に設定した色
+            let mut fill = String::new();
+            for command in display_list.commands(){
+                match command{
+                    DrawCommand::FillStyle(color) => fill = color.clone(),
+                    DrawCommand::RoundRect(..) => return fill,
+                    _ => (),
+                }
+            }
+            fill
+        };
+        let mut options = RenderOptions::default();
+        let dark = fill(&options);
+        options.velocity_curves.set(0, VelocityCurve::Fixed(127));
+        assert_eq!(fill(&options), "#4682B4");
+        assert_ne!(dark, "#4682B4");
+    }
+
+    #[test]
+    fn test_stereo(){
+        use super::{AudioEngine, Note, OfflineEngine, PanMode, StereoSettings};
+
+        // 左右それぞれの大きさ
+        let levels = |stereo: StereoSettings, note: Note|{
+            let mut engine = OfflineEngine::new(8000);
+            engine.set_stereo(stereo);
+            let Ok(()) = engine.play_note(&note, 0.0, 1.0, 0.0);
+            let samples = engine.render_stereo(0.0, 1.0);
+            let level = |channel: usize| samples.iter().skip(channel).step_by(2).fold(0.0f32, |peak, x| peak.max(x.abs()));
+            (level(0), level(1))
+        };
+        let low = Note::new(0.0, 1.0, 30, 100, 0);
+        let high = Note::new(0.0, 1.0, 100, 100, 1);
+
+        let (left, right) = levels(StereoSettings::default(), low);
+        assert!((left - right).abs() < 1e-6);
+
+        // 低い音は左、高い音は右
+        let perspective = StereoSettings{ mode: PanMode::PianoPerspective, ..Default::default() };
+        let (left, right) = levels(perspective.clone(), low);
+        assert!(left > 2.0 * right);
+        let (left, right) = levels(perspective.clone(), high);
+        assert!(right > 2.0 * left);
+
+        // 広がりを0にすると真ん中、トラックの位置は鍵盤より優先する
+        let (left, right) = levels(StereoSettings{ width: 0.0, ..perspective.clone() }, low);
+        assert!((left - right).abs() < 1e-6);
+        let mut stereo = perspective;
+        stereo.track_pans.set(1, Some(-1.0));
+        let (left, right) = levels(stereo, high);
+        assert!(left > 0.1 && right < 1e-6);
+    }
+
+    #[test]
+    fn test_master_effects(){
+        use super::dsp::{BiquadFilter, Convolver};
+        use super::effects::impulse_response;
+        use super::{AudioEngine, MasterEffects, Note, OfflineEngine};
+
+        assert!(MasterEffects::preset_names().iter().all(|name| MasterEffects::preset(name).is_some()));
+
+        // 残響は左右で違うノイズで、エネルギーは1に揃える
+        let hall = MasterEffects::preset("concert hall").unwrap();
+        let [left, right] = impulse_response(&hall, 8000);
+        assert_eq!(left.len(), (hall.reverb_time * 8000.0).ceil() as usize);
+        // 長すぎる残響やNaNでもインパルス応答の大きさは抑える
+        for reverb_time in [f64::INFINITY, 1e300, f64::NAN]{
+            let [left, _] = impulse_response(&MasterEffects{ reverb_time, ..hall }, 8000);
+            assert!(!left.is_empty() && left.len() <= 10 * 8000);
+        }
+        assert_eq!(MasterEffects{ reverb_time: f64::INFINITY, ..hall }.clamped().reverb_time, 10.0);
+        assert!(!MasterEffects{ reverb_time: f64::NAN, ..hall }.clamped().has_reverb());
+        assert_ne!(left, right);
+        assert!((left.iter().map(|&x| (x * x) as f64).sum::<f64>() - 1.0).abs() < 1e-3);
+
+        // FFTの畳み込みはそのまま畳み込んだものと同じ
+        let response = [0.5, -0.25, 0.125, 1.0, 0.0, 0.3];
+        let input: Vec<f64> = (0..40).map(|i| ((i * 7 % 11) as f64 - 5.0) / 5.0).collect();
+        let output = Convolver::new(&response).process(&input);
+        for (i, &y) in output.iter().enumerate(){
+            let expected: f64 = (0..response.len()).filter(|&k| k <= i).map(|k| response[k] as f64 * input[i - k]).sum();
+            assert!((y - expected).abs() < 1e-9);
+        }
+
+        // 低音のシェルフは直流をそのdBだけ持ち上げる
+        let mut filter = BiquadFilter::default();
+        filter.set_lowshelf(250.0, 6.0, 8000.0);
+        let dc = (0..4000).map(|_| filter.process(1.0)).last().unwrap();
+        assert!((dc - 10f64.powf(6.0 / 20.0)).abs() < 1e-3);
+
+        // ホールでは音が止まっても残響が残る
+        let tail = |effects: MasterEffects|{
+            let mut engine = OfflineEngine::new(8000);
+            engine.set_effects(effects);
+            let Ok(()) = engine.play_note(&Note::new(0.0, 0.2, 60, 100, 0), 0.0, 0.2, 0.0);
+            let samples = engine.render(0.0, 2.5);
+            samples[13000..14000].iter().fold(0.0f32, |peak, x| peak.max(x.abs()))
+        };
+        assert_eq!(tail(MasterEffects::default()), 0.0);
+        assert!(tail(hall) > 0.001);
+    }
+
+    #[test]
+    fn test_mixer(){
+        use super::{render_scene, AudioEngine, DisplayList, DrawCommand, Mixer, MixerStrip, Note, OfflineEngine, Rectangle, RenderOptions, Song};
+
+        // トラック0と1、トラック1にはチャンネル0と9が入っている
+        let mut drum = Note::new(0.0, 1.0, 38, 100, 1);
+        drum.set_channel(9);
+        let notes = [Note::new(0.0, 1.0, 60, 100, 0), Note::new(0.0, 1.0, 64, 100, 1), drum];
+        let audible = |mixer: &Mixer| notes.iter().map(|note| mixer.audible(note)).collect::<Vec<bool>>();
+
+        let mut mixer = Mixer::default();
+        assert_eq!(audible(&mixer), vec![true, true, true]);
+        mixer.tracks.set(1, MixerStrip{ mute: true, ..Default::default() });
+        assert_eq!(audible(&mixer), vec![true, false, false]);
+        // ソロはトラックとチャンネルで別々に効く
+        let mut mixer = Mixer::default();
+        mixer.tracks.set(1, MixerStrip{ solo: true, ..Default::default() });
+        assert_eq!(audible(&mixer), vec![false, true, true]);
+        mixer.channels.set(9, MixerStrip{ mute: true, ..Default::default() });
+        assert_eq!(audible(&mixer), vec![false, true, false]);
+        mixer.tracks.set(1, MixerStrip{ solo: true, volume: 0.5, ..Default::default() });
+        assert_eq!(mixer.gain(&notes[1]), 0.5);
+        assert_eq!(mixer.gain(&notes[0]), 0.0);
+        // 音量だけ変えたときは鳴らし直さない
+        let mut louder = mixer.clone();
+        louder.channels.set(9, MixerStrip{ mute: true, volume: 2.0, ..Default::default() });
+        assert!(mixer.same_routing(&louder));
+        louder.tracks.set(200, MixerStrip{ mute: true, ..Default::default() });
+        assert!(!mixer.same_routing(&louder));
+
+        // ミュートしたトラックは鳴らさず、音量を下げると小さくなる (コンプがかかるので比例はしない)
+        let peak = |mixer: Mixer, note: &Note|{
+            let mut engine = OfflineEngine::new(8000);
+            engine.set_mixer(mixer);
+            let Ok(()) = engine.play_note(note, 0.0, 1.0, 0.0);
+            engine.render(0.0, 1.0).iter().fold(0.0f32, |peak, x| peak.max(x.abs()))
+        };
+        let mut muted = Mixer::default();
+        muted.tracks.set(0, MixerStrip{ mute: true, ..Default::default() });
+        assert_eq!(peak(muted, &notes[0]), 0.0);
+        let mut quiet = Mixer::default();
+        quiet.tracks.set(0, MixerStrip{ volume: 0.1, ..Default::default() });
+        assert!(peak(quiet, &notes[0]) < peak(Mixer::default(), &notes[0]) * 0.5);
+
+        // ミュートしたトラックのノートは暗く表示する
+        let song = Song::new(vec![super::Bar::new(0.0, 2.0, 0)], notes[..2].to_vec(), 2);
+        let fill_styles = |options: &RenderOptions|{
+            let mut display_list = DisplayList::new();
+            let Ok(()) = render_scene(&mut display_list, &song, options, &Rectangle::new(0.0, 0.0, 880.0, 600.0));
+            display_list.commands().iter().filter_map(|command| match command{
+                DrawCommand::FillStyle(color) if color.starts_with('#') => Some(color.clone()),
+                _ => None,
+            }).collect::<Vec<String>>()
+        };
+        let mut mixer = Mixer::default();
+        mixer.tracks.set(1, MixerStrip{ mute: true, ..Default::default() });
+        let normal = fill_styles(&RenderOptions::default());
+        let dimmed = fill_styles(&RenderOptions{ mixer: mixer.clone(), ..Default::default() });
+        assert_eq!(normal[0], dimmed[0]);
+        assert_ne!(normal[1], dimmed[1]);
+        assert_eq!(normal, fill_styles(&RenderOptions{ mixer, dim_muted: false, ..Default::default() }));
+    }
+
+    #[test]
+    fn test_metronome(){
+        use super::metronome::{beat_at, clicks, Accent, CountIn};
+        use super::{render_scene, AudioEngine, Bar, DisplayList, DrawCommand, Metronome, OfflineEngine, Rectangle, RenderOptions, Song};
+
+        // 4/4拍子を2つに割ると8回、6/8拍子は6回で、テンポが変わった小節は間隔も変わる
+        let mut bars = vec![Bar::new(0.0, 2.0, 0), Bar::new(2.0, 5.0, 1)];
+        bars[1].set_time_signature(6, 8);
+        let song = Song::new(bars, Vec::new(), 1);
+        let clicks = clicks(&song, 2);
+        assert_eq!(clicks.len(), 8 + 12);
+        assert_eq!(clicks[0].accent, Accent::Downbeat);
+        assert_eq!(clicks[1].accent, Accent::Subdivision);
+        assert_eq!(clicks[2].accent, Accent::Beat);
+        assert!((clicks[1].time - 0.25).abs() < 1e-9);
+        assert_eq!(clicks[8].accent, Accent::Downbeat);
+        assert!((clicks[9].time - 2.25).abs() < 1e-9);
+        assert_eq!(beat_at(&song, 1.7), Some((1.5, false)));
+        assert_eq!(beat_at(&song, 2.1), Some((2.0, true)));
+
+        // 小節の途中で120から60BPMに変わると、その後の拍の間隔は倍になる
+        let mut tempo_song = Song::new(vec![Bar::new(0.0, 3.0, 0)], Vec::new(), 1);
+        tempo_song.set_tempo_changes(vec![(0.0, 120.0), (1.0, 60.0)]);
+        let times: Vec<f64> = super::metronome::clicks(&tempo_song, 1).iter().map(|click| click.time).collect();
+        assert_eq!(times, vec![0.0, 0.5, 1.0, 2.0]);
+        assert_eq!(beat_at(&tempo_song, 2.5), Some((2.0, false)));
+        // 拍の途中で変わったときも残りを新しいテンポで進める
+        tempo_song.set_tempo_changes(vec![(0.0, 120.0), (0.25, 60.0)]);
+        let times: Vec<f64> = super::metronome::clicks(&tempo_song, 1).iter().map(|click| click.time).collect();
+        assert_eq!(times, vec![0.0, 0.75, 1.75, 2.75]);
+        assert_eq!(CountIn::at(&tempo_song, 1.0, 2).unwrap().beat_length, 1.0);
+
+        // クリックは短く鳴ってすぐ消える
+        let mut engine = OfflineEngine::new(8000);
+        engine.set_metronome(Metronome{ enabled: true, ..Default::default() });
+        let Ok(()) = engine.play_click(&clicks[0], 0.1);
+        let samples = engine.render(0.0, 0.5);
+        assert!(samples[..800].iter().all(|&x| x == 0.0));
+        assert!(samples[800..1600].iter().any(|&x| x.abs() > 0.05));
+        assert!(samples[2400..].iter().all(|&x| x.abs() < 0.001));
+
+        // クリックが重なってもノートの音は止めない
+        use super::polyphony::Polyphony;
+        let mut engine = OfflineEngine::new(8000);
+        engine.set_polyphony(Polyphony{ max_voices: 1, ..Default::default() });
+        let Ok(()) = engine.play_note(&super::Note::new(0.0, 1.0, 69, 100, 0), 0.0, 1.0, 0.0);
+        for i in 0..8{
+            let Ok(()) = engine.play_click(&clicks[i % 2], i as f64 * 0.01);
+        }
+        assert_eq!(engine.num_voices(), 1);
+        let samples = engine.render(0.0, 1.0);
+        assert!(samples[4000..4800].iter().any(|&x| x.abs() > 0.05));
+
+        // 拍の頭で左上のランプが光る
+        let lamps = |current_time: f64|{
+            let mut display_list = DisplayList::new();
+            let options = RenderOptions{ current_time, beat_flash: true, ..Default::default() };
+            let Ok(()) = render_scene(&mut display_list, &song, &options, &Rectangle::new(0.0, 0.0, 800.0, 600.0));
+            display_list.commands().iter().filter(|command| matches!(command, DrawCommand::RoundRect(rect, _) if rect.top() < 30.0)).count()
+        };
+        assert_eq!(lamps(2.05), 1);
+        assert_eq!(lamps(2.3), 0);
+    }
+
+    #[test]
+    fn test_practice_sections(){
+        use super::{Bar, PracticeSections};
+
+        // 3/4拍子で1.5秒の小節の2拍目は0.5秒後
+        let mut bar = Bar::new(2.0, 3.5, 1);
+        bar.set_time_signature(3, 4);
+        assert!((bar.beat_time(1.0) - 2.5).abs() < 1e-9);
+        assert!((bar.beat_time(2.5) - 3.25).abs() < 1e-9);
+        assert_eq!(bar.beat_time(5.0), 3.5);
+
+        let mut sections = PracticeSections::default();
+        assert!(sections.add("逆向き", 2.0, 1.0).is_err());
+        assert_eq!(sections.add("12-16小節 左手", 22.0, 32.0), Ok(0));
+        assert_eq!(sections.add("難しいところ", 40.5, 43.25), Ok(1));
+        assert_eq!(sections.add("最後", 60.0, 70.0), Ok(2));
+        sections.rename(1, "速いパッセージ").unwrap();
+        assert!(sections.rename(3, "なし").is_err());
+        assert_eq!(sections.get(1).unwrap().name, "速いパッセージ");
+
+        // 前の区間を消すとループしている区間の番号も詰める
+        sections.activate(Some(2)).unwrap();
+        assert!(sections.activate(Some(3)).is_err());
+        assert_eq!(sections.remove(0).unwrap().start_time, 22.0);
+        assert_eq!(sections.active(), Some(1));
+        assert_eq!(sections.get(1).unwrap().name, "最後");
+        sections.remove(1).unwrap();
+        assert_eq!(sections.active(), None);
+        assert_eq!(sections.sections().len(), 1);
+    }
+
+    #[test]
+    fn test_retrigger_held_notes(){
+        use super::scheduler::Scheduler;
+        use super::Note;
+
+        // 鳴らし直すのは選んだときだけ
+        assert!(!Scheduler::new(0.1).retrigger());
+
+        // 伸ばしている音の途中にシークしたりループで戻ったりしたら途中から鳴らし直す
+        let notes = vec![Note::new(0.0, 3.0, 48, 100, 0), Note::new(1.0, 1.5, 60, 100, 0)];
+        let mut engine = RecordingEngine::default();
+        let mut scheduler = Scheduler::new(0.1);
+        scheduler.set_retrigger(true);
+        scheduler.reset(&notes, 1.0, 0.0);
+        let mut now = 0.0;
+        while now < 1.5{
+            let Ok(()) = scheduler.schedule(&mut engine, &notes, now, Some((0.5, 2.0)), 4.0);
+            now += 0.016;
+        }
+        let played = engine.starts();
+        assert_eq!(played.len(), 4);
+        assert_eq!(played[0], (48, 0.0, 1.0));
+        assert_eq!(played[1].0, 60);
+        let (key, start_time, offset) = played[2];
+        assert_eq!(key, 48);
+        assert!((start_time - 1.0).abs() < 1e-9 && (offset - 0.5).abs() < 1e-9);
+
+        let mut engine = RecordingEngine::default();
+        scheduler.set_retrigger(false);
+        scheduler.reset(&notes, 1.0, 0.0);
+        let Ok(()) = scheduler.schedule(&mut engine, &notes, 0.0, None, 4.0);
+        assert_eq!(engine.played.len(), 1);
+
+        // 半分の速さでは間隔も途中から鳴らす経過時間も倍になる
+        let mut engine = RecordingEngine::default();
+        scheduler.set_retrigger(true);
+        scheduler.set_rate(0.5);
+        scheduler.reset(&notes, 0.5, 0.0);
+        let mut now = 0.0;
+        while now < 4.0{
+            let Ok(()) = scheduler.schedule(&mut engine, &notes, now, Some((0.0, 2.0)), 4.0);
+            now += 0.016;
+        }
+        let played = engine.starts();
+        assert_eq!(played.len(), 3);
+        assert_eq!(played[0], (48, 0.0, 1.0));
+        assert_eq!(played[1].0, 60);
+        assert!((played[1].1 - 1.0).abs() < 1e-9);
+        // ループの始めには3秒で戻る
+        assert_eq!(played[2].0, 48);
+        assert!((played[2].1 - 3.0).abs() < 1e-9);
+
+        // ループで戻るたびに1回だけ鳴らし直す
+        let mut engine = RecordingEngine::default();
+        scheduler.set_rate(1.0);
+        scheduler.reset(&notes, 1.0, 0.0);
+        let mut now = 0.0;
+        while now < 2.9{
+            let Ok(()) = scheduler.schedule(&mut engine, &notes, now, Some((0.5, 2.0)), 4.0);
+            now += 0.016;
+        }
+        let held: Vec<(f64, f64)> = engine.starts().into_iter().filter(|&(key, _, _)| key == 48).map(|(_, start_time, offset)| (start_time, offset)).collect();
+        assert_eq!(held.len(), 3);
+        assert!((held[1].0 - 1.0).abs() < 1e-9 && (held[1].1 - 0.5).abs() < 1e-9);
+        assert!((held[2].0 - 2.5).abs() < 1e-9 && (held[2].1 - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_scheduler_lookahead(){
+        use super::scheduler::Scheduler;
+        use super::Note;
+
+        // 0.5秒ごとに8音
+        let notes: Vec<Note> = (0..8).map(|i| Note::new(i as f64 * 0.5, i as f64 * 0.5 + 0.4, 60 + i, 100, 0)).collect();
+        let mut engine = RecordingEngine::default();
+        let mut scheduler = Scheduler::new(0.1);
+        scheduler.reset(&notes, 0.0, 10.0);
+
+        // フレームの間隔がばらばらでも取りこぼしも重複もしない
+        // 先読みより長く止まったときに遅れたノートはすぐに鳴らす
+        let mut now = 10.0;
+        for step in [0.016, 0.3, 0.001, 0.2, 0.016, 0.3, 0.05].iter().cycle().take(40){
+            let Ok(()) = scheduler.schedule(&mut engine, &notes, now, None, 4.0);
+            now += step;
+        }
+        assert_eq!(engine.played.len(), 8);
+        for (i, &(key, start_time, _)) in engine.starts().iter().enumerate(){
+            assert_eq!(key, 60 + i as u8);
+            let late = start_time - (10.0 + i as f64 * 0.5);
+            assert!((-1e-9..0.25).contains(&late));
+        }
+
+        // タブが裏に回っていたくらい大きく遅れたノートはまとめて鳴らさない
+        let mut engine = RecordingEngine::default();
+        scheduler.reset(&notes, 0.0, 10.0);
+        let Ok(()) = scheduler.schedule(&mut engine, &notes, 10.0, None, 4.0);
+        let Ok(()) = scheduler.schedule(&mut engine, &notes, 12.0, None, 4.0);
+        assert_eq!(engine.keys(), vec![60, 64]);
+
+        // 音を残したままシークしても、先読みで予約した音は二重に鳴らさない
+        let mut engine = RecordingEngine::default();
+        let mut scheduler = Scheduler::new(1.0);
+        scheduler.reset(&notes, 0.0, 0.0);
+        let Ok(()) = scheduler.schedule(&mut engine, &notes, 0.0, None, 4.0);
+        assert_eq!(engine.played.len(), 2);
+        scheduler.seek(&mut engine, &notes, 0.45, 0.1);
+        let Ok(()) = scheduler.schedule(&mut engine, &notes, 0.1, None, 4.0);
+        assert_eq!(engine.keys(), vec![60, 61, 62]);
+        assert!((engine.played[1].start_time - 0.15).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_scheduler_loop(){
+        use super::scheduler::Scheduler;
+        use super::Note;
+
+        // 1.0..2.0をループすると2.0の後に1.0の音が続く
+        let notes: Vec<Note> = (0..8).map(|i| Note::new(i as f64 * 0.5, i as f64 * 0.5 + 0.4, 60 + i, 100, 0)).collect();
+        let mut engine = RecordingEngine::default();
+        let mut scheduler = Scheduler::new(0.1);
+        scheduler.reset(&notes, 1.5, 0.0);
+        let mut now = 0.0;
+        while now < 2.0{
+            let Ok(()) = scheduler.schedule(&mut engine, &notes, now, Some((1.0, 2.0)), 4.0);
+            now += 0.016;
+        }
+        assert_eq!(engine.keys(), vec![63, 62, 63, 62, 63]);
+        assert!((engine.played[1].start_time - 0.5).abs() < 1e-9);
+        assert!((engine.played[2].start_time - 1.0).abs() < 1e-9);
+        // ループの終わりで鳴っている音はフェードアウト
+        assert_eq!(engine.released.len(), 2);
+        assert!((engine.released[0] - 0.5).abs() < 1e-9 && (engine.released[1] - 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_scheduler_speed_trainer(){
+        use super::scheduler::Scheduler;
+        use super::{Note, SpeedTrainer};
+
+        // 0.5倍から0.25ずつ速くして、3回弾いたらループを抜ける
+        let notes: Vec<Note> = (0..4).map(|i| Note::new(i as f64 * 0.5, i as f64 * 0.5 + 0.1, 60 + i, 100, 0)).collect();
+        let mut engine = RecordingEngine::default();
+        let mut scheduler = Scheduler::new(0.1);
+        scheduler.set_speed_trainer(Some(SpeedTrainer{ start_rate: 0.5, step: 0.25, target_rate: 1.0, limit: Some(3) }));
+        scheduler.reset(&notes, 0.0, 0.0);
+        let mut now = 0.0;
+        while now < 6.0{
+            let Ok(()) = scheduler.schedule(&mut engine, &notes, now, Some((0.0, 1.0)), 2.0);
+            now += 0.016;
+        }
+        let expected = [(60, 0.0), (61, 1.0), (60, 2.0), (61, 2.0 + 0.5 / 0.75), (60, 2.0 + 1.0 / 0.75), (61, 2.5 + 1.0 / 0.75), (62, 3.0 + 1.0 / 0.75), (63, 3.5 + 1.0 / 0.75)];
+        assert_eq!(engine.played.len(), expected.len());
+        for (played, &(expected_key, expected_time)) in engine.played.iter().zip(expected.iter()){
+            assert_eq!(played.key, expected_key);
+            assert!((played.start_time - expected_time).abs() < 1e-9);
+        }
+        assert_eq!(scheduler.loop_counter().iteration(), 2);
+    }
+
+    #[test]
+    fn test_scheduler_clicks(){
+        use super::metronome::{clicks, Accent};
+        use super::scheduler::Scheduler;
+        use super::{Bar, Song};
+
+        // メトロノームは3/4拍子の2小節目から倍速で鳴らしても拍に合う
+        let mut bars = vec![Bar::new(0.0, 1.5, 0), Bar::new(1.5, 3.0, 1)];
+        bars.iter_mut().for_each(|bar| bar.set_time_signature(3, 4));
+        let mut engine = RecordingEngine::default();
+        let mut scheduler = Scheduler::new(0.1);
+        scheduler.set_rate(2.0);
+        scheduler.set_clicks(clicks(&Song::new(bars, Vec::new(), 1), 1));
+        scheduler.reset(&[], 1.5, 0.0);
+        let mut now = 0.0;
+        while now < 1.0{
+            let Ok(()) = scheduler.schedule(&mut engine, &[], now, None, 3.0);
+            now += 0.016;
+        }
+        assert_eq!(engine.clicks.iter().map(|&(accent, _)| accent).collect::<Vec<Accent>>(), vec![Accent::Downbeat, Accent::Beat, Accent::Beat]);
+        for (i, &(_, start_time)) in engine.clicks.iter().enumerate(){
+            assert!((start_time - i as f64 * 0.25).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_scheduler_count_in(){
+        use super::metronome::CountIn;
+        use super::scheduler::Scheduler;
+        use super::{Bar, Note, Song};
+
+        // 2拍の予備拍を鳴らしてから1.0秒から弾き、ループで0.5秒に戻るときも2拍数える
+        let song = Song::new(vec![Bar::new(0.0, 2.0, 0), Bar::new(2.0, 4.0, 1)], Vec::new(), 1);
+        let notes = vec![Note::new(0.0, 3.0, 48, 100, 0), Note::new(1.0, 1.5, 60, 100, 0)];
+        let mut engine = RecordingEngine::default();
+        let mut scheduler = Scheduler::new(0.1);
+        scheduler.set_retrigger(true);
+        scheduler.set_loop_count_in(CountIn::at(&song, 0.5, 2));
+        scheduler.reset_with_count_in(&notes, 1.0, 0.0, CountIn::at(&song, 1.0, 2));
+        let mut now = 0.0;
+        while now < 3.2{
+            let Ok(()) = scheduler.schedule(&mut engine, &notes, now, Some((0.5, 2.0)), 4.0);
+            now += 0.016;
+        }
+        let clicks: Vec<f64> = engine.clicks.iter().map(|&(_, start_time)| start_time).collect();
+        assert_eq!(clicks, vec![0.0, 0.5, 2.0, 2.5]);
+        // 予備拍の間は何も鳴らさず、伸ばしている音は弾き始めるところから鳴らし直す
+        assert_eq!(engine.starts(), vec![(48, 1.0, 1.0), (60, 1.0, 0.0), (48, 3.0, 0.5)]);
+    }
+
+    #[test]
+    fn test_playback_rate(){
+        use super::clock::checked_rate;
+        use super::patch::SynthPatch;
+        use super::scheduler::Scheduler;
+        use super::synth::vca_envelope;
+        use super::{Note, SpeedTrainer};
+
+        // NaNや無限大は受け付けず、それ以外は範囲に収める
+        assert_eq!(checked_rate(f64::NAN), None);
+        assert_eq!(checked_rate(f64::INFINITY), None);
+        assert_eq!(checked_rate(0.5), Some(0.5));
+        assert_eq!(checked_rate(0.1), Some(0.25));
+        assert_eq!(checked_rate(3.0), Some(2.0));
+
+        // 半分の速さではADSRの時間が倍になる
+        let patch = SynthPatch{ attack: 0.01, decay: 0.2, sustain: 0.5, release: 0.3, ..SynthPatch::default() };
+        let slow = patch.at_rate(0.5);
+        assert_eq!((slow.attack, slow.decay, slow.sustain, slow.release), (0.02, 0.4, 0.5, 0.6));
+        let envelope = vca_envelope(&slow, 1.0, 0.0, 2.0, 0.0);
+        for (point, time) in envelope.iter().zip([0.0, 0.02, 0.42, 2.0, 2.6]){
+            assert!((point.0 - time).abs() < 1e-9);
+        }
+        assert_eq!(envelope.len(), 5);
+
+        // 0.5倍から0.5ずつ速くして、ループを折り返したら1倍で鳴らす
+        let notes = vec![Note::new(0.0, 0.4, 60, 100, 0), Note::new(0.5, 0.9, 62, 100, 0)];
+        let mut engine = RecordingEngine::default();
+        let mut scheduler = Scheduler::new(0.1);
+        scheduler.set_speed_trainer(Some(SpeedTrainer{ start_rate: 0.5, step: 0.5, target_rate: 1.0, limit: Some(2) }));
+        scheduler.reset(&notes, 0.0, 0.0);
+        let mut now = 0.0;
+        while now < 3.0{
+            let Ok(()) = scheduler.schedule(&mut engine, &notes, now, Some((0.0, 1.0)), 1.0);
+            now += 0.016;
+        }
+        // (開始, 終わり, その音を鳴らしたときの再生速度)
+        let expected = [(0.0, 0.8, 0.5), (1.0, 1.8, 0.5), (2.0, 2.4, 1.0), (2.5, 2.9, 1.0)];
+        assert_eq!(engine.played.len(), expected.len());
+        for (played, expected) in engine.played.iter().zip(expected.iter()){
+            assert!((played.start_time - expected.0).abs() < 1e-9);
+            assert!((played.end_time - expected.1).abs() < 1e-9);
+            assert_eq!(played.rate, expected.2);
+        }
+    }
+
+    #[test]
+    fn test_playhead(){
+        use super::clock::Playhead;
+        use super::scheduler::Scheduler;
+        use super::{Bar, Song, SpeedTrainer};
+
+        // MidiPlayer::tick と同じように1フレーム進める
+        fn frame(engine: &mut RecordingEngine, scheduler: &mut Scheduler, playhead: &mut Playhead, time: f64, loop_range: Option<(f64, f64)>) -> f64{
+            engine.time = time;
+            let Ok(()) = scheduler.schedule(engine, &[], time, loop_range, 10.0);
+            while let Some(wrap) = scheduler.take_wrap(time){
+                playhead.follow(&wrap);
+            }
+            playhead.position(engine)
+        }
+
+        // 2.5秒から再生して1.0..3.0をループ
+        let mut engine = RecordingEngine{ time: 100.0, ..Default::default() };
+        let mut scheduler = Scheduler::new(0.1);
+        let mut playhead = Playhead::new();
+        scheduler.reset(&[], 2.5, 100.0);
+        playhead.reset(2.5, 100.0);
+        let loop_range = Some((1.0, 3.0));
+
+        // スケジューラは先に折り返しても、再生位置は時計がループの終わりに来るまで戻らない
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 100.45, loop_range) - 2.95).abs() < 1e-9);
+        // フレームが飛んでも時計どおりの位置になる
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 101.0, loop_range) - 1.5).abs() < 1e-9);
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 104.6, loop_range) - 1.1).abs() < 1e-9);
+        assert_eq!(playhead.loop_count(), 3);
+        // ループを外せばそのまま進む
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 105.1, None) - 1.6).abs() < 1e-9);
+
+        // ループの外から始めたらすぐにループの始めに戻る
+        scheduler.reset(&[], 3.5, 105.1);
+        playhead.reset(3.5, 105.1);
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 105.1, loop_range) - 1.0).abs() < 1e-9);
+
+        // 半分の速さなら時計の2秒で曲の1秒進み、ループの折り返しも同じ
+        scheduler.set_rate(0.5);
+        playhead.set_rate(0.5);
+        scheduler.reset(&[], 2.5, 110.0);
+        playhead.reset(2.5, 110.0);
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 110.8, loop_range) - 2.9).abs() < 1e-9);
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 112.0, loop_range) - 1.5).abs() < 1e-9);
+
+        // スピードトレーナーは折り返すたびに速くなり、2回弾いたらループを抜ける
+        let trainer = SpeedTrainer{ start_rate: 0.5, step: 0.5, target_rate: 2.0, limit: Some(2) };
+        scheduler.set_speed_trainer(Some(trainer));
+        playhead.restart_loop_count(scheduler.rate());
+        scheduler.reset(&[], 1.0, 120.0);
+        playhead.reset(1.0, 120.0);
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 124.5, loop_range) - 1.5).abs() < 1e-9);
+        assert_eq!(playhead.loop_count(), 1);
+        assert_eq!(playhead.rate(), 1.0);
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 126.5, loop_range) - 3.5).abs() < 1e-9);
+        assert_eq!(playhead.loop_count(), 1);
+
+        // 先読みで折り返した直後に今の位置から鳴らし直しても、二重に数えない
+        scheduler.set_speed_trainer(Some(trainer));
+        playhead.restart_loop_count(scheduler.rate());
+        scheduler.reset(&[], 1.0, 130.0);
+        playhead.reset(1.0, 130.0);
+        let position = frame(&mut engine, &mut scheduler, &mut playhead, 133.95, loop_range);
+        assert!((position - 2.975).abs() < 1e-9);
+        assert_eq!(scheduler.loop_counter().iteration(), 1);
+        assert_eq!(playhead.loop_count(), 0);
+        scheduler.reset(&[], position, 133.95);
+        playhead.reset(position, 133.95);
+        assert_eq!(scheduler.loop_counter().iteration(), 0);
+        assert_eq!(scheduler.rate(), 0.5);
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 134.5, loop_range) - 1.5).abs() < 1e-9);
+        assert_eq!(playhead.loop_count(), 1);
+        assert_eq!(scheduler.loop_counter().iteration(), 1);
+
+        // 予備拍があると画面はその分だけ手前から動き始め、ループの始めに戻るときも同じ
+        use super::metronome::{CountIn, Accent};
+        let song = Song::new(vec![Bar::new(0.0, 2.0, 0)], Vec::new(), 1);
+        let count_in = CountIn::at(&song, 1.0, 5).unwrap();
+        assert_eq!(count_in.length(), 2.5);
+        let accents: Vec<Accent> = count_in.clicks(1.0).iter().map(|click| click.accent).collect();
+        assert_eq!(accents, vec![Accent::Beat, Accent::Downbeat, Accent::Beat, Accent::Beat, Accent::Beat]);
+        assert!((count_in.clicks(1.0)[0].time + 1.5).abs() < 1e-9);
+        assert_eq!(CountIn::at(&song, 1.0, 0), None);
+
+        let mut scheduler = Scheduler::new(0.1);
+        let mut playhead = Playhead::new();
+        scheduler.set_loop_count_in(CountIn::at(&song, 1.0, 2));
+        scheduler.reset(&[], 2.0, 140.0);
+        playhead.reset(2.0, 140.0);
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 140.5, loop_range) - 2.5).abs() < 1e-9);
+        assert_eq!(playhead.content_start(), 2.0);
+        // ループの予備拍の間は、止めたときに戻る位置がループの始めになる
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 141.5, loop_range) - 0.5).abs() < 1e-9);
+        assert_eq!(playhead.content_start(), 1.0);
+        assert!((frame(&mut engine, &mut scheduler, &mut playhead, 142.5, loop_range) - 1.5).abs() < 1e-9);
+
+        // 再生を始めるときの予備拍
+        let mut playhead = Playhead::new();
+        playhead.reset_with_count_in(0.0, 150.0, CountIn::at(&song, 0.0, 2));
+        engine.time = 150.0;
+        assert!((playhead.position(&engine) + 1.0).abs() < 1e-9);
+        assert_eq!(playhead.content_start(), 0.0);
+        engine.time = 150.5;
+        assert!((playhead.position(&engine) + 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_voice_stealing(){
+        use super::polyphony::{Polyphony, VoiceStealing, VoiceState};
+
+        // (鍵盤, 鳴り始め, 音量)
+        struct TestVoice(u8, f64, f64);
+        impl VoiceState for TestVoice{
+            fn key(&self) -> u8{ self.0 }
+            fn start_time(&self) -> f64{ self.1 }
+            fn end_time(&self) -> f64{ 10.0 }
+            fn released(&self) -> bool{ false }
+            fn level(&self, _time: f64) -> f64{ self.2 }
+        }
+
+        let voices = vec![TestVoice(60, 1.0, 0.5), TestVoice(64, 0.5, 0.8), TestVoice(67, 2.0, 0.1)];
+        let victims = |max_voices, stealing, key|{
+            Polyphony{ max_voices, stealing }.victims(&voices, key, 3.0)
+        };
+        assert_eq!(victims(4, VoiceStealing::Oldest, 60), Vec::<usize>::new());
+        assert_eq!(victims(3, VoiceStealing::Oldest, 72), vec![1]);
+        assert_eq!(victims(2, VoiceStealing::Oldest, 72), vec![1, 0]);
+        assert_eq!(victims(3, VoiceStealing::Quietest, 72), vec![2]);
+        // 同じ鍵盤は上限に関係なく鳴らし直す
+        assert_eq!(victims(4, VoiceStealing::SameKey, 60), vec![0]);
+        assert_eq!(victims(3, VoiceStealing::SameKey, 72), vec![1]);
+
+        // 上限を超えた音はフェードアウトして消える
+        use super::{AudioEngine, Note, OfflineEngine};
+        let mut engine = OfflineEngine::new(8000);
+        engine.set_polyphony(Polyphony{ max_voices: 2, stealing: VoiceStealing::Oldest });
+        for (i, key) in [60, 64, 67].into_iter().enumerate(){
+            let Ok(()) = engine.play_note(&Note::new(0.0, 2.0, key, 100, 0), i as f64 * 0.1, 2.0, 0.0);
+        }
+        engine.set_current_time(1.0);
+        engine.update();
+        assert_eq!(engine.num_voices(), 2);
+    }
+
+    #[test]
+    fn test_voice_end_time(){
+        use super::dsp::{OfflineEngine, Voice};
+        use super::patch::SynthPatch;
+        use super::polyphony::{Polyphony, VoiceStealing, VoiceState};
+        use super::synth::{vca_off_time, VCA_FADE_OUT, VCA_RETRIGGER_ATTACK};
+        use super::{AudioEngine, Note};
+
+        // 音が消えるのは離鍵からリリースの分だけ後
+        let patch = SynthPatch{ attack: 0.01, decay: 0.2, sustain: 0.5, release: 0.3, ..SynthPatch::default() };
+        let end_time = |start_time, end_time, offset| Voice::new(&patch, 60, 100, start_time, end_time, offset, 8000.0, 1.0).end_time();
+        assert!((vca_off_time(&patch, 0.0, 1.0, 0.0) - 1.0).abs() < 1e-9);
+        assert!((end_time(0.0, 1.0, 0.0) - 1.3).abs() < 1e-9);
+        // アタックとディケイより短い音はディケイが終わってから離す
+        assert!((vca_off_time(&patch, 0.0, 0.05, 0.0) - 0.21).abs() < 1e-9);
+        assert!((end_time(0.0, 0.05, 0.0) - 0.51).abs() < 1e-9);
+        // 途中から鳴らし直した音も同じ
+        assert!((end_time(2.0, 2.5, 1.0) - 2.8).abs() < 1e-9);
+        assert!((end_time(2.0, 2.01, 0.0) - 2.51).abs() < 1e-9);
+        assert!((end_time(2.0, 2.0, 1.0) - (2.0 + VCA_RETRIGGER_ATTACK + 0.3)).abs() < 1e-9);
+        // リリースがなくてもフェードの分は鳴らす
+        let patch = SynthPatch{ release: 0.0, ..patch };
+        assert!((Voice::new(&patch, 60, 100, 0.0, 1.0, 0.0, 8000.0, 1.0).end_time() - (1.0 + VCA_FADE_OUT)).abs() < 1e-9);
+
+        // 上限を超えて止められた音はフェードアウトが終わったら消える
+        let mut engine = OfflineEngine::new(8000);
+        engine.set_polyphony(Polyphony{ max_voices: 2, stealing: VoiceStealing::Oldest });
+        for (i, key) in [60, 64, 67].into_iter().enumerate(){
+            let Ok(()) = engine.play_note(&Note::new(0.0, 2.0, key, 100, 0), i as f64 * 0.1, 2.0, 0.0);
+        }
+        engine.set_current_time(0.2 + VCA_FADE_OUT * 0.5);
+        engine.update();
+        assert_eq!(engine.num_voices(), 3);
+        engine.set_current_time(0.2 + VCA_FADE_OUT + 0.001);
+        engine.update();
+        assert_eq!(engine.num_voices(), 2);
+    }
 }
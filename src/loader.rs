@@ -0,0 +1,96 @@
+use crate::abc::parse_abc;
+use crate::bar::Bar;
+use crate::musicxml::parse_musicxml;
+use crate::mxl::extract_musicxml;
+use crate::note::Note;
+use crate::song::Song;
+use crate::{parse_midi, parse_midi_with_report};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SongFormat{
+    Smf,
+    Rmid,
+    MusicXml,
+    Mxl,
+    Abc,
+}
+
+// 先頭の数バイトで判別して、テキスト形式で判別できないときだけファイル名の拡張子を見る
+pub fn detect_format(data: &[u8], name_hint: &str) -> Option<SongFormat>{
+    if data.starts_with(b"MThd"){
+        return Some(SongFormat::Smf);
+    }
+    if data.starts_with(b"RIFF") && data.get(8..12) == Some(b"RMID"){
+        return Some(SongFormat::Rmid);
+    }
+    if data.starts_with(b"PK\x03\x04"){
+        return Some(SongFormat::Mxl);
+    }
+
+    let head = String::from_utf8_lossy(&data[..data.len().min(4096)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+    // score-timewise は読めないので MusicXML とはみなさない
+    if head.starts_with('<') && head.contains("<score-partwise"){
+        return Some(SongFormat::MusicXml);
+    }
+    if head.lines().any(|line| line.starts_with("X:")) && head.lines().any(|line| line.starts_with("K:")){
+        return Some(SongFormat::Abc);
+    }
+
+    let extension = name_hint.rsplit_once('.').map(|(_, ext)| ext.to_ascii_lowercase()).unwrap_or_default();
+    match extension.as_str(){
+        "mid" | "midi" | "smf" => Some(SongFormat::Smf),
+        "rmi" => Some(SongFormat::Rmid),
+        "xml" | "musicxml" => Some(SongFormat::MusicXml),
+        "mxl" => Some(SongFormat::Mxl),
+        "abc" => Some(SongFormat::Abc),
+        _ => None,
+    }
+}
+
+// RIFF RMIDのdataチャンクに入っているSMFを取り出す
+fn extract_rmid(data: &[u8]) -> Result<&[u8], String>{
+    let mut offset: usize = 12;
+    while let Some(header) = data.get(offset..offset.saturating_add(8)){
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let end = (offset + 8).checked_add(size).ok_or("RMIDのチャンクが壊れています")?;
+        let body = data.get(offset + 8..end).ok_or("RMIDのチャンクが壊れています")?;
+        if &header[..4] == b"data"{
+            return Ok(body);
+        }
+        // チャンクは2バイト境界に揃えられている
+        offset = end.checked_add(size & 1).ok_or("RMIDのチャンクが壊れています")?;
+    }
+    Err("RMIDにdataチャンクがありません".to_string())
+}
+
+fn to_text(data: &[u8]) -> Result<&str, String>{
+    std::str::from_utf8(data).map_err(|_| "テキストがUTF-8ではありません".to_string())
+}
+
+pub fn parse_song(data: &[u8], name_hint: &str) -> Result<(Vec<Bar>, Vec<Note>, u8), String>{
+    match detect_format(data, name_hint){
+        Some(SongFormat::Smf) => parse_midi(data),
+        Some(SongFormat::Rmid) => parse_midi(extract_rmid(data)?),
+        Some(SongFormat::MusicXml) => parse_musicxml(to_text(data)?.trim_start_matches('\u{feff}')),
+        Some(SongFormat::Mxl) => parse_musicxml(&extract_musicxml(data)?),
+        Some(SongFormat::Abc) => parse_abc(to_text(data)?),
+        None => Err(format!("対応していないファイル形式です: {}", name_hint)),
+    }
+}
+
+// MIDIのときは小節の途中のテンポの変化も曲に入れる
+pub fn load_song(data: &[u8], name_hint: &str) -> Result<Song, String>{
+    let smf = match detect_format(data, name_hint){
+        Some(SongFormat::Smf) => data,
+        Some(SongFormat::Rmid) => extract_rmid(data)?,
+        _ => {
+            let (bars, notes, num_tracks) = parse_song(data, name_hint)?;
+            return Ok(Song::new(bars, notes, num_tracks));
+        },
+    };
+    let (bars, notes, num_tracks, report) = parse_midi_with_report(smf)?;
+    let mut song = Song::new(bars, notes, num_tracks);
+    song.set_tempo_changes(report.tempo_changes);
+    Ok(song)
+}
@@ -0,0 +1,231 @@
+use crate::bar::Bar;
+use crate::note::Note;
+use crate::patch::{SynthPatch, Waveform};
+use crate::polyphony::{Polyphony, VoiceStealing};
+use crate::song::Song;
+use wasm_bindgen::prelude::*;
+
+// クリックを鳴らす長さ (秒) 音色の減衰で実際にはもっと早く消える
+pub const CLICK_LENGTH: f64 = 0.05;
+// 画面の拍のランプを光らせておく時間 (曲の秒)
+pub const BEAT_FLASH_TIME: f64 = 0.15;
+// クリックはノートとは別にこの数だけ同時に鳴らす (ノートの同時発音数を使わない)
+const MAX_CLICK_VOICES: usize = 4;
+
+// 拍の強さ
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Accent{
+    // 小節の頭
+    Downbeat,
+    Beat,
+    // 拍を細かく割ったところ
+    Subdivision,
+}
+
+// メトロノームの1回の音 (曲の時刻)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Click{
+    pub time: f64,
+    pub accent: Accent,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ClickSound{
+    // 電子音
+    #[default]
+    Beep,
+    // 木を叩いたような短い音
+    Woodblock,
+    // 高くて硬い音
+    Tick,
+}
+
+impl ClickSound{
+    pub fn patch(&self) -> SynthPatch{
+        let click = SynthPatch{
+            attack: 0.001,
+            sustain: 0.0,
+            release: 0.01,
+            velocity_sensitivity: 1.0,
+            ..SynthPatch::default()
+        };
+        match self{
+            ClickSound::Beep => SynthPatch{
+                waveform: Waveform::Sine,
+                decay: 0.04,
+                ..click
+            },
+            ClickSound::Woodblock => SynthPatch{
+                waveform: Waveform::Square,
+                decay: 0.03,
+                cutoff_ratio: 2.0,
+                cutoff_end_ratio: 1.0,
+                resonance: 12.0,
+                ..click
+            },
+            ClickSound::Tick => SynthPatch{
+                waveform: Waveform::Sawtooth,
+                decay: 0.01,
+                cutoff_ratio: 16.0,
+                cutoff_end_ratio: 4.0,
+                ..click
+            },
+        }
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Metronome{
+    pub enabled: bool,
+    // 1拍をいくつに割って鳴らすか (1なら拍だけ)
+    pub subdivision: u8,
+    pub volume: f64,
+    pub sound: ClickSound,
+}
+
+impl Default for Metronome{
+    fn default() -> Self{
+        Metronome{
+            enabled: false,
+            subdivision: 1,
+            volume: 0.8,
+            sound: ClickSound::Beep,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Metronome{
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Metronome{
+        Metronome::default()
+    }
+}
+
+impl Metronome{
+    // クリックを鳴らすノート (小節の頭は高く強く)
+    pub fn click_note(&self, click: &Click) -> Note{
+        let (key, velocity) = match click.accent{
+            Accent::Downbeat => (88, 127),
+            Accent::Beat => (81, 100),
+            Accent::Subdivision => (81, 60),
+        };
+        Note::new(click.time, click.time + CLICK_LENGTH, key, velocity, 0)
+    }
+}
+
+// クリックの同時発音数 (あふれたら古いものから止める)
+pub fn click_polyphony() -> Polyphony{
+    Polyphony{ max_voices: MAX_CLICK_VOICES, stealing: VoiceStealing::Oldest }
+}
+
+// 小節の中を拍の subdivision 分の1ずつに分けた時刻
+// 小節の途中でテンポが変わるときはテンポの変化をたどって、変わらなければ小節の長さを等分する
+fn beat_times(song: &Song, bar: &Bar, subdivision: usize) -> Vec<f64>{
+    let (numerator, denominator) = bar.time_signature();
+    let count = numerator.max(1) as usize * subdivision;
+    let mut changes = song.tempo_changes().iter().filter(|change| bar.begin_time() < change.0 && change.0 < bar.end_time()).peekable();
+    if changes.peek().is_none(){
+        return (0..count).map(|i| bar.beat_time(i as f64 / subdivision as f64)).collect();
+    }
+
+    // 1つ分の長さ (四分音符いくつ分)
+    let step = 4.0 / denominator.max(1) as f64 / subdivision as f64;
+    let mut bpm = song.tempo_at(bar.begin_time()).unwrap_or(bar.tempo());
+    let mut time = bar.begin_time();
+    let mut times = Vec::with_capacity(count);
+    for _ in 0..count{
+        times.push(time.min(bar.end_time()));
+        let mut remain = step;
+        loop{
+            let length = remain * 60.0 / bpm;
+            match changes.peek(){
+                Some(&&(change_time, change_bpm)) if change_time < time + length => {
+                    remain -= (change_time - time) * bpm / 60.0;
+                    time = change_time;
+                    bpm = change_bpm;
+                    changes.next();
+                },
+                _ => {
+                    time += length;
+                    break;
+                },
+            }
+        }
+    }
+    times
+}
+
+// 小節の拍子とテンポから拍の位置を決めるので、テンポの変化に合わせて鳴る
+pub fn clicks(song: &Song, subdivision: u8) -> Vec<Click>{
+    let subdivision = subdivision.max(1) as usize;
+    let mut clicks = Vec::new();
+    for bar in song.bars().iter().filter(|bar| bar.begin_time() < bar.end_time()){
+        for (i, time) in beat_times(song, bar, subdivision).into_iter().enumerate(){
+            let accent = match (i, i % subdivision){
+                (0, _) => Accent::Downbeat,
+                (_, 0) => Accent::Beat,
+                _ => Accent::Subdivision,
+            };
+            clicks.push(Click{ time, accent });
+        }
+    }
+    clicks
+}
+
+// time のときに鳴った最後の拍 (拍の時刻と小節の頭かどうか)
+pub fn beat_at(song: &Song, time: f64) -> Option<(f64, bool)>{
+    let bar = song.bars().iter().find(|bar| bar.begin_time() <= time && time < bar.end_time())?;
+    let times = beat_times(song, bar, 1);
+    let beat = times.iter().rposition(|&beat_time| beat_time <= time)?;
+    Some((times[beat], beat == 0))
+}
+
+// 再生を始める前やループの始めに戻ったときに鳴らす予備拍
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CountIn{
+    pub beats: u8,
+    // 1拍の長さ (曲の秒)
+    pub beat_length: f64,
+    // 1小節の拍数 (小節の頭にあたる拍を強くする)
+    pub beats_per_bar: u8,
+}
+
+impl CountIn{
+    // 曲の time の位置のテンポと拍子で beats 拍 (0ならなし)
+    pub fn at(song: &Song, time: f64, beats: u8) -> Option<CountIn>{
+        if beats == 0{
+            return None;
+        }
+        let bars = song.bars();
+        let bar = bars.iter().find(|bar| time < bar.end_time()).or(bars.last())?;
+        let (beats_per_bar, denominator) = bar.time_signature();
+        let beats_per_bar = beats_per_bar.max(1);
+        // 小節の途中でテンポが変わるときはその位置のテンポにする
+        let tempo_changes_in_bar = song.tempo_changes().iter().any(|change| bar.begin_time() < change.0 && change.0 < bar.end_time());
+        let beat_length = match song.tempo_at(time){
+            Some(bpm) if tempo_changes_in_bar => 60.0 / bpm * 4.0 / denominator.max(1) as f64,
+            _ => (bar.end_time() - bar.begin_time()) / beats_per_bar as f64,
+        };
+        (beat_length > 0.0).then_some(CountIn{ beats, beat_length, beats_per_bar })
+    }
+
+    // 予備拍の長さ (曲の秒)
+    pub fn length(&self) -> f64{
+        self.beats as f64 * self.beat_length
+    }
+
+    // start_time の直前に鳴らすクリック
+    pub fn clicks(&self, start_time: f64) -> Vec<Click>{
+        (0..self.beats).map(|i|{
+            let remain = self.beats - i;
+            let accent = if remain.is_multiple_of(self.beats_per_bar) { Accent::Downbeat } else { Accent::Beat };
+            Click{ time: start_time - remain as f64 * self.beat_length, accent }
+        }).collect()
+    }
+}
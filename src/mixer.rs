@@ -0,0 +1,61 @@
+use crate::note::Note;
+use crate::track::PerTrack;
+
+// 1つのトラック (またはチャンネル) の音量とミュート、ソロ
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct MixerStrip{
+    pub mute: bool,
+    pub solo: bool,
+    // 1でそのままの音量
+    pub volume: f64,
+}
+
+impl Default for MixerStrip{
+    fn default() -> Self{
+        MixerStrip{
+            mute: false,
+            solo: false,
+            volume: 1.0,
+        }
+    }
+}
+
+// トラックごととMIDIチャンネルごとの音量
+// 1つのトラックに複数のチャンネルが入っている曲 (SMFのフォーマット0を変換したものなど) はチャンネルで分ける
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct Mixer{
+    pub tracks: PerTrack<MixerStrip>,
+    pub channels: PerTrack<MixerStrip>,
+}
+
+impl Mixer{
+    // ミュートされていなくて、どれかがソロならソロのトラック (チャンネル) のノートだけ鳴らす
+    pub fn audible(&self, note: &Note) -> bool{
+        let track = self.tracks.get(note.track());
+        let channel = self.channels.get(note.channel());
+        !track.mute && !channel.mute
+            && (track.solo || !self.tracks.iter().any(|strip| strip.solo))
+            && (channel.solo || !self.channels.iter().any(|strip| strip.solo))
+    }
+
+    // ミュートとソロが同じなら、鳴らすノートは同じで音量だけが違う
+    pub fn same_routing(&self, other: &Mixer) -> bool{
+        let routing = |strips: &PerTrack<MixerStrip>, index: u8|{
+            let strip = strips.get(index);
+            (strip.mute, strip.solo)
+        };
+        (0..=u8::MAX).all(|i| routing(&self.tracks, i) == routing(&other.tracks, i) && routing(&self.channels, i) == routing(&other.channels, i))
+    }
+
+    // ノートを鳴らす音量の倍率 (鳴らさないノートは0)
+    pub fn gain(&self, note: &Note) -> f64{
+        if !self.audible(note){
+            return 0.0;
+        }
+        (self.tracks.get(note.track()).volume * self.channels.get(note.channel()).volume).max(0.0)
+    }
+}
@@ -0,0 +1,177 @@
+use crate::bar::Bar;
+use crate::note::Note;
+use crate::tempo_map::TempoMap;
+use roxmltree::{Document, Node};
+use std::collections::HashMap;
+
+// MusicXMLのdynamics=100がだいたいforte(velocity 90)らしい
+const DEFAULT_DYNAMICS: f64 = 88.89;
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>>{
+    node.children().find(|n| n.has_tag_name(name))
+}
+
+fn child_text<T: std::str::FromStr>(node: Node, name: &str) -> Option<T>{
+    child(node, name)?.text()?.trim().parse().ok()
+}
+
+fn dynamics_to_velocity(dynamics: f64) -> u8{
+    (dynamics * 90.0 / 100.0).round().clamp(1.0, 127.0) as u8
+}
+
+fn step_to_key(step: &str) -> Option<i32>{
+    match step{
+        "C" => Some(0),
+        "D" => Some(2),
+        "E" => Some(4),
+        "F" => Some(5),
+        "G" => Some(7),
+        "A" => Some(9),
+        "B" => Some(11),
+        _ => None,
+    }
+}
+
+fn pitch_to_key(pitch: Node) -> Option<u8>{
+    let step = step_to_key(child(pitch, "step")?.text()?.trim())?;
+    let alter: f64 = child_text(pitch, "alter").unwrap_or(0.0);
+    let octave: i32 = child_text(pitch, "octave")?;
+    let key = (octave + 1) * 12 + step + alter.round() as i32;
+    u8::try_from(key).ok().filter(|&key| key < 128)
+}
+
+struct QuarterNote{
+    on: f64,
+    off: f64,
+    key: u8,
+    velocity: u8,
+    track: u8,
+}
+
+pub fn parse_musicxml(text: &str) -> Result<(Vec<Bar>, Vec<Note>, u8), String>{
+    let doc = Document::parse(text).map_err(|e| format!("MusicXMLの読み込みに失敗しました: {}", e))?;
+    let root = doc.root_element();
+    if !root.has_tag_name("score-partwise"){
+        return Err("score-partwiseだけサポート".to_string());
+    }
+
+    let parts: Vec<Node> = root.children().filter(|n| n.has_tag_name("part")).collect();
+    if parts.is_empty(){
+        return Err("partがありません".to_string());
+    }
+
+    let mut tempo_points: Vec<(f64, f64)> = Vec::new();
+    // (開始位置, 終了位置, 拍子) 小節は最初のパートから作る
+    let mut measures: Vec<(f64, f64, (u8, u8))> = Vec::new();
+    let mut notes: Vec<QuarterNote> = Vec::new();
+
+    for (track, part) in parts.iter().enumerate(){
+        let mut divisions = 1.0;
+        let mut beats = 4.0;
+        let mut beat_type: f64 = 4.0;
+        let mut dynamics = DEFAULT_DYNAMICS;
+        let mut position = 0.0;
+        let mut last_onset = 0.0;
+        let mut tied_notes: HashMap<u8, usize> = HashMap::new();
+
+        for measure in part.children().filter(|n| n.has_tag_name("measure")){
+            let measure_start = position;
+            let mut measure_end = position;
+
+            for element in measure.children().filter(|n| n.is_element()){
+                match element.tag_name().name(){
+                    "attributes" => {
+                        if let Some(value) = child_text::<f64>(element, "divisions"){
+                            // 0や負の値では長さが求まらない
+                            if !(value.is_finite() && value > 0.0){
+                                return Err(format!("divisionsが正しくありません: {}", value));
+                            }
+                            divisions = value;
+                        }
+                        if let Some(time) = child(element, "time"){
+                            beats = child_text::<String>(time, "beats").and_then(|s| s.split('+').map(|b| b.parse::<f64>().ok()).sum()).unwrap_or(beats);
+                            beat_type = child_text(time, "beat-type").unwrap_or(beat_type);
+                            // 拍子は u8 で持つので、その範囲の正の値だけ受け付ける
+                            if !(1.0..=255.0).contains(&beats) || !(1.0..=255.0).contains(&beat_type) || beat_type.fract() != 0.0{
+                                return Err(format!("拍子が正しくありません: {}/{}", beats, beat_type));
+                            }
+                        }
+                    },
+                    "direction" | "sound" => {
+                        let sound = if element.has_tag_name("sound"){ Some(element) } else { child(element, "sound") };
+                        if let Some(sound) = sound{
+                            if let Some(tempo) = sound.attribute("tempo").and_then(|t| t.parse::<f64>().ok()).filter(|&t| t > 0.0){
+                                tempo_points.push((position, tempo));
+                            }
+                            if let Some(value) = sound.attribute("dynamics").and_then(|d| d.parse().ok()){
+                                dynamics = value;
+                            }
+                        }
+                    },
+                    "backup" => {
+                        position -= child_text(element, "duration").unwrap_or(0.0) / divisions;
+                    },
+                    "forward" => {
+                        position += child_text(element, "duration").unwrap_or(0.0) / divisions;
+                    },
+                    "note" => {
+                        // 装飾音は長さを持たないので無視する
+                        if child(element, "grace").is_some(){
+                            continue;
+                        }
+                        let duration = child_text(element, "duration").unwrap_or(0.0) / divisions;
+                        let onset = if child(element, "chord").is_some(){ last_onset } else { position };
+
+                        if let Some(key) = child(element, "pitch").and_then(pitch_to_key){
+                            let velocity = element.attribute("dynamics").and_then(|d| d.parse().ok()).unwrap_or(dynamics);
+                            let ties: Vec<&str> = element.children().filter(|n| n.has_tag_name("tie")).filter_map(|n| n.attribute("type")).collect();
+
+                            let continued = if ties.contains(&"stop"){ tied_notes.remove(&key) } else { None };
+                            let id = match continued{
+                                Some(id) => {
+                                    notes[id].off = onset + duration;
+                                    id
+                                },
+                                None => {
+                                    notes.push(QuarterNote{ on: onset, off: onset + duration, key, velocity: dynamics_to_velocity(velocity), track: track as u8 });
+                                    notes.len() - 1
+                                },
+                            };
+                            if ties.contains(&"start"){
+                                tied_notes.insert(key, id);
+                            }
+                        }
+
+                        if child(element, "chord").is_none(){
+                            last_onset = position;
+                            position += duration;
+                        }
+                    },
+                    _ => (),
+                }
+                measure_end = f64::max(measure_end, position);
+            }
+
+            // 弱起などで小節が短いことがあるので実際に進んだ分を優先する
+            if measure_end <= measure_start{
+                measure_end = measure_start + beats * 4.0 / beat_type;
+            }
+            position = measure_end;
+            if track == 0{
+                measures.push((measure_start, measure_end, (beats as u8, beat_type as u8)));
+            }
+        }
+    }
+
+    let tempo_map = TempoMap::new(tempo_points);
+    let bars = measures.iter().enumerate().map(|(i, &(begin, end, time_signature))|{
+        let mut bar = Bar::new(tempo_map.to_sec(begin), tempo_map.to_sec(end), i as u32);
+        bar.set_time_signature(time_signature.0, time_signature.1);
+        bar.set_tempo(tempo_map.bpm_at(begin));
+        bar
+    }).collect();
+    notes.sort_by(|a, b| a.on.total_cmp(&b.on));
+    let notes = notes.iter().map(|note| Note::new(tempo_map.to_sec(note.on), tempo_map.to_sec(note.off), note.key, note.velocity, note.track)).collect();
+
+    Ok((bars, notes, parts.len() as u8))
+}
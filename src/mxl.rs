@@ -0,0 +1,93 @@
+use miniz_oxide::inflate::decompress_to_vec;
+
+// .mxlは圧縮されたMusicXMLのzipファイル
+// 必要なのは中のxmlだけなので、セントラルディレクトリを読んで目的のファイルだけ展開する
+
+fn read_u16(data: &[u8], offset: usize) -> Option<usize>{
+    let bytes = data.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<usize>{
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+struct ZipEntry{
+    name: String,
+    method: usize,
+    compressed_size: usize,
+    local_header_offset: usize,
+}
+
+fn read_entries(data: &[u8]) -> Result<Vec<ZipEntry>, String>{
+    const EOCD_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x05, 0x06];
+    const CENTRAL_SIGNATURE: [u8; 4] = [0x50, 0x4b, 0x01, 0x02];
+
+    let eocd = (0..data.len().saturating_sub(21)).rev().find(|&i| data[i..].starts_with(&EOCD_SIGNATURE)).ok_or("zipの終端が見つかりません")?;
+    let num_entries = read_u16(data, eocd + 10).ok_or("zipが壊れています")?;
+    let mut offset = read_u32(data, eocd + 16).ok_or("zipが壊れています")?;
+
+    let mut entries = Vec::new();
+    for _ in 0..num_entries{
+        if !data.get(offset..).is_some_and(|d| d.starts_with(&CENTRAL_SIGNATURE)){
+            return Err("zipのセントラルディレクトリが壊れています".to_string());
+        }
+        let end = offset.checked_add(46).ok_or("zipが壊れています")?;
+        let method = read_u16(data, offset + 10).ok_or("zipが壊れています")?;
+        let compressed_size = read_u32(data, offset + 20).ok_or("zipが壊れています")?;
+        let name_len = read_u16(data, offset + 28).ok_or("zipが壊れています")?;
+        let extra_len = read_u16(data, offset + 30).ok_or("zipが壊れています")?;
+        let comment_len = read_u16(data, offset + 32).ok_or("zipが壊れています")?;
+        let local_header_offset = read_u32(data, offset + 42).ok_or("zipが壊れています")?;
+        let name = data.get(end..end + name_len).ok_or("zipが壊れています")?;
+        entries.push(ZipEntry{
+            name: String::from_utf8_lossy(name).into_owned(),
+            method,
+            compressed_size,
+            local_header_offset,
+        });
+        offset = end + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+fn extract(data: &[u8], entry: &ZipEntry) -> Result<Vec<u8>, String>{
+    let offset = entry.local_header_offset;
+    if data.len() < offset.saturating_add(30){
+        return Err("zipが壊れています".to_string());
+    }
+    let name_len = read_u16(data, offset + 26).ok_or("zipが壊れています")?;
+    let extra_len = read_u16(data, offset + 28).ok_or("zipが壊れています")?;
+    let begin = offset + 30 + name_len + extra_len;
+    let end = begin.checked_add(entry.compressed_size).ok_or("zipが壊れています")?;
+    let compressed = data.get(begin..end).ok_or("zipが壊れています")?;
+    match entry.method{
+        0 => Ok(compressed.to_vec()),
+        8 => decompress_to_vec(compressed).map_err(|e| format!("{} の展開に失敗しました: {:?}", entry.name, e)),
+        method => Err(format!("未対応の圧縮方式です: {}", method)),
+    }
+}
+
+// META-INF/container.xml に書かれている rootfile を探す
+fn rootfile_path(container: &str) -> Option<String>{
+    let doc = roxmltree::Document::parse(container).ok()?;
+    doc.descendants().find(|n| n.has_tag_name("rootfile")).and_then(|n| n.attribute("full-path")).map(str::to_string)
+}
+
+pub fn extract_musicxml(data: &[u8]) -> Result<String, String>{
+    let entries = read_entries(data)?;
+
+    let container_path = entries.iter()
+        .find(|entry| entry.name == "META-INF/container.xml")
+        .and_then(|entry| extract(data, entry).ok())
+        .and_then(|container| rootfile_path(&String::from_utf8_lossy(&container)));
+
+    let entry = match container_path{
+        Some(path) => entries.iter().find(|entry| entry.name == path),
+        None => entries.iter().find(|entry| !entry.name.starts_with("META-INF/") && (entry.name.ends_with(".xml") || entry.name.ends_with(".musicxml"))),
+    }.ok_or("mxlの中にMusicXMLが見つかりません")?;
+
+    let xml = extract(data, entry)?;
+    String::from_utf8(xml).map_err(|_| "MusicXMLがUTF-8ではありません".to_string())
+}
@@ -1,12 +1,18 @@
 use std::fmt;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Note{
     on_time: f64,
     off_time: f64,
     key: u8,
     velocity: u8,
     track: u8,
+    // MIDIチャンネル (MIDI以外から読み込んだものは0)
+    #[cfg_attr(feature = "serde", serde(default))]
+    channel: u8,
 }
 
 impl Note{
@@ -16,7 +22,8 @@ impl Note{
             off_time,
             key,
             velocity,
-            track
+            track,
+            channel: 0,
         }
     }
     pub fn on_time(&self) -> f64{
@@ -34,14 +41,25 @@ impl Note{
     pub fn velocity(&self) -> u8{
         self.velocity
     }
+    pub fn set_velocity(&mut self, velocity: u8){
+        self.velocity = velocity;
+    }
 
     pub fn track(&self) -> u8{
         self.track
     }
 
-    fn midi_key_to_note_name(key: u8) -> String{
-        const SCALE: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "G#", "A", "A#", "B", "B#", "C"];
-        format!("{}{}", key / 12, SCALE[(key % 12) as usize])
+    pub fn channel(&self) -> u8{
+        self.channel
+    }
+    pub fn set_channel(&mut self, channel: u8){
+        self.channel = channel;
+    }
+
+    // C4 = 60
+    pub fn midi_key_to_note_name(key: u8) -> String{
+        const SCALE: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+        format!("{}{}", SCALE[(key % 12) as usize], key as i32 / 12 - 1)
     }
 }
 
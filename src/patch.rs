@@ -0,0 +1,234 @@
+use crate::piano::brightness_ratio;
+use crate::track::PerTrack;
+use crate::synth::{VCA_ATTACK, VCA_DECAY, VCA_RELEASE, VCA_SUSTAIN, VCF_CUTOFF_MAX, VCF_CUTOFF_RATIO, VCF_END_CUTOFF_RATIO};
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Waveform{
+    Sine,
+    Square,
+    Sawtooth,
+    Triangle,
+}
+
+// 音の作り方
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VoiceModel{
+    // オシレーター -> フィルタ -> ADSR
+    Subtractive,
+    // 倍音を重ねたピアノの音 (waveform, unison, cutoff_ratio, cutoff_end_ratio は使わない)
+    Piano,
+}
+
+// 1トラック分の音色
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SynthPatch{
+    pub voice: VoiceModel,
+    pub waveform: Waveform,
+    // ADSR (秒, サステインは最大音量に対する割合)
+    pub attack: f64,
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+    // カットオフは鳴り始めで周波数の cutoff_ratio 倍 (cutoff_max Hzまで)、鳴り終わりで cutoff_end_ratio 倍
+    pub cutoff_ratio: f64,
+    pub cutoff_max: f64,
+    pub cutoff_end_ratio: f64,
+    // フィルタのQ (dB)
+    pub resonance: f64,
+    // 0なら強さに関係なく同じ音量、1なら強さに比例した音量
+    pub velocity_sensitivity: f64,
+    // 重ねるオシレーターの数と、両端のオシレーターの音程の差 (セント)
+    pub unison: u8,
+    pub detune: f64,
+}
+
+impl Default for SynthPatch{
+    fn default() -> Self{
+        SynthPatch{
+            voice: VoiceModel::Subtractive,
+            waveform: Waveform::Sawtooth,
+            attack: VCA_ATTACK,
+            decay: VCA_DECAY,
+            sustain: VCA_SUSTAIN as f64,
+            release: VCA_RELEASE,
+            cutoff_ratio: VCF_CUTOFF_RATIO as f64,
+            cutoff_max: VCF_CUTOFF_MAX as f64,
+            cutoff_end_ratio: VCF_END_CUTOFF_RATIO as f64,
+            resonance: 1.0,
+            velocity_sensitivity: 1.0,
+            unison: 1,
+            detune: 0.0,
+        }
+    }
+}
+
+// JSやJSONから来た音色の値の範囲
+const MAX_ENVELOPE_TIME: f64 = 20.0;
+const MIN_CUTOFF_RATIO: f64 = 0.01;
+const MAX_CUTOFF_RATIO: f64 = 100.0;
+const MIN_CUTOFF: f64 = 20.0;
+const MAX_CUTOFF: f64 = 20000.0;
+const MAX_RESONANCE: f64 = 30.0;
+const MAX_DETUNE: f64 = 1200.0;
+
+// NaNは標準の値にして、それ以外は範囲に収める
+fn clamp_or(value: f64, default: f64, min: f64, max: f64) -> f64{
+    if value.is_nan() { default } else { value.clamp(min, max) }
+}
+
+pub const PRESET_NAMES: [&str; 6] = ["classic", "piano", "pluck", "pad", "organ", "bass"];
+
+#[wasm_bindgen]
+impl SynthPatch{
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> SynthPatch{
+        SynthPatch::default()
+    }
+
+    // 組み込みの音色 classic(今までの音), piano, pluck, pad, organ, bass
+    pub fn preset(name: &str) -> Option<SynthPatch>{
+        let classic = SynthPatch::default();
+        match name{
+            "classic" => Some(classic),
+            // 減衰は倍音ごとに piano で計算するので、VCAは離鍵のダンパーだけ
+            "piano" => Some(SynthPatch{
+                voice: VoiceModel::Piano,
+                attack: 0.002,
+                decay: 0.0,
+                sustain: 1.0,
+                release: 0.25,
+                velocity_sensitivity: 0.9,
+                ..classic
+            }),
+            // 伴奏向けの短く減衰する音
+            "pluck" => Some(SynthPatch{
+                attack: 0.005,
+                decay: 0.4,
+                sustain: 0.0,
+                release: 0.3,
+                cutoff_ratio: 8.0,
+                cutoff_end_ratio: 1.0,
+                velocity_sensitivity: 0.8,
+                ..classic
+            }),
+            // ゆっくり立ち上がる柔らかい音
+            "pad" => Some(SynthPatch{
+                attack: 0.4,
+                decay: 0.5,
+                sustain: 0.8,
+                release: 2.0,
+                cutoff_ratio: 3.0,
+                cutoff_end_ratio: 2.0,
+                velocity_sensitivity: 0.5,
+                unison: 3,
+                detune: 12.0,
+                ..classic
+            }),
+            "organ" => Some(SynthPatch{
+                waveform: Waveform::Square,
+                attack: 0.01,
+                decay: 0.05,
+                sustain: 0.9,
+                release: 0.1,
+                cutoff_ratio: 6.0,
+                cutoff_end_ratio: 6.0,
+                velocity_sensitivity: 0.0,
+                ..classic
+            }),
+            "bass" => Some(SynthPatch{
+                waveform: Waveform::Triangle,
+                attack: 0.01,
+                decay: 0.3,
+                sustain: 0.6,
+                release: 0.2,
+                cutoff_ratio: 2.0,
+                cutoff_end_ratio: 1.0,
+                resonance: 6.0,
+                ..classic
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn preset_names() -> Vec<String>{
+        PRESET_NAMES.iter().map(|name| name.to_string()).collect()
+    }
+}
+
+impl SynthPatch{
+    // JSやJSONから来た音色は Web Audio のパラメーターに渡せる範囲にする
+    pub fn clamped(self) -> SynthPatch{
+        let default = SynthPatch::default();
+        SynthPatch{
+            attack: clamp_or(self.attack, default.attack, 0.0, MAX_ENVELOPE_TIME),
+            decay: clamp_or(self.decay, default.decay, 0.0, MAX_ENVELOPE_TIME),
+            sustain: clamp_or(self.sustain, default.sustain, 0.0, 1.0),
+            release: clamp_or(self.release, default.release, 0.0, MAX_ENVELOPE_TIME),
+            cutoff_ratio: clamp_or(self.cutoff_ratio, default.cutoff_ratio, MIN_CUTOFF_RATIO, MAX_CUTOFF_RATIO),
+            cutoff_max: clamp_or(self.cutoff_max, default.cutoff_max, MIN_CUTOFF, MAX_CUTOFF),
+            cutoff_end_ratio: clamp_or(self.cutoff_end_ratio, default.cutoff_end_ratio, MIN_CUTOFF_RATIO, MAX_CUTOFF_RATIO),
+            resonance: clamp_or(self.resonance, default.resonance, 0.0, MAX_RESONANCE),
+            velocity_sensitivity: clamp_or(self.velocity_sensitivity, default.velocity_sensitivity, 0.0, 1.0),
+            detune: clamp_or(self.detune, default.detune, 0.0, MAX_DETUNE),
+            ..self
+        }
+    }
+
+    // 再生速度 rate で鳴らすときの音色 (ADSRの時間を 1/rate 倍にする、音の高さは変えない)
+    pub fn at_rate(&self, rate: f64) -> SynthPatch{
+        SynthPatch{
+            attack: self.attack / rate,
+            decay: self.decay / rate,
+            release: self.release / rate,
+            ..*self
+        }
+    }
+
+    // 強さから最大音量 (ユニゾンで重ねた分は小さくする)
+    pub fn velocity_to_gain(&self, velocity: u8) -> f64{
+        let sensitivity = self.velocity_sensitivity.clamp(0.0, 1.0);
+        (1.0 - sensitivity + sensitivity * velocity as f64 / 127.0) / (self.num_oscillators() as f64).sqrt()
+    }
+
+    // フィルタのカットオフの倍率 (鳴り始め, 鳴り終わり)
+    pub fn cutoff_ratios(&self, velocity: u8) -> (f64, f64){
+        match self.voice{
+            VoiceModel::Subtractive => (self.cutoff_ratio, self.cutoff_end_ratio),
+            // 強く弾くほど明るく、伸ばしているうちにこもっていく
+            VoiceModel::Piano => (brightness_ratio(velocity), brightness_ratio(velocity) * 0.5),
+        }
+    }
+
+    pub fn num_oscillators(&self) -> usize{
+        self.unison.max(1) as usize
+    }
+
+    // i番目のオシレーターの音程のずれ (セント) 両端が ±detune/2 になるように並べる
+    pub fn detune_cents(&self, i: usize) -> f64{
+        let n = self.num_oscillators();
+        if n == 1{
+            return 0.0;
+        }
+        self.detune * (i as f64 / (n - 1) as f64 - 0.5)
+    }
+}
+
+// トラックごとの音色
+pub type TrackPatches = PerTrack<SynthPatch>;
+
+impl TrackPatches{
+    pub fn clamped(&self) -> TrackPatches{
+        let mut patches = TrackPatches::default();
+        for (track, patch) in self.iter().enumerate(){
+            patches.set(track as u8, patch.clamped());
+        }
+        patches
+    }
+}
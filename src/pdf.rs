@@ -0,0 +1,139 @@
+use crate::rectangle::Rectangle;
+use crate::render::{parse_color, RenderBackend, TextStyle};
+use std::convert::Infallible;
+use std::fmt::Write;
+
+// 印刷用の最低限のPDF書き出し
+// 図形は四角形と線だけ、文字はHelvetica(ASCIIのみ)で描く
+
+fn pdf_color(color: &str) -> (f64, f64, f64){
+    let [r, g, b] = parse_color(color);
+    (r as f64 / 255.0, g as f64 / 255.0, b as f64 / 255.0)
+}
+
+fn escape_text(text: &str) -> String{
+    text.chars().map(|c| match c{
+        '(' | ')' | '\\' => format!("\\{}", c),
+        c if c.is_ascii() && !c.is_ascii_control() => c.to_string(),
+        _ => "?".to_string(),
+    }).collect()
+}
+
+pub struct PdfBackend{
+    page_width: f64,
+    page_height: f64,
+    pages: Vec<String>,
+    text_style: TextStyle,
+}
+
+impl PdfBackend{
+    pub fn new(page_width: f64, page_height: f64) -> Self{
+        PdfBackend{
+            page_width,
+            page_height,
+            pages: Vec::new(),
+            text_style: TextStyle{ font: "10px sans-serif".to_string(), align: "left".to_string(), baseline: "alphabetic".to_string() },
+        }
+    }
+
+    pub fn new_page(&mut self){
+        self.pages.push(String::new());
+    }
+
+    fn content(&mut self) -> &mut String{
+        if self.pages.is_empty(){
+            self.new_page();
+        }
+        self.pages.last_mut().unwrap()
+    }
+
+    // PDFは左下が原点なのでy軸を反転する
+    fn y(&self, y: f64) -> f64{
+        self.page_height - y
+    }
+
+    fn font_size(&self) -> f64{
+        self.text_style.font.split_once("px").and_then(|(size, _)| size.trim().parse().ok()).unwrap_or(10.0)
+    }
+
+    pub fn finish(self) -> Vec<u8>{
+        let mut objects: Vec<String> = Vec::new();
+        let num_pages = self.pages.len().max(1);
+        let page_ids: Vec<usize> = (0..num_pages).map(|i| 4 + i * 2).collect();
+
+        objects.push("<< /Type /Catalog /Pages 2 0 R >>".to_string());
+        let kids: Vec<String> = page_ids.iter().map(|id| format!("{} 0 R", id)).collect();
+        objects.push(format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), num_pages));
+        objects.push("<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>".to_string());
+        for (i, page_id) in page_ids.iter().enumerate(){
+            let content = self.pages.get(i).map(String::as_str).unwrap_or("");
+            objects.push(format!("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R >> >> /Contents {} 0 R >>", self.page_width, self.page_height, page_id + 1));
+            objects.push(format!("<< /Length {} >>\nstream\n{}endstream", content.len(), content));
+        }
+
+        let mut out = String::from("%PDF-1.4\n");
+        let mut offsets = Vec::new();
+        for (i, object) in objects.iter().enumerate(){
+            offsets.push(out.len());
+            let _ = write!(out, "{} 0 obj\n{}\nendobj\n", i + 1, object);
+        }
+        let xref_offset = out.len();
+        let _ = write!(out, "xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+        for offset in offsets.iter(){
+            let _ = writeln!(out, "{:010} 00000 n ", offset);
+        }
+        let _ = write!(out, "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref_offset);
+        out.into_bytes()
+    }
+}
+
+impl RenderBackend for PdfBackend{
+    type Error = Infallible;
+
+    fn set_fill_style(&mut self, color: &str){
+        let (r, g, b) = pdf_color(color);
+        let _ = writeln!(self.content(), "{:.3} {:.3} {:.3} rg", r, g, b);
+    }
+    fn set_stroke_style(&mut self, color: &str){
+        let (r, g, b) = pdf_color(color);
+        let _ = writeln!(self.content(), "{:.3} {:.3} {:.3} RG", r, g, b);
+    }
+    fn set_text_style(&mut self, style: &TextStyle){
+        self.text_style = style.clone();
+    }
+    fn fill_rect(&mut self, rect: &Rectangle) -> Result<(), Infallible>{
+        let y = self.y(rect.bottom());
+        let _ = writeln!(self.content(), "{:.2} {:.2} {:.2} {:.2} re f", rect.left(), y, rect.width(), rect.height());
+        Ok(())
+    }
+    fn round_rect(&mut self, rect: &Rectangle, _radius: f64) -> Result<(), Infallible>{
+        // 印刷では角丸は目立たないので普通の四角形にする
+        let y = self.y(rect.bottom());
+        let _ = writeln!(self.content(), "{:.2} {:.2} {:.2} {:.2} re B", rect.left(), y, rect.width(), rect.height());
+        Ok(())
+    }
+    fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) -> Result<(), Infallible>{
+        let (y1, y2) = (self.y(y1), self.y(y2));
+        let _ = writeln!(self.content(), "{:.2} {:.2} m {:.2} {:.2} l S", x1, y1, x2, y2);
+        Ok(())
+    }
+    fn text(&mut self, text: &str, x: f64, y: f64) -> Result<(), Infallible>{
+        let size = self.font_size();
+        // Helveticaの平均的な文字幅でだいたいの幅を見積もる
+        let width = text.chars().count() as f64 * size * 0.55;
+        let x = match self.text_style.align.as_str(){
+            "right" | "end" => x - width,
+            "center" => x - width / 2.0,
+            _ => x,
+        };
+        let y = match self.text_style.baseline.as_str(){
+            "bottom" => y - size * 0.2,
+            "top" => y + size * 0.8,
+            "middle" => y + size * 0.3,
+            _ => y,
+        };
+        let y = self.y(y);
+        let _ = writeln!(self.content(), "BT /F1 {:.1} Tf {:.2} {:.2} Td ({}) Tj ET", size, x, y, escape_text(text));
+        Ok(())
+    }
+}
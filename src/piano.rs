@@ -0,0 +1,85 @@
+use crate::dsp::WhiteNoise;
+
+// ピアノの音のモデル
+// 鋸波の代わりに、少しずつ音程のずれた倍音を重ねて、それぞれを鍵盤ごとの速さで減衰させる
+// SoundSourceでもオフラインのVoiceでも同じパラメーターを使う
+
+pub const NUM_PARTIALS: usize = 8;
+// 倍音はこの周波数より上は作らない
+const MAX_PARTIAL_FREQ: f64 = 12000.0;
+pub const HAMMER_NOISE_LENGTH: f64 = 0.04;
+// ハンマーの音はこの時定数で消える
+const HAMMER_NOISE_DECAY: f64 = 0.008;
+
+pub struct Partial{
+    pub freq: f64,
+    pub amplitude: f64,
+    // 減衰の時定数 (秒)
+    pub decay: f64,
+}
+
+// 弦の硬さによる倍音のずれの係数 低音ほど小さく、高音ほど大きい
+pub fn inharmonicity(key: u8) -> f64{
+    0.0002 * 2f64.powf((key as f64 - 21.0) / 22.0)
+}
+
+// 基音の減衰の時定数 (秒) 低音ほど長く響く
+pub fn decay_time(key: u8) -> f64{
+    4.0 * 2f64.powf(-(key as f64 - 21.0) / 24.0)
+}
+
+// 強く弾くほど高い倍音が強くなる
+// 再生速度 playback_rate で遅くしたときは、その分だけ長く響かせる
+pub fn partials(key: u8, velocity: u8, freq: f64, playback_rate: f64) -> Vec<Partial>{
+    let b = inharmonicity(key);
+    let strength = velocity as f64 / 127.0;
+    let slope = 2.2 - strength;
+    let decay = decay_time(key) / playback_rate;
+
+    let mut partials: Vec<Partial> = (1..=NUM_PARTIALS)
+        .map(|n| n as f64)
+        .map(|n| Partial{
+            freq: n * freq * (1.0 + b * n * n).sqrt(),
+            amplitude: 1.0 / n.powf(slope),
+            // 高い倍音ほど早く消える
+            decay: decay / (1.0 + 0.6 * (n - 1.0)),
+        })
+        .take_while(|partial| partial.freq < MAX_PARTIAL_FREQ)
+        .collect();
+
+    // 倍音の数が違っても音量が揃うようにする
+    let total: f64 = partials.iter().map(|partial| partial.amplitude).sum();
+    for partial in partials.iter_mut(){
+        partial.amplitude /= total.max(1.0);
+    }
+    partials
+}
+
+// 鳴り始めから time 秒たったときの倍音の大きさ
+pub fn partial_amplitude(partial: &Partial, time: f64) -> f64{
+    partial.amplitude * (-time.max(0.0) / partial.decay).exp()
+}
+
+// 強さに応じたフィルタのカットオフの倍率
+pub fn brightness_ratio(velocity: u8) -> f64{
+    3.0 + 13.0 * velocity as f64 / 127.0
+}
+
+pub fn hammer_gain(velocity: u8) -> f64{
+    0.15 * (velocity as f64 / 127.0).powi(2)
+}
+
+// ハンマーの音の中心周波数
+pub fn hammer_freq(freq: f64) -> f64{
+    (freq * 4.0).clamp(500.0, 6000.0)
+}
+
+// ハンマーが弦を叩く音 毎回同じになるように決まった乱数で作る
+pub fn hammer_noise(sample_rate: u32) -> Vec<f32>{
+    let length = (HAMMER_NOISE_LENGTH * sample_rate as f64).ceil() as usize;
+    let mut noise = WhiteNoise::new(0x2545_f491);
+    (0..length).map(|i|{
+        let time = i as f64 / sample_rate as f64;
+        (noise.sample() * (-time / HAMMER_NOISE_DECAY).exp()) as f32
+    }).collect()
+}
@@ -0,0 +1,70 @@
+use wasm_bindgen::prelude::*;
+
+// 同時発音数を超えたときにどの音を止めるか
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VoiceStealing{
+    // 一番前に鳴り始めた音
+    Oldest,
+    // 一番小さく鳴っている音
+    Quietest,
+    // 同じ鍵盤の音は常に止めて鳴らし直し、あふれたら一番前に鳴り始めた音
+    SameKey,
+}
+
+// エンジンが鳴らしている1音の状態
+pub trait VoiceState{
+    fn key(&self) -> u8;
+    fn start_time(&self) -> f64;
+    // リリースまで含めて鳴り終わる時刻
+    fn end_time(&self) -> f64;
+    // もうフェードアウトさせている
+    fn released(&self) -> bool;
+    fn level(&self, time: f64) -> f64;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Polyphony{
+    pub max_voices: usize,
+    pub stealing: VoiceStealing,
+}
+
+impl Default for Polyphony{
+    fn default() -> Self{
+        Polyphony{
+            max_voices: 64,
+            stealing: VoiceStealing::SameKey,
+        }
+    }
+}
+
+impl Polyphony{
+    // start_time に key を鳴らすときに止める音の番号
+    pub fn victims<V: VoiceState>(&self, voices: &[V], key: u8, start_time: f64) -> Vec<usize>{
+        let mut active: Vec<usize> = (0..voices.len()).filter(|&i| !voices[i].released() && voices[i].end_time() > start_time).collect();
+        let mut victims = Vec::new();
+
+        if self.stealing == VoiceStealing::SameKey{
+            active.retain(|&i|{
+                let same_key = voices[i].key() == key;
+                if same_key{
+                    victims.push(i);
+                }
+                !same_key
+            });
+        }
+
+        // 新しい音の分を空ける
+        while !active.is_empty() && active.len() >= self.max_voices.max(1){
+            let position = match self.stealing{
+                VoiceStealing::Quietest => (0..active.len()).min_by(|&a, &b| voices[active[a]].level(start_time).total_cmp(&voices[active[b]].level(start_time))),
+                VoiceStealing::Oldest | VoiceStealing::SameKey => (0..active.len()).min_by(|&a, &b| voices[active[a]].start_time().total_cmp(&voices[active[b]].start_time())),
+            };
+            let Some(position) = position else{
+                break;
+            };
+            victims.push(active.remove(position));
+        }
+        victims
+    }
+}
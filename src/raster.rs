@@ -0,0 +1,200 @@
+use crate::rectangle::Rectangle;
+use crate::render::{parse_color, RenderBackend, TextStyle};
+use std::convert::Infallible;
+
+// RGBAのピクセルバッファに直接描くソフトウェアラスタライザ
+// ブラウザなしで動画用のフレームを作るのに使う
+
+// 5x7のビットマップフォント (小節番号が描ければ良いので最低限)
+fn glyph(c: char) -> Option<[u8; 7]>{
+    match c{
+        '0' => Some([0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E]),
+        '1' => Some([0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E]),
+        '2' => Some([0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F]),
+        '3' => Some([0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E]),
+        '4' => Some([0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02]),
+        '5' => Some([0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E]),
+        '6' => Some([0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E]),
+        '7' => Some([0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08]),
+        '8' => Some([0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E]),
+        '9' => Some([0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C]),
+        'C' => Some([0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E]),
+        '/' => Some([0x01, 0x01, 0x02, 0x04, 0x08, 0x10, 0x10]),
+        '-' => Some([0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00]),
+        ' ' => Some([0x00; 7]),
+        // フォントにない文字は豆腐で描く
+        _ => None,
+    }
+}
+
+const TOFU: [u8; 7] = [0x1F, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1F];
+
+pub struct RasterBackend{
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+    fill: [u8; 3],
+    stroke: [u8; 3],
+    text_style: TextStyle,
+}
+
+impl RasterBackend{
+    pub fn new(width: usize, height: usize) -> Self{
+        RasterBackend{
+            width,
+            height,
+            pixels: vec![0; width * height * 4],
+            fill: [0, 0, 0],
+            stroke: [0, 0, 0],
+            text_style: TextStyle{ font: "10px sans-serif".to_string(), align: "left".to_string(), baseline: "alphabetic".to_string() },
+        }
+    }
+
+    pub fn width(&self) -> usize{
+        self.width
+    }
+    pub fn height(&self) -> usize{
+        self.height
+    }
+    pub fn pixels(&self) -> &[u8]{
+        &self.pixels
+    }
+    pub fn into_pixels(self) -> Vec<u8>{
+        self.pixels
+    }
+
+    fn put(&mut self, x: i64, y: i64, color: [u8; 3]){
+        if x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64{
+            return;
+        }
+        let index = (y as usize * self.width + x as usize) * 4;
+        self.pixels[index..index + 4].copy_from_slice(&[color[0], color[1], color[2], 255]);
+    }
+
+    // 角丸の四角形の内側にあるピクセルを塗る
+    fn fill_shape(&mut self, rect: &Rectangle, radius: f64, color: [u8; 3]){
+        let x0 = rect.left().round().max(0.0) as i64;
+        let x1 = rect.right().round().min(self.width as f64) as i64;
+        let y0 = rect.top().round().max(0.0) as i64;
+        let y1 = rect.bottom().round().min(self.height as f64) as i64;
+        let radius = radius.min(rect.width() / 2.0).min(rect.height() / 2.0).max(0.0);
+        for y in y0..y1{
+            for x in x0..x1{
+                if radius > 0.0{
+                    let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+                    let cx = px.clamp(rect.left() + radius, rect.right() - radius);
+                    let cy = py.clamp(rect.top() + radius, rect.bottom() - radius);
+                    if (px - cx).powi(2) + (py - cy).powi(2) > radius * radius{
+                        continue;
+                    }
+                }
+                self.put(x, y, color);
+            }
+        }
+    }
+
+    // 画面より1px広い範囲に線分を切り詰める (Liang-Barsky)
+    fn clip_line(&self, x1: f64, y1: f64, x2: f64, y2: f64) -> Option<(f64, f64, f64, f64)>{
+        if ![x1, y1, x2, y2].iter().all(|v| v.is_finite()){
+            return None;
+        }
+        let (dx, dy) = (x2 - x1, y2 - y1);
+        let (left, top, right, bottom) = (-1.0, -1.0, self.width as f64 + 1.0, self.height as f64 + 1.0);
+        let (mut t0, mut t1) = (0.0f64, 1.0f64);
+        for (p, q) in [(-dx, x1 - left), (dx, right - x1), (-dy, y1 - top), (dy, bottom - y1)]{
+            if p == 0.0{
+                if q < 0.0{
+                    return None;
+                }
+                continue;
+            }
+            let t = q / p;
+            if p < 0.0{
+                t0 = t0.max(t);
+            }else{
+                t1 = t1.min(t);
+            }
+        }
+        (t0 <= t1).then_some((x1 + dx * t0, y1 + dy * t0, x1 + dx * t1, y1 + dy * t1))
+    }
+
+    fn font_size(&self) -> f64{
+        self.text_style.font.split_once("px").and_then(|(size, _)| size.trim().parse().ok()).unwrap_or(10.0)
+    }
+}
+
+impl RenderBackend for RasterBackend{
+    type Error = Infallible;
+
+    fn set_fill_style(&mut self, color: &str){
+        self.fill = parse_color(color);
+    }
+    fn set_stroke_style(&mut self, color: &str){
+        self.stroke = parse_color(color);
+    }
+    fn set_text_style(&mut self, style: &TextStyle){
+        self.text_style = style.clone();
+    }
+    fn fill_rect(&mut self, rect: &Rectangle) -> Result<(), Infallible>{
+        self.fill_shape(rect, 0.0, self.fill);
+        Ok(())
+    }
+    fn round_rect(&mut self, rect: &Rectangle, radius: f64) -> Result<(), Infallible>{
+        // 枠線の色で塗ってから1px内側を塗りの色で塗る
+        self.fill_shape(rect, radius, self.stroke);
+        let inner = Rectangle::new(rect.left() + 1.0, rect.top() + 1.0, rect.width() - 2.0, rect.height() - 2.0);
+        self.fill_shape(&inner, (radius - 1.0).max(0.0), self.fill);
+        Ok(())
+    }
+    fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) -> Result<(), Infallible>{
+        if self.width == 0 || self.height == 0{
+            return Ok(());
+        }
+        // 画面外にはみ出す部分は先に切り落とす
+        let Some((x1, y1, x2, y2)) = self.clip_line(x1, y1, x2, y2) else{
+            return Ok(());
+        };
+        let steps = (x2 - x1).abs().max((y2 - y1).abs()).ceil().max(1.0);
+        for i in 0..=steps as i64{
+            let t = i as f64 / steps;
+            let x = x1 + (x2 - x1) * t;
+            let y = y1 + (y2 - y1) * t;
+            self.put(x.floor() as i64, y.floor() as i64, self.stroke);
+        }
+        Ok(())
+    }
+    fn text(&mut self, text: &str, x: f64, y: f64) -> Result<(), Infallible>{
+        let size = self.font_size();
+        let scale = (size / 10.0).round().max(1.0) as i64;
+        let advance = 6 * scale;
+        let width = text.chars().count() as i64 * advance - scale;
+        let left = match self.text_style.align.as_str(){
+            "right" | "end" => x as i64 - width,
+            "center" => x as i64 - width / 2,
+            _ => x as i64,
+        };
+        let glyph_height = 7 * scale;
+        let top = match self.text_style.baseline.as_str(){
+            "bottom" | "alphabetic" => y as i64 - glyph_height,
+            "middle" => y as i64 - glyph_height / 2,
+            _ => y as i64,
+        };
+        for (i, c) in text.chars().enumerate(){
+            let rows = glyph(c).unwrap_or(TOFU);
+            let glyph_left = left + i as i64 * advance;
+            for (row, bits) in rows.iter().enumerate(){
+                for column in 0..5{
+                    if bits & (0x10 >> column) == 0{
+                        continue;
+                    }
+                    for dy in 0..scale{
+                        for dx in 0..scale{
+                            self.put(glyph_left + column * scale + dx, top + row as i64 * scale + dy, self.fill);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
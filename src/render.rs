@@ -0,0 +1,303 @@
+use crate::metronome::{beat_at, BEAT_FLASH_TIME};
+use crate::mixer::Mixer;
+use crate::note::Note;
+use crate::velocity::TrackVelocityCurves;
+use crate::rectangle::Rectangle;
+use crate::song::Song;
+use wasm_bindgen::JsValue;
+use web_sys::CanvasRenderingContext2d;
+
+pub const MIN_KEY: u8 = 21;
+pub const MAX_KEY: u8 = 108;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextStyle{
+    pub font: String,
+    pub align: String,
+    pub baseline: String,
+}
+
+// 描画先の抽象化
+// 画面への描画(Canvas2D)のほかにSVGやテスト用の記録にも使う
+pub trait RenderBackend{
+    type Error;
+
+    fn set_fill_style(&mut self, color: &str);
+    fn set_stroke_style(&mut self, color: &str);
+    fn set_text_style(&mut self, style: &TextStyle);
+    // 塗りつぶしのみ
+    fn fill_rect(&mut self, rect: &Rectangle) -> Result<(), Self::Error>;
+    // 角丸の四角形を塗りつぶして枠線も描く
+    fn round_rect(&mut self, rect: &Rectangle, radius: f64) -> Result<(), Self::Error>;
+    fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) -> Result<(), Self::Error>;
+    fn text(&mut self, text: &str, x: f64, y: f64) -> Result<(), Self::Error>;
+}
+
+// 描画する時刻などの画面の状態
+#[derive(Clone, Debug)]
+pub struct RenderOptions{
+    pub current_time: f64,
+    pub display_range_sec: f64,
+    // ノートの明るさは曲線をかけた強さで決める
+    pub velocity_curves: TrackVelocityCurves,
+    // ミュートされて鳴らないノートを暗くするか
+    pub mixer: Mixer,
+    pub dim_muted: bool,
+    // メトロノームの拍に合わせて左上を光らせるか
+    pub beat_flash: bool,
+}
+
+impl Default for RenderOptions{
+    fn default() -> Self{
+        RenderOptions{
+            current_time: 0.0,
+            display_range_sec: 3.0,
+            velocity_curves: TrackVelocityCurves::default(),
+            mixer: Mixer::default(),
+            dim_muted: true,
+            beat_flash: false,
+        }
+    }
+}
+
+// 拍のランプの色 (小節の頭とそれ以外)
+const DOWNBEAT_FLASH_COLOR: &str = "#FF5050";
+const BEAT_FLASH_COLOR: &str = "#FFFFFF";
+
+pub const TRACK_FILL_COLORS: [&str; 4] = ["#4682B4", "#E66101", "#009E73", "#7B4173"];
+pub const TRACK_STROKE_COLORS: [&str; 4] = ["#266294", "#C64101", "#007E53", "#5B2153"];
+
+pub fn calc_key_area(rect: &Rectangle, min_key: u8, max_key: u8) -> Vec<Rectangle>{
+    // キーボードの１オクターブ分の鍵盤の比率位置テーブルを作成、黒鍵は白鍵にかぶさる上に幅や位置が等幅ではないので定義して使うことにした
+    const OCTAVE_SIZE_RATIO_TABLE:[(f64, f64); 12] = [(0.0, 0.1428), (0.0951, 0.1666),(0.1428, 0.2857),(0.2618, 0.3333),(0.2857, 0.4285),(0.4285, 0.5714),(0.5237, 0.5952),(0.5714, 0.7142),(0.6784, 0.7499),(0.7142, 0.8571),(0.8332, 0.9047),(0.8571, 1.0)];
+
+    let min_octave = min_key / 12;
+    let min_note = min_key % 12;
+    let _max_octave = max_key / 12;
+    let max_note = max_key % 12;
+    
+    let base_offset_ratio = min_octave as f64 + OCTAVE_SIZE_RATIO_TABLE[min_note as usize].0;
+    let octave_width = {
+        let min_aligned = min_key + 12 - (min_key % 12);
+        let max_aligned = max_key - (max_key % 12);
+        let total_octave_ratio = (max_aligned - min_aligned) as f64 / 12.0 + (1.0 - OCTAVE_SIZE_RATIO_TABLE[min_note as usize].0) + OCTAVE_SIZE_RATIO_TABLE[max_note as usize].1;
+        rect.width() / total_octave_ratio
+    };
+
+    let mut ret: Vec<Rectangle> = Vec::new();
+    for key in min_key..=max_key{
+        let note_index = (key % 12) as usize;
+        let octave = (key / 12) as f64;
+        let left = rect.left() + (octave + OCTAVE_SIZE_RATIO_TABLE[note_index].0 - base_offset_ratio) * octave_width;
+        let right = rect.left() + (octave + OCTAVE_SIZE_RATIO_TABLE[note_index].1 - base_offset_ratio) * octave_width;
+        ret.push(Rectangle::new(left, rect.top(), right - left, rect.height()));
+    }
+    ret
+}
+
+// "#RRGGBB" か、描画で使っている色の名前をRGBにする
+pub fn parse_color(color: &str) -> [u8; 3]{
+    if let Some(hex) = color.strip_prefix('#') && hex.len() == 6{
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap_or(0);
+        return [channel(0), channel(2), channel(4)];
+    }
+    match color{
+        "white" => [255, 255, 255],
+        "gray" | "grey" => [128, 128, 128],
+        "lightgray" | "lightgrey" => [211, 211, 211],
+        "darkgray" | "darkgrey" => [169, 169, 169],
+        _ => [0, 0, 0],
+    }
+}
+
+// ミュートされたノートの明るさ
+const MUTED_BRIGHTNESS: f64 = 0.3;
+
+// 弱いノートほど暗くする (一番弱くて半分の明るさ)
+fn velocity_color(color: &str, velocity: u8) -> String{
+    scale_color(color, 0.5 + 0.5 * velocity.min(127) as f64 / 127.0)
+}
+
+fn scale_color(color: &str, brightness: f64) -> String{
+    let [r, g, b] = parse_color(color).map(|channel| (channel as f64 * brightness).round() as u8);
+    format!("#{:02X}{:02X}{:02X}", r, g, b)
+}
+
+pub fn is_white_key(key: u8) -> bool{
+    matches!(key % 12, 0 | 2 | 4 | 5 | 7 | 9 | 11)
+}
+
+pub fn render_scene<B: RenderBackend>(backend: &mut B, song: &Song, options: &RenderOptions, rect: &Rectangle) -> Result<(), B::Error>{
+    let keybord_height = rect.height() * 0.1;
+    let key_areas = calc_key_area(rect, MIN_KEY, MAX_KEY);
+    let current_time = options.current_time;
+
+    // 背景
+    backend.set_fill_style("black");
+    backend.fill_rect(rect)?;
+
+    // オクターブ分割線
+    backend.set_stroke_style("gray");
+    for key in (MIN_KEY..=MAX_KEY).filter(|key| key % 12 == 0){
+        let area = &key_areas[(key - MIN_KEY) as usize];
+        backend.line(area.left(), area.top(), area.left(), area.bottom())?;
+    }
+
+    let display_start_sec = current_time;
+    let display_end_sec = display_start_sec + options.display_range_sec;
+    let pixel_per_sec = rect.height() / options.display_range_sec;
+    let current_time_pos = rect.bottom() - keybord_height;
+
+    // 小節線描画
+    backend.set_stroke_style("gray");
+    backend.set_fill_style("gray");
+    backend.set_text_style(&TextStyle{ font: "32px sans-serif".to_string(), align: "right".to_string(), baseline: "bottom".to_string() });
+    let bars = song.bars();
+    for bar in bars.iter(){
+        if bar.begin_time() > display_end_sec || bar.end_time() < display_start_sec {
+            continue;
+        }
+        let bar_pos = current_time_pos - (bar.begin_time() - current_time) * pixel_per_sec;
+        backend.line(rect.left(), bar_pos, rect.right(), bar_pos)?;
+        backend.text(&(bar.number() + 1).to_string(), rect.right() - 2.0, bar_pos - 2.0)?;
+        if bar.number() == bars.len() as u32 - 1{
+            // 最後の小節線も描画
+            let end_bar_pos = current_time_pos - (bar.end_time() - current_time) * pixel_per_sec;
+            backend.line(rect.left(), end_bar_pos, rect.right(), end_bar_pos)?;
+            backend.text("おわり", rect.right() - 2.0, end_bar_pos - 2.0)?;
+        }
+    }
+
+    // ノート描画
+    let in_range = |note: &&Note| MIN_KEY <= note.key() && note.key() <= MAX_KEY;
+    let display_notes: Vec<&Note> = song.notes().iter().filter(in_range).filter(|note| note.on_time() <= display_end_sec && display_start_sec <= note.off_time()).collect();
+    // 同じ色が続くときは塗りの色を設定し直さない
+    let mut fill_color = String::new();
+    for track_no in 0..song.num_tracks(){
+        let color_index = track_no as usize % TRACK_FILL_COLORS.len();
+        backend.set_stroke_style(TRACK_STROKE_COLORS[color_index]);
+        let velocity_curve = options.velocity_curves.get(track_no);
+        for note in display_notes.iter().filter(|note| note.track() == track_no){
+            let mut color = velocity_color(TRACK_FILL_COLORS[color_index], velocity_curve.apply(note.velocity()));
+            if options.dim_muted && !options.mixer.audible(note){
+                color = scale_color(&color, MUTED_BRIGHTNESS);
+            }
+            if color != fill_color{
+                backend.set_fill_style(&color);
+                fill_color = color;
+            }
+            let area = &key_areas[(note.key() - MIN_KEY) as usize];
+            let note_top = current_time_pos - (note.off_time() - current_time) * pixel_per_sec;
+            let note_height = current_time_pos - (note.on_time() - current_time) * pixel_per_sec - note_top;
+            backend.round_rect(&Rectangle::new(area.left(), note_top, area.width(), note_height), 4.0)?;
+        }
+    }
+
+    let playing_notes: Vec<&Note> = song.notes().iter().filter(in_range).filter(|note| note.on_time() <= current_time && current_time <= note.off_time()).collect();
+
+    // 白鍵
+    let white_note_height = keybord_height;
+    backend.set_stroke_style("gray");
+    backend.set_fill_style("white");
+    for key in (MIN_KEY..=MAX_KEY).filter(|&key| is_white_key(key)){
+        let area = &key_areas[(key - MIN_KEY) as usize];
+        let top = area.bottom() - white_note_height;
+        backend.fill_rect(&Rectangle::new(area.left(), top, area.width(), white_note_height))?;
+        backend.line(area.left(), top, area.left(), area.bottom())?;
+    }
+
+    // 再生している白鍵
+    for track_no in 0..song.num_tracks(){
+        let color_index = track_no as usize % TRACK_FILL_COLORS.len();
+        backend.set_stroke_style(TRACK_STROKE_COLORS[color_index]);
+        backend.set_fill_style(TRACK_FILL_COLORS[color_index]);
+        for note in playing_notes.iter().filter(|note| note.track() == track_no && is_white_key(note.key())){
+            let area = &key_areas[(note.key() - MIN_KEY) as usize];
+            let top = area.bottom() - white_note_height;
+            backend.fill_rect(&Rectangle::new(area.left(), top, area.width(), white_note_height))?;
+            backend.line(area.left(), top, area.left(), area.bottom())?;
+        }
+    }
+
+    // 黒鍵
+    let black_note_height = keybord_height * 0.6;
+    backend.set_fill_style("black");
+    for key in (MIN_KEY..=MAX_KEY).filter(|&key| !is_white_key(key)){
+        let area = &key_areas[(key - MIN_KEY) as usize];
+        backend.fill_rect(&Rectangle::new(area.left(), area.bottom() - white_note_height, area.width(), black_note_height))?;
+    }
+
+    // 再生している黒鍵
+    for track_no in 0..song.num_tracks(){
+        let color_index = track_no as usize % TRACK_FILL_COLORS.len();
+        backend.set_stroke_style(TRACK_STROKE_COLORS[color_index]);
+        backend.set_fill_style(TRACK_FILL_COLORS[color_index]);
+        for note in playing_notes.iter().filter(|note| note.track() == track_no && !is_white_key(note.key())){
+            let area = &key_areas[(note.key() - MIN_KEY) as usize];
+            backend.fill_rect(&Rectangle::new(area.left(), area.bottom() - white_note_height, area.width(), black_note_height))?;
+        }
+    }
+
+    // 拍のランプ (拍の頭で光ってだんだん暗くなる)
+    if options.beat_flash && let Some((beat_time, downbeat)) = beat_at(song, current_time){
+        let brightness = 1.0 - (current_time - beat_time) / BEAT_FLASH_TIME;
+        if brightness > 0.0{
+            let color = scale_color(if downbeat { DOWNBEAT_FLASH_COLOR } else { BEAT_FLASH_COLOR }, brightness);
+            backend.set_fill_style(&color);
+            backend.set_stroke_style(&color);
+            let size = rect.height() * 0.05;
+            backend.round_rect(&Rectangle::new(rect.left() + size * 0.5, rect.top() + size * 0.5, size, size), size * 0.5)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub struct Canvas2dBackend<'a>{
+    context: &'a CanvasRenderingContext2d,
+}
+
+impl<'a> Canvas2dBackend<'a>{
+    pub fn new(context: &'a CanvasRenderingContext2d) -> Self{
+        Canvas2dBackend{
+            context,
+        }
+    }
+}
+
+impl RenderBackend for Canvas2dBackend<'_>{
+    type Error = JsValue;
+
+    fn set_fill_style(&mut self, color: &str){
+        self.context.set_fill_style_str(color);
+    }
+    fn set_stroke_style(&mut self, color: &str){
+        self.context.set_stroke_style_str(color);
+    }
+    fn set_text_style(&mut self, style: &TextStyle){
+        self.context.set_font(&style.font);
+        self.context.set_text_align(&style.align);
+        self.context.set_text_baseline(&style.baseline);
+    }
+    fn fill_rect(&mut self, rect: &Rectangle) -> Result<(), JsValue>{
+        self.context.fill_rect(rect.left(), rect.top(), rect.width(), rect.height());
+        Ok(())
+    }
+    fn round_rect(&mut self, rect: &Rectangle, radius: f64) -> Result<(), JsValue>{
+        self.context.begin_path();
+        self.context.round_rect_with_f64(rect.left(), rect.top(), rect.width(), rect.height(), radius)?;
+        self.context.fill();
+        self.context.stroke();
+        Ok(())
+    }
+    fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) -> Result<(), JsValue>{
+        self.context.begin_path();
+        self.context.move_to(x1, y1);
+        self.context.line_to(x2, y2);
+        self.context.stroke();
+        Ok(())
+    }
+    fn text(&mut self, text: &str, x: f64, y: f64) -> Result<(), JsValue>{
+        self.context.fill_text(text, x, y)
+    }
+}
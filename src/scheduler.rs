@@ -0,0 +1,274 @@
+use crate::engine::AudioEngine;
+use crate::metronome::{Click, CountIn};
+use crate::note::Note;
+use crate::trainer::{LoopCounter, SpeedTrainer};
+use std::collections::VecDeque;
+
+// 先読みで音を予約するスケジューラ
+// エンジンの時計で「今から lookahead 秒先」までに鳴り始めるノートを、開始時刻順のカーソルで順番に予約していく
+// フレームが飛んでもカーソルより後ろのノートは必ず1回だけ予約される
+// 再生速度 rate のときは曲の時刻がエンジンの時刻の rate 倍の速さで進む (音の高さは変えずにノートの長さを伸び縮みさせる)
+// 予備拍があるときは鳴らし始める位置の手前の曲の時刻から進めて、その間はノートの代わりに予備拍のクリックを鳴らす
+const MAX_LATENESS: f64 = 0.25;
+
+// スケジューラがループの始めに戻ったところ
+// 先読みしているので、再生位置はエンジンの時刻が engine_time になってからこれに合わせる
+#[derive(Clone, Copy, Debug)]
+pub struct LoopWrap{
+    pub engine_time: f64,
+    // 戻った先の曲の時刻 (予備拍があればその分だけ手前)
+    pub song_time: f64,
+    pub content_start: f64,
+    pub rate: f64,
+    pub loop_count: u32,
+    // 再生位置がまだ追いついていないうちに reset したら、折り返す前に戻す
+    counter_before: LoopCounter,
+    rate_before: f64,
+}
+
+pub struct Scheduler{
+    lookahead: f64,
+    // 次に予約するノートの番号 (ノートは開始時刻順に並んでいること)
+    cursor: usize,
+    // メトロノームのクリックもノートと同じように予約する (時刻順)
+    clicks: Vec<Click>,
+    click_cursor: usize,
+    // 予備拍のクリック (content_start より前の時刻)
+    count_in_clicks: Vec<Click>,
+    count_in_cursor: usize,
+    // ループの始めに戻るたびに鳴らす予備拍
+    loop_count_in: Option<CountIn>,
+    // ノートを鳴らし始める曲の時刻 (予備拍の間は song_time より後ろ)
+    content_start: f64,
+    // ここまで予約した曲の時刻と、それに対応するエンジンの時刻
+    song_time: f64,
+    engine_time: f64,
+    // 再生速度 (1で元の速さ)
+    rate: f64,
+    // ループを折り返した回数と、折り返すたびに変える速さ (再生位置もこれに従う)
+    loop_counter: LoopCounter,
+    // 予約したけれど、まだ再生位置が追いついていない折り返し
+    wraps: VecDeque<LoopWrap>,
+    // シークやループで飛んだ先で鳴っている途中のノートを鳴らし直すか
+    retrigger: bool,
+    // 飛んだ直後で、まだ鳴らし直していない
+    retrigger_pending: bool,
+}
+
+impl Scheduler{
+    pub fn new(lookahead: f64) -> Self{
+        Scheduler{
+            lookahead,
+            cursor: 0,
+            clicks: Vec::new(),
+            click_cursor: 0,
+            count_in_clicks: Vec::new(),
+            count_in_cursor: 0,
+            loop_count_in: None,
+            content_start: 0.0,
+            song_time: 0.0,
+            engine_time: 0.0,
+            rate: 1.0,
+            loop_counter: LoopCounter::default(),
+            wraps: VecDeque::new(),
+            retrigger: false,
+            retrigger_pending: false,
+        }
+    }
+
+    pub fn lookahead(&self) -> f64{
+        self.lookahead
+    }
+
+    pub fn set_lookahead(&mut self, lookahead: f64){
+        self.lookahead = lookahead.max(0.0);
+    }
+
+    pub fn rate(&self) -> f64{
+        self.rate
+    }
+
+    // 予約済みの区間は前の速さのままなので、再生中に変えるときは reset し直すこと
+    pub fn set_rate(&mut self, rate: f64){
+        self.rate = rate;
+        for wrap in self.wraps.iter_mut(){
+            wrap.rate_before = rate;
+        }
+    }
+
+    pub fn loop_counter(&self) -> &LoopCounter{
+        &self.loop_counter
+    }
+
+    // 折り返した回数を0に戻して、練習するなら最初の速さにする
+    pub fn set_speed_trainer(&mut self, trainer: Option<SpeedTrainer>){
+        self.loop_counter = LoopCounter::new(trainer);
+        self.wraps.clear();
+        if let Some(trainer) = trainer{
+            self.rate = trainer.rate(0);
+        }
+    }
+
+    // エンジンの時刻 engine_now までに来た折り返しを古い順に取り出す
+    pub fn take_wrap(&mut self, engine_now: f64) -> Option<LoopWrap>{
+        self.wraps.pop_front_if(|wrap| wrap.engine_time <= engine_now)
+    }
+
+    // 鳴らすクリック (空ならメトロノームなし) 予約済みのところより後ろから鳴らす
+    pub fn set_clicks(&mut self, clicks: Vec<Click>){
+        self.click_cursor = clicks.partition_point(|click| click.time < self.song_time);
+        self.clicks = clicks;
+    }
+
+    pub fn set_loop_count_in(&mut self, count_in: Option<CountIn>){
+        self.loop_count_in = count_in;
+    }
+
+    pub fn retrigger(&self) -> bool{
+        self.retrigger
+    }
+
+    pub fn set_retrigger(&mut self, retrigger: bool){
+        self.retrigger = retrigger;
+    }
+
+    // 曲の時刻 song_time をエンジンの時刻 engine_time から鳴らし始める (再生開始やシーク)
+    pub fn reset(&mut self, notes: &[Note], song_time: f64, engine_time: f64){
+        self.reset_with_count_in(notes, song_time, engine_time, None);
+    }
+
+    // 先読みして予約した音を取り消してから song_time に移る (鳴っている音はそのまま鳴らしておく)
+    // 予約した音を残すと、新しい位置から予約し直した音と二重に鳴る
+    pub fn seek<E: AudioEngine>(&mut self, engine: &mut E, notes: &[Note], song_time: f64, engine_time: f64){
+        engine.cancel_scheduled(engine_time);
+        self.reset(notes, song_time, engine_time);
+    }
+
+    // 予備拍を鳴らしてから曲の時刻 song_time を鳴らす (予備拍はエンジンの時刻 engine_time から始まる)
+    pub fn reset_with_count_in(&mut self, notes: &[Note], song_time: f64, engine_time: f64, count_in: Option<CountIn>){
+        // 先読みで折り返しただけで再生位置はまだ戻っていないので、回数も速さも数え直さない
+        if let Some(wrap) = self.wraps.front(){
+            self.loop_counter = wrap.counter_before;
+            self.rate = wrap.rate_before;
+        }
+        self.wraps.clear();
+        self.start_at(notes, song_time, count_in);
+        self.engine_time = engine_time;
+    }
+
+    fn start_at(&mut self, notes: &[Note], song_time: f64, count_in: Option<CountIn>){
+        self.cursor = notes.partition_point(|note| note.on_time() < song_time);
+        self.click_cursor = self.clicks.partition_point(|click| click.time < song_time);
+        self.count_in_clicks = count_in.map(|count_in| count_in.clicks(song_time)).unwrap_or_default();
+        self.count_in_cursor = 0;
+        self.content_start = song_time;
+        self.song_time = song_time - count_in.map(|count_in| count_in.length()).unwrap_or(0.0);
+        self.retrigger_pending = true;
+    }
+
+    // 曲の時刻をエンジンの時刻に変換する (予約済みの区間の中だけ正しい)
+    pub fn engine_time_of(&self, song_time: f64) -> f64{
+        self.engine_time + (song_time - self.song_time) / self.rate
+    }
+
+    // engine_now + lookahead までのノートを予約する
+    // ループ中ならループの終わりまで予約したらループの始めに戻って続きを予約する
+    pub fn schedule<E: AudioEngine>(&mut self, engine: &mut E, notes: &[Note], engine_now: f64, loop_range: Option<(f64, f64)>, song_length: f64) -> Result<(), E::Error>{
+        let target = engine_now + self.lookahead;
+        loop{
+            // ループの終わりまで来ていたら (ループの外にシークしたときも) ループの始めに戻る
+            if let Some((loop_start, loop_end)) = loop_range && loop_start < loop_end && self.song_time >= loop_end && self.loop_counter.repeats(){
+                // ループの終わりで鳴っている音はフェードアウトさせて、始めで鳴らし直す音とクロスフェードにする
+                engine.release_all(self.engine_time.max(engine_now));
+                let (counter_before, rate_before) = (self.loop_counter, self.rate);
+                self.start_at(notes, loop_start, self.loop_count_in);
+                if let Some(rate) = self.loop_counter.advance(){
+                    self.rate = rate;
+                }
+                self.wraps.push_back(LoopWrap{
+                    engine_time: self.engine_time,
+                    song_time: self.song_time,
+                    content_start: loop_start,
+                    rate: self.rate,
+                    loop_count: self.loop_counter.iteration(),
+                    counter_before,
+                    rate_before,
+                });
+            }
+            if self.engine_time >= target || self.song_time >= song_length{
+                break;
+            }
+
+            // ループの終わりか曲の終わりで区切られなければ target まで予約しきる
+            let mut segment_end = self.song_time + (target - self.engine_time) * self.rate;
+            let mut clipped = false;
+            if let Some((loop_start, loop_end)) = loop_range && loop_start < loop_end && loop_end < segment_end && self.loop_counter.repeats(){
+                segment_end = loop_end;
+                clipped = true;
+            }
+            if song_length < segment_end{
+                segment_end = song_length;
+                clipped = true;
+            }
+
+            // スピードトレーナーで折り返すたびに速さが変わるので、区間ごとにエンジンに伝える
+            engine.set_playback_rate(self.rate);
+
+            // 予備拍が終わってノートを鳴らし始めるところまで来たら、鳴っている途中のノートを鳴らし直す
+            if self.retrigger_pending && self.content_start < segment_end{
+                self.retrigger_pending = false;
+                if self.retrigger{
+                    self.retrigger_held_notes(engine, notes, engine_now)?;
+                }
+            }
+
+            while let Some(note) = notes.get(self.cursor) && note.on_time() < segment_end{
+                // フレームが飛んで予約が遅れたノートは今から鳴らす
+                // タブが裏に回っていたときのように大きく遅れたものはまとめて鳴ると困るので飛ばす
+                let start_time = self.engine_time_of(note.on_time());
+                if start_time >= engine_now - MAX_LATENESS{
+                    let start_time = start_time.max(engine_now);
+                    engine.play_note(note, start_time, start_time + (note.off_time() - note.on_time()) / self.rate, 0.0)?;
+                }
+                self.cursor += 1;
+            }
+            while let Some(click) = self.count_in_clicks.get(self.count_in_cursor) && click.time < segment_end{
+                self.play_click(engine, click, engine_now)?;
+                self.count_in_cursor += 1;
+            }
+            while let Some(click) = self.clicks.get(self.click_cursor) && click.time < segment_end{
+                self.play_click(engine, click, engine_now)?;
+                self.click_cursor += 1;
+            }
+            // 足し算の誤差で target に届かずに回り続けないよう、区切られていなければ target に揃える
+            self.engine_time = if clipped{ self.engine_time_of(segment_end) } else { target };
+            self.song_time = segment_end;
+        }
+        Ok(())
+    }
+
+    fn play_click<E: AudioEngine>(&self, engine: &mut E, click: &Click, engine_now: f64) -> Result<(), E::Error>{
+        let start_time = self.engine_time_of(click.time);
+        if start_time >= engine_now - MAX_LATENESS{
+            engine.play_click(click, start_time.max(engine_now))?;
+        }
+        Ok(())
+    }
+
+    // 鳴らし始める位置より前に鳴り始めて、まだ鳴っているノートを途中から鳴らす
+    fn retrigger_held_notes<E: AudioEngine>(&self, engine: &mut E, notes: &[Note], engine_now: f64) -> Result<(), E::Error>{
+        let content_time = self.engine_time_of(self.content_start);
+        if content_time < engine_now - MAX_LATENESS{
+            return Ok(());
+        }
+        let start_time = content_time.max(engine_now);
+        // 処理が遅れた分だけ先の位置から鳴らす
+        let song_time = self.content_start + (start_time - content_time) * self.rate;
+        for note in notes[..self.cursor].iter().filter(|note| song_time < note.off_time()){
+            // 経過時間もエンジンの時刻で渡す
+            let offset = (song_time - note.on_time()) / self.rate;
+            engine.play_note(note, start_time, start_time + (note.off_time() - song_time) / self.rate, offset)?;
+        }
+        Ok(())
+    }
+}
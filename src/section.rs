@@ -0,0 +1,69 @@
+// 名前をつけて保存しておく練習の区間 (「12〜16小節の左手」など)
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PracticeSection{
+    pub name: String,
+    // 曲の時刻 (秒)
+    pub start_time: f64,
+    pub end_time: f64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PracticeSections{
+    sections: Vec<PracticeSection>,
+    // 今ループしている区間
+    active: Option<usize>,
+}
+
+impl PracticeSections{
+    pub fn sections(&self) -> &[PracticeSection]{
+        &self.sections
+    }
+
+    pub fn get(&self, index: usize) -> Option<&PracticeSection>{
+        self.sections.get(index)
+    }
+
+    pub fn active(&self) -> Option<usize>{
+        self.active
+    }
+
+    // 追加した区間の番号を返す
+    pub fn add(&mut self, name: &str, start_time: f64, end_time: f64) -> Result<usize, String>{
+        if end_time <= start_time{
+            return Err(format!("区間の終わりが始めより前です: {} - {}", start_time, end_time));
+        }
+        self.sections.push(PracticeSection{ name: name.to_string(), start_time, end_time });
+        Ok(self.sections.len() - 1)
+    }
+
+    pub fn rename(&mut self, index: usize, name: &str) -> Result<(), String>{
+        let section = self.sections.get_mut(index).ok_or_else(|| format!("区間がありません: {}", index))?;
+        section.name = name.to_string();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<PracticeSection, String>{
+        if index >= self.sections.len(){
+            return Err(format!("区間がありません: {}", index));
+        }
+        // 後ろの区間は番号が1つずれる
+        self.active = match self.active{
+            Some(active) if active == index => None,
+            Some(active) if active > index => Some(active - 1),
+            active => active,
+        };
+        Ok(self.sections.remove(index))
+    }
+
+    // None なら区間のループをやめる
+    pub fn activate(&mut self, index: Option<usize>) -> Result<(), String>{
+        if let Some(index) = index && index >= self.sections.len(){
+            return Err(format!("区間がありません: {}", index));
+        }
+        self.active = index;
+        Ok(())
+    }
+}
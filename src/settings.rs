@@ -0,0 +1,57 @@
+use crate::patch::TrackPatches;
+use crate::effects::MasterEffects;
+use crate::metronome::Metronome;
+use crate::mixer::Mixer;
+use crate::section::PracticeSections;
+use crate::stereo::StereoSettings;
+use crate::trainer::SpeedTrainer;
+use crate::velocity::TrackVelocityCurves;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+// 曲とは別に保存しておきたいプレイヤーの設定
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PlayerSettings{
+    pub display_range_sec: f64,
+    pub volume: f32,
+    // 小節でしかループできなかった頃のセッション用 (読み込んだJSONにあって loop_points がないときだけ使う)
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub loop_start_bar: Option<usize>,
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
+    pub loop_end_bar: Option<usize>,
+    pub loop_points: Option<(f64, f64)>,
+    pub sections: PracticeSections,
+    pub patches: TrackPatches,
+    pub velocity_curves: TrackVelocityCurves,
+    pub stereo: StereoSettings,
+    pub mixer: Mixer,
+    pub effects: MasterEffects,
+    pub playback_rate: f64,
+    pub speed_trainer: Option<SpeedTrainer>,
+    pub metronome: Metronome,
+    pub count_in_beats: u8,
+}
+
+impl Default for PlayerSettings{
+    fn default() -> Self{
+        PlayerSettings{
+            display_range_sec: 3.0,
+            volume: 1.0,
+            loop_start_bar: None,
+            loop_end_bar: None,
+            loop_points: None,
+            sections: PracticeSections::default(),
+            patches: TrackPatches::default(),
+            velocity_curves: TrackVelocityCurves::default(),
+            stereo: StereoSettings::default(),
+            mixer: Mixer::default(),
+            effects: MasterEffects::default(),
+            playback_rate: 1.0,
+            speed_trainer: None,
+            metronome: Metronome::default(),
+            count_in_beats: 0,
+        }
+    }
+}
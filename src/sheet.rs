@@ -0,0 +1,166 @@
+use crate::note::Note;
+use crate::pdf::PdfBackend;
+use crate::rectangle::Rectangle;
+use crate::render::{calc_key_area, is_white_key, RenderBackend, TextStyle, MAX_KEY, MIN_KEY, TRACK_FILL_COLORS, TRACK_STROKE_COLORS};
+use crate::song::Song;
+use crate::svg::SvgBackend;
+
+// 曲全体を印刷用のページに割り付ける
+// 1段にN小節ずつ、下から上に時間が進むピアノロールを並べて、各段の下に鍵盤を描く
+
+#[derive(Clone, Debug)]
+pub struct SheetLayout{
+    // 単位はpt (A4縦がデフォルト)
+    pub page_width: f64,
+    pub page_height: f64,
+    pub margin: f64,
+    pub bars_per_system: usize,
+    pub systems_per_page: usize,
+    pub system_spacing: f64,
+    // 段の高さに対する鍵盤の高さの比率
+    pub keyboard_ratio: f64,
+}
+
+impl Default for SheetLayout{
+    fn default() -> Self{
+        SheetLayout{
+            page_width: 595.0,
+            page_height: 842.0,
+            margin: 36.0,
+            bars_per_system: 4,
+            systems_per_page: 4,
+            system_spacing: 16.0,
+            keyboard_ratio: 0.12,
+        }
+    }
+}
+
+impl SheetLayout{
+    pub fn num_systems(&self, song: &Song) -> usize{
+        song.bars().len().div_ceil(self.bars_per_system.max(1))
+    }
+
+    pub fn num_pages(&self, song: &Song) -> usize{
+        self.num_systems(song).div_ceil(self.systems_per_page.max(1)).max(1)
+    }
+
+    fn system_rect(&self, index_in_page: usize) -> Rectangle{
+        let systems_per_page = self.systems_per_page.max(1) as f64;
+        // ページ番号の分だけ下を空けておく
+        let usable_height = self.page_height - self.margin * 2.0 - 12.0;
+        let height = (usable_height - self.system_spacing * (systems_per_page - 1.0)) / systems_per_page;
+        let top = self.margin + (height + self.system_spacing) * index_in_page as f64;
+        Rectangle::new(self.margin, top, self.page_width - self.margin * 2.0, height)
+    }
+}
+
+fn render_system<B: RenderBackend>(backend: &mut B, song: &Song, layout: &SheetLayout, system: usize, rect: &Rectangle) -> Result<(), B::Error>{
+    let bars = song.bars();
+    let first_bar = system * layout.bars_per_system.max(1);
+    let last_bar = (first_bar + layout.bars_per_system.max(1)).min(bars.len()) - 1;
+    let begin_time = bars[first_bar].begin_time();
+    let end_time = bars[last_bar].end_time();
+
+    let keyboard_height = rect.height() * layout.keyboard_ratio;
+    let roll = Rectangle::new(rect.left(), rect.top(), rect.width(), rect.height() - keyboard_height);
+    let key_areas = calc_key_area(rect, MIN_KEY, MAX_KEY);
+    let pixel_per_sec = roll.height() / (end_time - begin_time).max(0.001);
+    let time_to_y = |time: f64| roll.bottom() - (time - begin_time) * pixel_per_sec;
+
+    // オクターブ分割線
+    backend.set_stroke_style("lightgray");
+    for key in (MIN_KEY..=MAX_KEY).filter(|key| key % 12 == 0){
+        let area = &key_areas[(key - MIN_KEY) as usize];
+        backend.line(area.left(), roll.top(), area.left(), roll.bottom())?;
+    }
+
+    // 小節線と小節番号
+    backend.set_stroke_style("gray");
+    backend.set_fill_style("gray");
+    backend.set_text_style(&TextStyle{ font: "8px sans-serif".to_string(), align: "left".to_string(), baseline: "bottom".to_string() });
+    for bar in bars[first_bar..=last_bar].iter(){
+        let y = time_to_y(bar.begin_time());
+        backend.line(roll.left(), y, roll.right(), y)?;
+        backend.text(&(bar.number() + 1).to_string(), roll.left() + 2.0, y - 1.0)?;
+    }
+    backend.line(roll.left(), roll.top(), roll.right(), roll.top())?;
+
+    // ノート (段からはみ出す部分は切る)
+    let notes: Vec<&Note> = song.notes().iter().filter(|note| MIN_KEY <= note.key() && note.key() <= MAX_KEY && note.on_time() < end_time && begin_time < note.off_time()).collect();
+    for track_no in 0..song.num_tracks(){
+        let color_index = track_no as usize % TRACK_FILL_COLORS.len();
+        backend.set_stroke_style(TRACK_STROKE_COLORS[color_index]);
+        backend.set_fill_style(TRACK_FILL_COLORS[color_index]);
+        for note in notes.iter().filter(|note| note.track() == track_no){
+            let area = &key_areas[(note.key() - MIN_KEY) as usize];
+            let top = time_to_y(note.off_time().min(end_time));
+            let bottom = time_to_y(note.on_time().max(begin_time));
+            backend.round_rect(&Rectangle::new(area.left(), top, area.width(), bottom - top), 1.0)?;
+        }
+    }
+
+    // 鍵盤
+    let keyboard_top = roll.bottom();
+    backend.set_stroke_style("gray");
+    backend.set_fill_style("white");
+    for key in (MIN_KEY..=MAX_KEY).filter(|&key| is_white_key(key)){
+        let area = &key_areas[(key - MIN_KEY) as usize];
+        backend.fill_rect(&Rectangle::new(area.left(), keyboard_top, area.width(), keyboard_height))?;
+        backend.line(area.left(), keyboard_top, area.left(), area.bottom())?;
+    }
+    backend.line(rect.right(), keyboard_top, rect.right(), rect.bottom())?;
+    backend.line(rect.left(), rect.bottom(), rect.right(), rect.bottom())?;
+    backend.set_fill_style("black");
+    for key in (MIN_KEY..=MAX_KEY).filter(|&key| !is_white_key(key)){
+        let area = &key_areas[(key - MIN_KEY) as usize];
+        backend.fill_rect(&Rectangle::new(area.left(), keyboard_top, area.width(), keyboard_height * 0.6))?;
+    }
+
+    // Cの鍵盤にオクターブ番号を書いておく
+    backend.set_fill_style("gray");
+    backend.set_text_style(&TextStyle{ font: "5px sans-serif".to_string(), align: "center".to_string(), baseline: "bottom".to_string() });
+    for key in (MIN_KEY..=MAX_KEY).filter(|key| key % 12 == 0){
+        let area = &key_areas[(key - MIN_KEY) as usize];
+        backend.text(&Note::midi_key_to_note_name(key), area.left() + area.width() / 2.0, area.bottom() - 1.0)?;
+    }
+
+    Ok(())
+}
+
+pub fn render_sheet_page<B: RenderBackend>(backend: &mut B, song: &Song, layout: &SheetLayout, page: usize) -> Result<(), B::Error>{
+    backend.set_fill_style("white");
+    backend.fill_rect(&Rectangle::new(0.0, 0.0, layout.page_width, layout.page_height))?;
+
+    let systems_per_page = layout.systems_per_page.max(1);
+    let num_systems = layout.num_systems(song);
+    for index_in_page in 0..systems_per_page{
+        let system = page * systems_per_page + index_in_page;
+        if system >= num_systems{
+            break;
+        }
+        render_system(backend, song, layout, system, &layout.system_rect(index_in_page))?;
+    }
+
+    backend.set_fill_style("gray");
+    backend.set_text_style(&TextStyle{ font: "9px sans-serif".to_string(), align: "center".to_string(), baseline: "bottom".to_string() });
+    backend.text(&format!("{} / {}", page + 1, layout.num_pages(song)), layout.page_width / 2.0, layout.page_height - layout.margin)?;
+    Ok(())
+}
+
+// 1ページ1枚のSVG
+pub fn render_sheet_svg(song: &Song, layout: &SheetLayout) -> Vec<String>{
+    (0..layout.num_pages(song)).map(|page|{
+        let mut backend = SvgBackend::new(layout.page_width, layout.page_height);
+        let Ok(()) = render_sheet_page(&mut backend, song, layout, page);
+        backend.finish()
+    }).collect()
+}
+
+pub fn render_sheet_pdf(song: &Song, layout: &SheetLayout) -> Vec<u8>{
+    let mut backend = PdfBackend::new(layout.page_width, layout.page_height);
+    for page in 0..layout.num_pages(song){
+        backend.new_page();
+        let Ok(()) = render_sheet_page(&mut backend, song, layout, page);
+    }
+    backend.finish()
+}
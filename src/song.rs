@@ -0,0 +1,71 @@
+use crate::bar::Bar;
+use crate::note::Note;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+// 読み込んだ曲データ一式
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Song{
+    bars: Vec<Bar>,
+    notes: Vec<Note>,
+    num_tracks: u8,
+    // 小節の途中でもテンポが変わるところ (秒, BPM) 時刻順
+    // MIDIから読み込んだときだけ入っていて、空なら小節の中ではテンポは変わらないものとする
+    #[cfg_attr(feature = "serde", serde(default))]
+    tempo_changes: Vec<(f64, f64)>,
+}
+
+impl Song{
+    pub fn new(bars: Vec<Bar>, mut notes: Vec<Note>, num_tracks: u8) -> Self{
+        // 再生のスケジューラはノートが開始時刻順に並んでいる前提
+        notes.sort_by(|a, b| a.on_time().total_cmp(&b.on_time()));
+        Song{
+            bars,
+            notes,
+            num_tracks,
+            tempo_changes: Vec::new(),
+        }
+    }
+    pub fn bars(&self) -> &[Bar]{
+        &self.bars
+    }
+    pub fn notes(&self) -> &[Note]{
+        &self.notes
+    }
+    pub fn num_tracks(&self) -> u8{
+        self.num_tracks
+    }
+    pub fn tempo_changes(&self) -> &[(f64, f64)]{
+        &self.tempo_changes
+    }
+    pub fn set_tempo_changes(&mut self, mut tempo_changes: Vec<(f64, f64)>){
+        tempo_changes.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self.tempo_changes = tempo_changes;
+    }
+    // time のときのテンポ (テンポの変化がわからないときは None)
+    pub fn tempo_at(&self, time: f64) -> Option<f64>{
+        self.tempo_changes.iter().rev().find(|change| change.0 <= time).or(self.tempo_changes.first()).map(|change| change.1)
+    }
+    // JSONなどから読み込んだノートを開始時刻順に並べ直す
+    pub fn sorted(self) -> Song{
+        let tempo_changes = self.tempo_changes;
+        let mut song = Song::new(self.bars, self.notes, self.num_tracks);
+        song.set_tempo_changes(tempo_changes);
+        song
+    }
+    pub fn into_parts(self) -> (Vec<Bar>, Vec<Note>, u8){
+        (self.bars, self.notes, self.num_tracks)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Song{
+    pub fn to_json(&self) -> Result<String, String>{
+        serde_json::to_string(self).map_err(|e| e.to_string())
+    }
+    pub fn from_json(json: &str) -> Result<Song, String>{
+        let song: Song = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        Ok(song.sorted())
+    }
+}
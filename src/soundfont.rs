@@ -0,0 +1,474 @@
+use crate::synth::{interpolate, start_from, VCA_RETRIGGER_ATTACK};
+use std::rc::Rc;
+
+// SoundFont 2 (.sf2) の読み込み
+// RIFFの sdta からサンプル、pdta からプリセット -> インストゥルメント -> サンプルの階層を読んで、
+// プリセットごとに鍵盤と強さの範囲で引けるゾーンの一覧にまとめておく
+// モジュレーターやフィルタ、LFOは使わず、音程とループと音量エンベロープだけ再現する
+
+// ジェネレーターの番号 (SoundFont 2.04 の 8.1.2)
+const START_ADDRS_OFFSET: usize = 0;
+const END_ADDRS_OFFSET: usize = 1;
+const STARTLOOP_ADDRS_OFFSET: usize = 2;
+const ENDLOOP_ADDRS_OFFSET: usize = 3;
+const START_ADDRS_COARSE_OFFSET: usize = 4;
+const INITIAL_FILTER_FC: usize = 8;
+const END_ADDRS_COARSE_OFFSET: usize = 12;
+const DELAY_VOL_ENV: usize = 33;
+const ATTACK_VOL_ENV: usize = 34;
+const HOLD_VOL_ENV: usize = 35;
+const DECAY_VOL_ENV: usize = 36;
+const SUSTAIN_VOL_ENV: usize = 37;
+const RELEASE_VOL_ENV: usize = 38;
+const KEYNUM_TO_VOL_ENV_HOLD: usize = 39;
+const KEYNUM_TO_VOL_ENV_DECAY: usize = 40;
+const INSTRUMENT: usize = 41;
+const KEY_RANGE: usize = 43;
+const VEL_RANGE: usize = 44;
+const STARTLOOP_ADDRS_COARSE_OFFSET: usize = 45;
+const KEYNUM: usize = 46;
+const VELOCITY: usize = 47;
+const INITIAL_ATTENUATION: usize = 48;
+const ENDLOOP_ADDRS_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: usize = 53;
+const SAMPLE_MODES: usize = 54;
+const SCALE_TUNING: usize = 56;
+const OVERRIDING_ROOT_KEY: usize = 58;
+const NUM_GENERATORS: usize = 61;
+
+// 0から127まで全部
+const FULL_RANGE: i32 = 127 << 8;
+
+// エンベロープの減衰は dB で直線なので、折れ線はこの数に分けて近似する
+const ENVELOPE_STEPS: usize = 8;
+// 減衰やリリースの時間は 100dB 下がるまでの時間
+const ENVELOPE_RANGE_DB: f64 = 100.0;
+
+fn default_generators() -> [i32; NUM_GENERATORS]{
+    let mut generators = [0; NUM_GENERATORS];
+    for i in [DELAY_VOL_ENV, ATTACK_VOL_ENV, HOLD_VOL_ENV, DECAY_VOL_ENV, RELEASE_VOL_ENV]{
+        generators[i] = -12000;
+    }
+    generators[INITIAL_FILTER_FC] = 13500;
+    generators[KEY_RANGE] = FULL_RANGE;
+    generators[VEL_RANGE] = FULL_RANGE;
+    generators[SCALE_TUNING] = 100;
+    generators[OVERRIDING_ROOT_KEY] = -1;
+    generators[KEYNUM] = -1;
+    generators[VELOCITY] = -1;
+    generators
+}
+
+// 範囲のジェネレーターは下位バイトが下限、上位バイトが上限
+fn range(amount: i32) -> (u8, u8){
+    ((amount & 0xff) as u8, ((amount >> 8) & 0xff) as u8)
+}
+
+fn intersect(a: (u8, u8), b: (u8, u8)) -> Option<(u8, u8)>{
+    let range = (a.0.max(b.0), a.1.min(b.1));
+    (range.0 <= range.1).then_some(range)
+}
+
+// タイムセントから秒
+fn timecents(amount: i32) -> f64{
+    2f64.powf(amount as f64 / 1200.0)
+}
+
+// センチベルから音量の倍率
+fn centibels_to_gain(amount: f64) -> f64{
+    10f64.powf(-amount / 200.0)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16>{
+    let bytes = data.get(offset..offset + 2)?;
+    Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32>{
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_name(data: &[u8]) -> String{
+    let end = data.iter().position(|&c| c == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+const TRUNCATED_CHUNK: &str = "sf2のチャンクが途中で切れています";
+
+// RIFFのチャンク (ID, 中身)
+type Chunk<'a> = ([u8; 4], &'a [u8]);
+
+// RIFFのチャンクに分ける (奇数長のチャンクは1バイト詰める)
+fn read_chunks(data: &[u8]) -> Result<Vec<Chunk<'_>>, String>{
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= data.len(){
+        let id = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+        let size = read_u32(data, offset + 4).ok_or("sf2が壊れています")? as usize;
+        // wasm32ではファイルに書かれた大きさを足すと溢れることがある
+        let end = (offset + 8).checked_add(size).ok_or(TRUNCATED_CHUNK)?;
+        let body = data.get(offset + 8..end).ok_or(TRUNCATED_CHUNK)?;
+        chunks.push((id, body));
+        offset = end.checked_add(size % 2).ok_or(TRUNCATED_CHUNK)?;
+    }
+    Ok(chunks)
+}
+
+// LISTチャンクの中身
+fn read_list<'a>(chunks: &[Chunk<'a>], list_type: &[u8; 4]) -> Result<Vec<Chunk<'a>>, String>{
+    let body = chunks.iter()
+        .find(|(id, body)| id == b"LIST" && body.starts_with(list_type))
+        .map(|(_, body)| &body[4..])
+        .ok_or_else(|| format!("sf2に{}がありません", String::from_utf8_lossy(list_type)))?;
+    read_chunks(body)
+}
+
+fn find_chunk<'a>(chunks: &[Chunk<'a>], id: &[u8; 4]) -> Result<&'a [u8], String>{
+    chunks.iter()
+        .find(|(chunk_id, _)| chunk_id == id)
+        .map(|(_, body)| *body)
+        .ok_or_else(|| format!("sf2に{}がありません", String::from_utf8_lossy(id)))
+}
+
+// ゾーンごとのジェネレーター (設定されていないものは None)
+type ZoneGenerators = Vec<[Option<i32>; NUM_GENERATORS]>;
+
+// bag (ゾーンの一覧) と gen (ジェネレーターの一覧) から [first_bag, last_bag) のゾーンを読む
+fn read_zones(bags: &[u8], generators: &[u8], first_bag: usize, last_bag: usize) -> Result<ZoneGenerators, String>{
+    let mut zones = Vec::new();
+    for bag in first_bag..last_bag{
+        let first = read_u16(bags, bag * 4).ok_or("sf2のbagが壊れています")? as usize;
+        let last = read_u16(bags, bag * 4 + 4).ok_or("sf2のbagが壊れています")? as usize;
+        let mut zone = [None; NUM_GENERATORS];
+        for i in first..last{
+            let op = read_u16(generators, i * 4).ok_or("sf2のgenが壊れています")? as usize;
+            let amount = read_u16(generators, i * 4 + 2).ok_or("sf2のgenが壊れています")?;
+            if op < NUM_GENERATORS{
+                // 範囲は符号なし、それ以外は符号付き
+                zone[op] = Some(if op == KEY_RANGE || op == VEL_RANGE { amount as i32 } else { amount as i16 as i32 });
+            }
+        }
+        zones.push(zone);
+    }
+    Ok(zones)
+}
+
+// 最初のゾーンが last_generator を持っていなければグローバルゾーン
+fn split_global(mut zones: ZoneGenerators, last_generator: usize) -> ([Option<i32>; NUM_GENERATORS], ZoneGenerators){
+    if zones.first().is_some_and(|zone| zone[last_generator].is_none()){
+        let global = zones.remove(0);
+        (global, zones)
+    }else{
+        ([None; NUM_GENERATORS], zones)
+    }
+}
+
+struct SampleHeader{
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+    rom: bool,
+}
+
+fn read_sample_headers(shdr: &[u8]) -> Vec<SampleHeader>{
+    shdr.chunks_exact(46).map(|record| SampleHeader{
+        start: read_u32(record, 20).unwrap(),
+        end: read_u32(record, 24).unwrap(),
+        loop_start: read_u32(record, 28).unwrap(),
+        loop_end: read_u32(record, 32).unwrap(),
+        sample_rate: read_u32(record, 36).unwrap(),
+        original_pitch: record[40],
+        pitch_correction: record[41] as i8,
+        rom: read_u16(record, 44).unwrap() & 0x8000 != 0,
+    }).collect()
+}
+
+// 音量エンベロープ (秒, サステインは最大音量に対する割合)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VolumeEnvelope{
+    pub delay: f64,
+    pub attack: f64,
+    pub hold: f64,
+    // 最大音量から100dB下がるまでの時間
+    pub decay: f64,
+    pub sustain: f64,
+    pub release: f64,
+}
+
+impl VolumeEnvelope{
+    pub fn at_rate(&self, rate: f64) -> VolumeEnvelope{
+        VolumeEnvelope{
+            delay: self.delay / rate,
+            attack: self.attack / rate,
+            hold: self.hold / rate,
+            decay: self.decay / rate,
+            release: self.release / rate,
+            ..*self
+        }
+    }
+}
+
+// プリセットのゾーンとインストゥルメントのゾーンを合わせた1つのサンプルの鳴らし方
+#[derive(Clone, Debug)]
+pub struct Zone{
+    pub key_range: (u8, u8),
+    pub velocity_range: (u8, u8),
+    // サンプル全体の中の位置 (サンプル数)
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub looping: bool,
+    pub sample_rate: u32,
+    generators: [i32; NUM_GENERATORS],
+    root_key: u8,
+    pitch_correction: i8,
+}
+
+impl Zone{
+    pub fn contains(&self, key: u8, velocity: u8) -> bool{
+        self.key_range.0 <= key && key <= self.key_range.1 && self.velocity_range.0 <= velocity && velocity <= self.velocity_range.1
+    }
+
+    // サンプルを元の速さの何倍で再生すると key の音程になるか
+    pub fn pitch_ratio(&self, key: u8) -> f64{
+        let g = &self.generators;
+        let cents = (key as f64 - self.root_key as f64) * g[SCALE_TUNING] as f64 + g[COARSE_TUNE] as f64 * 100.0 + g[FINE_TUNE] as f64 + self.pitch_correction as f64;
+        2f64.powf(cents / 1200.0)
+    }
+
+    // 鳴り始めから elapsed 秒たったときの、サンプルの start からの位置 (サンプル数)
+    // ループしないサンプルを最後まで鳴らし終わっていたら None
+    pub fn position(&self, key: u8, elapsed: f64) -> Option<f64>{
+        self.wrap(elapsed.max(0.0) * self.pitch_ratio(key) * self.sample_rate as f64)
+    }
+
+    // ループの終わりを過ぎた位置をループの中に戻す
+    pub fn wrap(&self, position: f64) -> Option<f64>{
+        let loop_start = (self.loop_start - self.start) as f64;
+        let loop_end = (self.loop_end - self.start) as f64;
+        if self.looping && position >= loop_end{
+            Some(loop_start + (position - loop_start) % (loop_end - loop_start))
+        }else if position < (self.end - self.start) as f64{
+            Some(position)
+        }else{
+            None
+        }
+    }
+
+    // 強さに応じた最大音量
+    // 強さの既定のモジュレーターは凹型の曲線で減衰させるので、2乗で近似する
+    pub fn gain(&self, velocity: u8) -> f64{
+        centibels_to_gain(self.generators[INITIAL_ATTENUATION].max(0) as f64) * (velocity as f64 / 127.0).powi(2)
+    }
+
+    pub fn envelope(&self, key: u8) -> VolumeEnvelope{
+        let g = &self.generators;
+        // 鍵盤によってホールドと減衰の長さを変える (60が基準)
+        let key_offset = 60 - key as i32;
+        VolumeEnvelope{
+            delay: timecents(g[DELAY_VOL_ENV]),
+            attack: timecents(g[ATTACK_VOL_ENV]),
+            hold: timecents(g[HOLD_VOL_ENV] + g[KEYNUM_TO_VOL_ENV_HOLD] * key_offset),
+            decay: timecents(g[DECAY_VOL_ENV] + g[KEYNUM_TO_VOL_ENV_DECAY] * key_offset),
+            sustain: centibels_to_gain(g[SUSTAIN_VOL_ENV].clamp(0, 1440) as f64),
+            release: timecents(g[RELEASE_VOL_ENV]),
+        }
+    }
+
+    // 音量のエンベロープの折れ線 (synth::vca_envelope と同じく offset は鳴り始めてからの経過時間)
+    // 再生速度 playback_rate で鳴らすときは時間を 1/playback_rate 倍にする
+    pub fn vca_envelope(&self, key: u8, base_gain: f64, start_time: f64, end_time: f64, offset: f64, playback_rate: f64) -> Vec<(f64, f64)>{
+        let envelope = self.envelope(key).at_rate(playback_rate);
+        let origin = start_time - offset;
+        let attack_end = origin + envelope.delay + envelope.attack;
+        let hold_end = attack_end + envelope.hold;
+        let sustain_db = -20.0 * envelope.sustain.max(1e-5).log10();
+        let decay_end = hold_end + envelope.decay * (sustain_db / ENVELOPE_RANGE_DB).min(1.0);
+
+        let mut points = vec![(origin, 0.0), (origin + envelope.delay, 0.0), (attack_end, base_gain), (hold_end, base_gain)];
+        points.extend(exponential_decay(hold_end, decay_end, base_gain, sustain_db));
+
+        // 離鍵したらその時点の音量から下げていく
+        let end_time = end_time.max(start_time + VCA_RETRIGGER_ATTACK);
+        let level = interpolate(&points, end_time);
+        points.retain(|point| point.0 < end_time);
+        points.push((end_time, level));
+        points.extend(exponential_decay(end_time, end_time + envelope.release.max(VCA_RETRIGGER_ATTACK), level, ENVELOPE_RANGE_DB));
+        if let Some(last) = points.last_mut(){
+            last.1 = 0.0;
+        }
+        start_from(points, start_time, offset, VCA_RETRIGGER_ATTACK)
+    }
+}
+
+// from_time から to_time までに level から drop_db 下がる曲線の折れ線 (最初の点は含めない)
+fn exponential_decay(from_time: f64, to_time: f64, level: f64, drop_db: f64) -> impl Iterator<Item = (f64, f64)>{
+    (1..=ENVELOPE_STEPS).filter(move |_| to_time > from_time).map(move |i|{
+        let t = i as f64 / ENVELOPE_STEPS as f64;
+        (from_time + (to_time - from_time) * t, level * 10f64.powf(-drop_db * t / 20.0))
+    })
+}
+
+// 1つのプリセット (サンプル全体も共有して持つので、これだけで鳴らせる)
+#[derive(Clone, Debug)]
+pub struct Preset{
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    pub zones: Vec<Zone>,
+    pub samples: Rc<[f32]>,
+}
+
+impl Preset{
+    // key を velocity で弾いたときに鳴らすゾーン (ステレオのサンプルなどで複数のこともある)
+    pub fn zones_for(&self, key: u8, velocity: u8) -> impl Iterator<Item = &Zone>{
+        self.zones.iter().filter(move |zone| zone.contains(key, velocity))
+    }
+
+    pub fn sample_data(&self, zone: &Zone) -> &[f32]{
+        &self.samples[zone.start..zone.end]
+    }
+}
+
+pub struct SoundFont{
+    presets: Vec<Preset>,
+}
+
+impl SoundFont{
+    pub fn parse(data: &[u8]) -> Result<SoundFont, String>{
+        if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk"{
+            return Err("sf2ファイルではありません".to_string());
+        }
+        let size = read_u32(data, 4).unwrap() as usize;
+        let end = size.checked_add(8).ok_or(TRUNCATED_CHUNK)?;
+        let chunks = read_chunks(data.get(12..end).unwrap_or(&data[12..]))?;
+
+        let sdta = read_list(&chunks, b"sdta")?;
+        let samples: Rc<[f32]> = find_chunk(&sdta, b"smpl")?
+            .chunks_exact(2)
+            .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0)
+            .collect();
+
+        let pdta = read_list(&chunks, b"pdta")?;
+        let phdr = find_chunk(&pdta, b"phdr")?;
+        let pbag = find_chunk(&pdta, b"pbag")?;
+        let pgen = find_chunk(&pdta, b"pgen")?;
+        let inst = find_chunk(&pdta, b"inst")?;
+        let ibag = find_chunk(&pdta, b"ibag")?;
+        let igen = find_chunk(&pdta, b"igen")?;
+        let sample_headers = read_sample_headers(find_chunk(&pdta, b"shdr")?);
+
+        // 最後のレコードは終端
+        let num_presets = (phdr.len() / 38).saturating_sub(1);
+        let num_instruments = (inst.len() / 22).saturating_sub(1);
+        let mut presets = Vec::new();
+        for p in 0..num_presets{
+            let record = &phdr[p * 38..];
+            let first_bag = read_u16(record, 24).unwrap() as usize;
+            let last_bag = read_u16(record, 38 + 24).unwrap() as usize;
+            let (preset_global, preset_zones) = split_global(read_zones(pbag, pgen, first_bag, last_bag)?, INSTRUMENT);
+
+            let mut zones = Vec::new();
+            for preset_zone in preset_zones.iter(){
+                let Some(instrument) = preset_zone[INSTRUMENT].map(|i| i as usize).filter(|&i| i < num_instruments) else{
+                    continue;
+                };
+                let preset_value = |op: usize| preset_zone[op].or(preset_global[op]);
+                let first = read_u16(inst, instrument * 22 + 20).ok_or("sf2のinstが壊れています")? as usize;
+                let last = read_u16(inst, instrument * 22 + 22 + 20).ok_or("sf2のinstが壊れています")? as usize;
+                let (instrument_global, instrument_zones) = split_global(read_zones(ibag, igen, first, last)?, SAMPLE_ID);
+
+                for instrument_zone in instrument_zones.iter(){
+                    let Some(header) = instrument_zone[SAMPLE_ID].and_then(|i| sample_headers.get(i as usize)) else{
+                        continue;
+                    };
+                    if header.rom{
+                        continue;
+                    }
+
+                    // インストゥルメントの値にプリセットの値を足す (範囲は両方の重なり)
+                    let mut generators = default_generators();
+                    for op in 0..NUM_GENERATORS{
+                        if let Some(value) = instrument_zone[op].or(instrument_global[op]){
+                            generators[op] = value;
+                        }
+                        if op != KEY_RANGE && op != VEL_RANGE && op != SAMPLE_ID && op != SAMPLE_MODES && op != OVERRIDING_ROOT_KEY
+                            && let Some(value) = preset_value(op){
+                            generators[op] += value;
+                        }
+                    }
+                    let key_range = intersect(range(generators[KEY_RANGE]), range(preset_value(KEY_RANGE).unwrap_or(FULL_RANGE)));
+                    let velocity_range = intersect(range(generators[VEL_RANGE]), range(preset_value(VEL_RANGE).unwrap_or(FULL_RANGE)));
+                    let (Some(key_range), Some(velocity_range)) = (key_range, velocity_range) else{
+                        continue;
+                    };
+
+                    let g = &generators;
+                    let position = |base: u32, fine: usize, coarse: usize| (base as i64 + g[fine] as i64 + g[coarse] as i64 * 32768).clamp(0, samples.len() as i64) as usize;
+                    let start = position(header.start, START_ADDRS_OFFSET, START_ADDRS_COARSE_OFFSET);
+                    let end = position(header.end, END_ADDRS_OFFSET, END_ADDRS_COARSE_OFFSET).max(start);
+                    let loop_start = position(header.loop_start, STARTLOOP_ADDRS_OFFSET, STARTLOOP_ADDRS_COARSE_OFFSET).clamp(start, end);
+                    let loop_end = position(header.loop_end, ENDLOOP_ADDRS_OFFSET, ENDLOOP_ADDRS_COARSE_OFFSET).clamp(loop_start, end);
+                    if end == start || header.sample_rate == 0{
+                        continue;
+                    }
+                    let root_key = match g[OVERRIDING_ROOT_KEY]{
+                        key @ 0..=127 => key as u8,
+                        _ if header.original_pitch <= 127 => header.original_pitch,
+                        _ => 60,
+                    };
+
+                    zones.push(Zone{
+                        key_range,
+                        velocity_range,
+                        start,
+                        end,
+                        loop_start,
+                        loop_end,
+                        // 1: ずっとループ, 3: 離鍵まではループ (どちらもループさせたままリリースする)
+                        looping: g[SAMPLE_MODES] & 1 == 1 && loop_end > loop_start,
+                        sample_rate: header.sample_rate,
+                        generators,
+                        root_key,
+                        pitch_correction: header.pitch_correction,
+                    });
+                }
+            }
+
+            presets.push(Preset{
+                name: read_name(&record[..20]),
+                program: read_u16(record, 20).unwrap(),
+                bank: read_u16(record, 22).unwrap(),
+                zones,
+                samples: samples.clone(),
+            });
+        }
+
+        if presets.iter().all(|preset| preset.zones.is_empty()){
+            return Err("sf2に鳴らせるプリセットがありません".to_string());
+        }
+        Ok(SoundFont{
+            presets,
+        })
+    }
+
+    pub fn presets(&self) -> &[Preset]{
+        &self.presets
+    }
+
+    // 最初に使うプリセットの番号 (GMのピアノにあたるバンク0のプログラム0、なければ鳴らせる一番若いプリセット)
+    pub fn default_preset(&self) -> usize{
+        (0..self.presets.len())
+            .filter(|&i| !self.presets[i].zones.is_empty())
+            .min_by_key(|&i| (self.presets[i].bank, self.presets[i].program))
+            .unwrap_or(0)
+    }
+}
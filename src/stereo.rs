@@ -0,0 +1,63 @@
+use crate::note::Note;
+use crate::track::PerTrack;
+use std::f64::consts::FRAC_PI_2;
+use wasm_bindgen::prelude::*;
+
+// ピアノの鍵盤の両端 (A0とC8)
+const LOWEST_KEY: f64 = 21.0;
+const HIGHEST_KEY: f64 = 108.0;
+
+// ノートの左右の位置の決め方
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PanMode{
+    // 全部真ん中
+    #[default]
+    Center,
+    // 弾いている人から聞こえるように低い音は左、高い音は右
+    PianoPerspective,
+}
+
+// 鍵盤の位置の左右 (-1が左端、1が右端)
+pub fn key_pan(key: u8) -> f64{
+    ((key as f64 - LOWEST_KEY) / (HIGHEST_KEY - LOWEST_KEY) * 2.0 - 1.0).clamp(-1.0, 1.0)
+}
+
+// StereoPannerNodeと同じ等パワーの左右の音量
+pub fn pan_gains(pan: f64) -> (f64, f64){
+    let x = (pan.clamp(-1.0, 1.0) + 1.0) / 2.0;
+    ((x * FRAC_PI_2).cos(), (x * FRAC_PI_2).sin())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct StereoSettings{
+    pub mode: PanMode,
+    // 0でモノラル、1で決めた位置そのまま
+    pub width: f64,
+    // トラックごとに位置を決めるとき (None なら mode に従う)
+    pub track_pans: PerTrack<Option<f64>>,
+}
+
+impl Default for StereoSettings{
+    fn default() -> Self{
+        StereoSettings{
+            mode: PanMode::Center,
+            width: 1.0,
+            track_pans: PerTrack::default(),
+        }
+    }
+}
+
+impl StereoSettings{
+    // ノートを置く位置 (-1が左端、1が右端)
+    pub fn pan(&self, note: &Note) -> f64{
+        let pan = self.track_pans.get(note.track()).unwrap_or(match self.mode{
+            PanMode::Center => 0.0,
+            PanMode::PianoPerspective => key_pan(note.key()),
+        });
+        (pan * self.width.clamp(0.0, 1.0)).clamp(-1.0, 1.0)
+    }
+}
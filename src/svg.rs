@@ -0,0 +1,93 @@
+use crate::rectangle::Rectangle;
+use crate::render::{render_scene, RenderBackend, RenderOptions, TextStyle};
+use crate::song::Song;
+use std::convert::Infallible;
+use std::fmt::Write;
+
+// MidiPlayer::renderと同じ画面をSVGの文字列として書き出す
+// サムネイルやレッスンページへの埋め込み、描画のスナップショットテスト用
+
+fn escape(text: &str) -> String{
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub struct SvgBackend{
+    out: String,
+    fill: String,
+    stroke: String,
+    text_style: TextStyle,
+}
+
+impl SvgBackend{
+    pub fn new(width: f64, height: f64) -> Self{
+        SvgBackend{
+            out: format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n", width, height, width, height),
+            fill: "black".to_string(),
+            stroke: "black".to_string(),
+            text_style: TextStyle{ font: "10px sans-serif".to_string(), align: "start".to_string(), baseline: "alphabetic".to_string() },
+        }
+    }
+
+    pub fn finish(mut self) -> String{
+        self.out.push_str("</svg>\n");
+        self.out
+    }
+
+    fn text_attributes(&self) -> String{
+        // "32px sans-serif" のようなCSSのfont指定をサイズとファミリーに分ける
+        let (size, family) = self.text_style.font.split_once("px ").unwrap_or(("10", self.text_style.font.as_str()));
+        let anchor = match self.text_style.align.as_str(){
+            "right" | "end" => "end",
+            "center" => "middle",
+            _ => "start",
+        };
+        let baseline = match self.text_style.baseline.as_str(){
+            "bottom" => "text-after-edge",
+            "top" => "text-before-edge",
+            "middle" => "middle",
+            _ => "alphabetic",
+        };
+        format!(r#"font-family="{}" font-size="{}" text-anchor="{}" dominant-baseline="{}""#, escape(family), size, anchor, baseline)
+    }
+}
+
+impl RenderBackend for SvgBackend{
+    type Error = Infallible;
+
+    fn set_fill_style(&mut self, color: &str){
+        self.fill = escape(color);
+    }
+    fn set_stroke_style(&mut self, color: &str){
+        self.stroke = escape(color);
+    }
+    fn set_text_style(&mut self, style: &TextStyle){
+        self.text_style = style.clone();
+    }
+    fn fill_rect(&mut self, rect: &Rectangle) -> Result<(), Infallible>{
+        let _ = writeln!(self.out, r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="{}"/>"#, rect.left(), rect.top(), rect.width(), rect.height(), self.fill);
+        Ok(())
+    }
+    fn round_rect(&mut self, rect: &Rectangle, radius: f64) -> Result<(), Infallible>{
+        let _ = writeln!(self.out, r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" rx="{}" fill="{}" stroke="{}"/>"#, rect.left(), rect.top(), rect.width(), rect.height(), radius, self.fill, self.stroke);
+        Ok(())
+    }
+    fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64) -> Result<(), Infallible>{
+        let _ = writeln!(self.out, r#"<line x1="{:.2}" y1="{:.2}" x2="{:.2}" y2="{:.2}" stroke="{}"/>"#, x1, y1, x2, y2, self.stroke);
+        Ok(())
+    }
+    fn text(&mut self, text: &str, x: f64, y: f64) -> Result<(), Infallible>{
+        let _ = writeln!(self.out, r#"<text x="{:.2}" y="{:.2}" fill="{}" {}>{}</text>"#, x, y, self.fill, self.text_attributes(), escape(text));
+        Ok(())
+    }
+}
+
+pub fn render_svg(song: &Song, current_time: f64, display_range_sec: f64, width: f64, height: f64) -> String{
+    render_svg_with_options(song, &RenderOptions{ current_time, display_range_sec, ..Default::default() }, width, height)
+}
+
+// 画面と同じ設定 (ミュートの暗さや拍のランプなど) で描く
+pub fn render_svg_with_options(song: &Song, options: &RenderOptions, width: f64, height: f64) -> String{
+    let mut backend = SvgBackend::new(width, height);
+    let Ok(()) = render_scene(&mut backend, song, options, &Rectangle::new(0.0, 0.0, width, height));
+    backend.finish()
+}
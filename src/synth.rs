@@ -1,75 +1,539 @@
+use crate::note::Note;
+use crate::patch::{SynthPatch, VoiceModel, Waveform};
+use crate::piano;
+use crate::polyphony::VoiceState;
+use crate::effects::{impulse_response, MasterEffects, EQ_HIGH_FREQ, EQ_LOW_FREQ, EQ_MID_FREQ, EQ_MID_Q, LIMITER_ATTACK, LIMITER_RATIO, LIMITER_RELEASE};
+use crate::soundfont::{Preset, Zone};
 use wasm_bindgen::prelude::*;
-use web_sys::{AudioContext, AudioNode, BiquadFilterNode, GainNode, OscillatorNode, BiquadFilterType, OscillatorType};
+use web_sys::{AudioBuffer, AudioBufferSourceNode, AudioContext, AudioNode, AudioParam, AudioScheduledSourceNode, BiquadFilterNode, ConvolverNode, DynamicsCompressorNode, GainNode, OscillatorNode, StereoPannerNode, BiquadFilterType, OscillatorType};
 
-pub struct SoundSource {
-    vco: OscillatorNode,
+// ブラウザ(SoundSource)でもオフライン(dsp)でも同じ音になるように音色のパラメーターはここで決める
+// 音色ごとに変えられるものは SynthPatch の初期値
+pub const VCA_ATTACK: f64 = 0.1;
+pub const VCA_DECAY: f64 = 0.2;
+pub const VCA_SUSTAIN: f32 = 0.5;
+pub const VCA_RELEASE: f64 = 1.2;
+// 鳴っている途中から鳴らし直すときのアタックと、停止やシークで止めるときのフェード
+// ループの境目で同時に起きるので、同じ長さにしてクロスフェードになるようにしている
+pub const VCA_RETRIGGER_ATTACK: f64 = 0.03;
+pub const VCA_FADE_OUT: f64 = VCA_RETRIGGER_ATTACK;
+// 鳴っている音のトラックの音量を変えるときの時定数
+pub const LEVEL_CHANGE_TIME: f64 = 0.01;
+pub const VCF_CUTOFF_RATIO: f32 = 4.0;
+pub const VCF_CUTOFF_MAX: f32 = 10000.0;
+pub const VCF_END_CUTOFF_RATIO: f32 = 0.5;
+// 音が重なるとノイズが気になるので出力の手前にコンプ刺す (MasterEffects の初期値)
+pub const COMP_THRESHOLD: f32 = -20.0;
+pub const COMP_KNEE: f32 = 15.0;
+pub const COMP_RATIO: f32 = 20.0;
+
+// 折れ線 (時刻, 値) の time での値
+pub fn interpolate(points: &[(f64, f64)], time: f64) -> f64{
+    let Some(first) = points.first() else{
+        return 0.0;
+    };
+    if time <= first.0{
+        return first.1;
+    }
+    for w in points.windows(2){
+        let ((t0, v0), (t1, v1)) = (w[0], w[1]);
+        if time < t1{
+            return if t1 > t0 { v0 + (v1 - v0) * (time - t0) / (t1 - t0) } else { v1 };
+        }
+    }
+    points.last().unwrap().1
+}
+
+// 鳴り始めから offset 秒たったところから鳴らすときは、短いアタックでその時点の値まで上げて続きをたどる
+pub fn start_from(points: Vec<(f64, f64)>, start_time: f64, offset: f64, attack: f64) -> Vec<(f64, f64)>{
+    if offset <= 0.0{
+        return points;
+    }
+    let attack_end = start_time + attack;
+    let mut result = vec![(start_time, 0.0), (attack_end, interpolate(&points, attack_end))];
+    result.extend(points.into_iter().filter(|point| point.0 > attack_end));
+    result
+}
+
+// 音量のエンベロープ
+pub fn vca_envelope(patch: &SynthPatch, base_gain: f64, start_time: f64, end_time: f64, offset: f64) -> Vec<(f64, f64)>{
+    let origin = start_time - offset;
+    let sustain = patch.sustain.clamp(0.0, 1.0) * base_gain;
+    let off_time = vca_off_time(patch, start_time, end_time, offset);
+    start_from(vec![
+        (origin, 0.0),
+        (origin + patch.attack, base_gain),
+        (origin + patch.attack + patch.decay, sustain),
+        (off_time, sustain),
+        (vca_end_time(patch, off_time), 0.0001),
+    ], start_time, offset, VCA_RETRIGGER_ATTACK)
+}
+
+// リリースを始める時刻 (アタックとディケイより短い音でもそこまでは鳴らす)
+pub fn vca_off_time(patch: &SynthPatch, start_time: f64, end_time: f64, offset: f64) -> f64{
+    let origin = start_time - offset;
+    end_time.max(origin + patch.attack + patch.decay).max(start_time + VCA_RETRIGGER_ATTACK)
+}
+
+// リリースが終わって音が消える時刻
+pub fn vca_end_time(patch: &SynthPatch, off_time: f64) -> f64{
+    off_time + patch.release.max(VCA_FADE_OUT)
+}
+
+// 音量のエンベロープを time から VCA_FADE_OUT で0まで下げるように書き換える
+pub fn fade_out(points: &[(f64, f64)], time: f64) -> Vec<(f64, f64)>{
+    let mut result: Vec<(f64, f64)> = points.iter().copied().filter(|point| point.0 < time).collect();
+    result.push((time, interpolate(points, time)));
+    result.push((time + VCA_FADE_OUT, 0.0));
+    result
+}
+
+// フィルタのカットオフのエンベロープ (end_time に向けて変えていく)
+pub fn vcf_envelope(patch: &SynthPatch, velocity: u8, freq: f64, start_time: f64, end_time: f64, offset: f64) -> Vec<(f64, f64)>{
+    let origin = start_time - offset;
+    let (cutoff_ratio, cutoff_end_ratio) = patch.cutoff_ratios(velocity);
+    let points = vec![(origin, (freq * cutoff_ratio).min(patch.cutoff_max)), (end_time, (freq * cutoff_end_ratio).min(patch.cutoff_max))];
+    let value = interpolate(&points, start_time);
+    vec![(start_time, value), (end_time.max(start_time), points[1].1)]
+}
+
+// 全部の音をまとめた後のエフェクト
+// input -> EQ(低, 中, 高) -> 原音 + リバーブ -> コンプ -> リミッター -> destination
+pub struct MasterChain {
+    input: GainNode,
+    eq_low: BiquadFilterNode,
+    eq_mid: BiquadFilterNode,
+    eq_high: BiquadFilterNode,
+    convolver: ConvolverNode,
+    reverb_gain: GainNode,
+    comp: DynamicsCompressorNode,
+    limiter: DynamicsCompressorNode,
+    effects: MasterEffects,
+}
+
+impl MasterChain {
+    pub fn new(context: &AudioContext, destination_target: &AudioNode) -> Result<MasterChain, JsValue> {
+        let input = context.create_gain()?;
+        let eq_low = context.create_biquad_filter()?;
+        eq_low.set_type(BiquadFilterType::Lowshelf);
+        eq_low.frequency().set_value(EQ_LOW_FREQ as f32);
+        let eq_mid = context.create_biquad_filter()?;
+        eq_mid.set_type(BiquadFilterType::Peaking);
+        eq_mid.frequency().set_value(EQ_MID_FREQ as f32);
+        eq_mid.q().set_value(EQ_MID_Q as f32);
+        let eq_high = context.create_biquad_filter()?;
+        eq_high.set_type(BiquadFilterType::Highshelf);
+        eq_high.frequency().set_value(EQ_HIGH_FREQ as f32);
+
+        // インパルス応答はこちらで大きさを揃えて作る
+        let convolver = context.create_convolver()?;
+        convolver.set_normalize(false);
+        let reverb_gain = context.create_gain()?;
+        let comp = context.create_dynamics_compressor()?;
+        let limiter = context.create_dynamics_compressor()?;
+
+        input.connect_with_audio_node(&eq_low)?;
+        eq_low.connect_with_audio_node(&eq_mid)?;
+        eq_mid.connect_with_audio_node(&eq_high)?;
+        eq_high.connect_with_audio_node(&comp)?;
+        eq_high.connect_with_audio_node(&convolver)?;
+        convolver.connect_with_audio_node(&reverb_gain)?;
+        reverb_gain.connect_with_audio_node(&comp)?;
+        comp.connect_with_audio_node(&limiter)?;
+        limiter.connect_with_audio_node(destination_target)?;
+
+        let mut chain = MasterChain {
+            input,
+            eq_low,
+            eq_mid,
+            eq_high,
+            convolver,
+            reverb_gain,
+            comp,
+            limiter,
+            effects: MasterEffects::default(),
+        };
+        chain.set_effects(context, &MasterEffects::default())?;
+        Ok(chain)
+    }
+
+    // 音はここにつなぐ
+    pub fn input(&self) -> &AudioNode {
+        &self.input
+    }
+
+    pub fn effects(&self) -> &MasterEffects {
+        &self.effects
+    }
+
+    pub fn set_effects(&mut self, context: &AudioContext, effects: &MasterEffects) -> Result<(), JsValue> {
+        self.eq_low.gain().set_value(effects.eq_low as f32);
+        self.eq_mid.gain().set_value(effects.eq_mid as f32);
+        self.eq_high.gain().set_value(effects.eq_high as f32);
+
+        // インパルス応答は残響の長さか吸われ方が変わったときだけ作り直す
+        let old = &self.effects;
+        let same_reverb = old.has_reverb() == effects.has_reverb() && old.reverb_time == effects.reverb_time && old.reverb_damping == effects.reverb_damping;
+        if !same_reverb {
+            let buffer = if effects.has_reverb() {
+                let rate = context.sample_rate();
+                let response = impulse_response(effects, rate as u32);
+                let buffer = context.create_buffer(2, response[0].len() as u32, rate)?;
+                for (channel, samples) in response.iter().enumerate() {
+                    buffer.copy_to_channel(samples, channel as i32)?;
+                }
+                Some(buffer)
+            } else {
+                None
+            };
+            self.convolver.set_buffer(buffer.as_ref());
+        }
+        self.reverb_gain.gain().set_value(if effects.has_reverb() { effects.reverb_mix as f32 } else { 0.0 });
+
+        self.comp.threshold().set_value(effects.comp_threshold as f32);
+        self.comp.knee().set_value(effects.comp_knee as f32);
+        self.comp.ratio().set_value(effects.comp_ratio as f32);
+        self.comp.attack().set_value(effects.comp_attack as f32);
+        self.comp.release().set_value(effects.comp_release as f32);
+
+        // リミッターを使わないときは比率1で素通しにする
+        self.limiter.threshold().set_value(if effects.limiter { effects.limiter_threshold as f32 } else { 0.0 });
+        self.limiter.knee().set_value(0.0);
+        self.limiter.ratio().set_value(if effects.limiter { LIMITER_RATIO as f32 } else { 1.0 });
+        self.limiter.attack().set_value(LIMITER_ATTACK as f32);
+        self.limiter.release().set_value(LIMITER_RELEASE as f32);
+
+        self.effects = *effects;
+        Ok(())
+    }
+}
+
+// 1音分のVCFとVCAと左右の位置 (とピアノのハンマーの音のフィルタ)
+// オシレーターは1回しか start できないので毎回作るが、こちらは鳴り終わったら次の音で使い回す
+pub struct VoiceChain {
     vcf: BiquadFilterNode,
     vca: GainNode,
-    now_time: f64,
-    end_time: f64,
+    // トラックの音量
+    level: GainNode,
+    panner: StereoPannerNode,
+    hammer_filter: BiquadFilterNode,
+    hammer_gain: GainNode,
+    hammer_noise: AudioBuffer,
 }
 
-impl SoundSource {
-    pub fn new(context: &AudioContext, destination_target: &AudioNode, key: u8, velocity: u8, start_time: f64, end_time: f64) -> Result<SoundSource, JsValue> {
-        let base_gain = Self::velocity_to_ratio(velocity);
-        let vca_a = 0.1;
-        let vca_d = 0.2;
-        let vca_s = 0.5 * base_gain;
-        let vca_r = 1.2;
-        let end_time = end_time.max(start_time + vca_a + vca_d);
-
-        let freq = Self::midi_key_to_freq(key);
-        let vco = context.create_oscillator()?;
-        vco.set_type(OscillatorType::Sawtooth);
-        vco.frequency().set_value(freq);
-
+impl VoiceChain {
+    pub fn new(context: &AudioContext, destination_target: &AudioNode, hammer_noise: &AudioBuffer) -> Result<VoiceChain, JsValue> {
         let vcf = context.create_biquad_filter()?;
         vcf.set_type(BiquadFilterType::Lowpass);
-        vcf.frequency().set_value((freq * 4.0).min(10000.0));
-        vcf.frequency().linear_ramp_to_value_at_time(freq * 0.5, end_time)?;
-        
         let vca = context.create_gain()?;
-        vca.gain().set_value_at_time(0.0, start_time)?;
-        vca.gain().linear_ramp_to_value_at_time(base_gain, start_time + vca_a)?;
-        vca.gain().linear_ramp_to_value_at_time(vca_s, start_time + vca_a + vca_d)?;
-        vca.gain().linear_ramp_to_value_at_time(vca_s, end_time)?;
-        vca.gain().linear_ramp_to_value_at_time(0.0001, end_time + vca_r)?;
-        
-        vco.connect_with_audio_node(&vcf)?;
+        vca.gain().set_value(0.0);
+        let level = context.create_gain()?;
+        let panner = context.create_stereo_panner()?;
         vcf.connect_with_audio_node(&vca)?;
-        vca.connect_with_audio_node(destination_target)?;
-        vco.start_with_when(start_time)?;
+        vca.connect_with_audio_node(&level)?;
+        level.connect_with_audio_node(&panner)?;
+        panner.connect_with_audio_node(destination_target)?;
 
-        Ok(SoundSource {
-            vco,
+        let hammer_filter = context.create_biquad_filter()?;
+        hammer_filter.set_type(BiquadFilterType::Bandpass);
+        let hammer_gain = context.create_gain()?;
+        hammer_filter.connect_with_audio_node(&hammer_gain)?;
+        hammer_gain.connect_with_audio_node(&vca)?;
+
+        Ok(VoiceChain {
             vcf,
             vca,
-            now_time: start_time,
-            end_time: end_time,
+            level,
+            panner,
+            hammer_filter,
+            hammer_gain,
+            hammer_noise: hammer_noise.clone(),
+        })
+    }
+}
+
+impl Drop for VoiceChain {
+    fn drop(&mut self) {
+        self.vcf.disconnect().unwrap();
+        self.vca.disconnect().unwrap();
+        self.level.disconnect().unwrap();
+        self.panner.disconnect().unwrap();
+        self.hammer_filter.disconnect().unwrap();
+        self.hammer_gain.disconnect().unwrap();
+    }
+}
+
+// SoundFontの1つのゾーンのサンプル
+pub struct SampleBuffer {
+    pub zone: Zone,
+    pub buffer: AudioBuffer,
+}
+
+impl SampleBuffer {
+    // サンプルの周波数のままバッファにする (再生するときにWeb Audioが変換する)
+    pub fn new(context: &AudioContext, preset: &Preset, zone: &Zone) -> Result<SampleBuffer, JsValue> {
+        let data = preset.sample_data(zone);
+        let buffer = context.create_buffer(1, data.len() as u32, zone.sample_rate as f32)?;
+        buffer.copy_to_channel(data, 0)?;
+        Ok(SampleBuffer {
+            zone: zone.clone(),
+            buffer,
+        })
+    }
+}
+
+pub struct SoundSource {
+    // ユニゾンの数 (ピアノなら倍音の数) だけ重ねる
+    vcos: Vec<OscillatorNode>,
+    // ピアノの倍音ごとの減衰 (サンプルならゾーンごとの音量)
+    partial_gains: Vec<GainNode>,
+    // ピアノのハンマーの音やSoundFontのサンプル
+    buffer_sources: Vec<AudioBufferSourceNode>,
+    // into_chain で取り出したら None
+    chain: Option<VoiceChain>,
+    // 鳴らしているノート (ミキサーの音量を後から変えるときに使う)
+    note: Note,
+    start_time: f64,
+    vca_envelope: Vec<(f64, f64)>,
+    end_time: f64,
+    released: bool,
+}
+
+fn set_envelope(param: &AudioParam, points: &[(f64, f64)]) -> Result<(), JsValue>{
+    // 途中で書き換えるときも同じ折れ線を最初から入れ直せば、今の値から飛ばない
+    param.cancel_scheduled_values(0.0)?;
+    param.set_value_at_time(points[0].1 as f32, points[0].0)?;
+    for &(time, value) in points.iter().skip(1){
+        param.linear_ramp_to_value_at_time(value as f32, time)?;
+    }
+    Ok(())
+}
+
+impl SoundSource {
+    // offset は鳴り始めてからの経過時間 (途中から鳴らすとき)
+    // playback_rate は再生速度で、遅くするほどエンベロープやピアノの減衰を伸ばす
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(context: &AudioContext, chain: VoiceChain, patch: &SynthPatch, note: &Note, start_time: f64, end_time: f64, offset: f64, playback_rate: f64) -> Result<SoundSource, JsValue> {
+        let patch = &patch.at_rate(playback_rate);
+        let base_gain = patch.velocity_to_gain(note.velocity());
+        let freq = Self::midi_key_to_freq(note.key());
+        let vca_envelope = vca_envelope(patch, base_gain, start_time, end_time, offset);
+        let end_time = vca_off_time(patch, start_time, end_time, offset);
+
+        let mut vcos = Vec::new();
+        let mut partial_gains = Vec::new();
+        let mut buffer_sources = Vec::new();
+        match patch.voice{
+            VoiceModel::Subtractive => {
+                for i in 0..patch.num_oscillators(){
+                    let vco = context.create_oscillator()?;
+                    vco.set_type(match patch.waveform{
+                        Waveform::Sine => OscillatorType::Sine,
+                        Waveform::Square => OscillatorType::Square,
+                        Waveform::Sawtooth => OscillatorType::Sawtooth,
+                        Waveform::Triangle => OscillatorType::Triangle,
+                    });
+                    vco.frequency().set_value(freq);
+                    vco.detune().set_value(patch.detune_cents(i) as f32);
+                    vco.connect_with_audio_node(&chain.vcf)?;
+                    vco.start_with_when(start_time)?;
+                    vcos.push(vco);
+                }
+            },
+            VoiceModel::Piano => {
+                for partial in piano::partials(note.key(), note.velocity(), freq as f64, playback_rate){
+                    let vco = context.create_oscillator()?;
+                    vco.set_type(OscillatorType::Sine);
+                    vco.frequency().set_value(partial.freq as f32);
+                    let gain = context.create_gain()?;
+                    gain.gain().set_value_at_time(piano::partial_amplitude(&partial, offset) as f32, start_time)?;
+                    gain.gain().set_target_at_time(0.0, start_time, partial.decay)?;
+                    vco.connect_with_audio_node(&gain)?;
+                    gain.connect_with_audio_node(&chain.vcf)?;
+                    vco.start_with_when(start_time)?;
+                    vcos.push(vco);
+                    partial_gains.push(gain);
+                }
+                // 途中から鳴らすときはハンマーの音はもう聞こえない
+                if offset <= 0.0{
+                    let source = context.create_buffer_source()?;
+                    source.set_buffer(Some(&chain.hammer_noise));
+                    chain.hammer_filter.frequency().set_value(piano::hammer_freq(freq as f64) as f32);
+                    chain.hammer_gain.gain().set_value(piano::hammer_gain(note.velocity()) as f32);
+                    source.connect_with_audio_node(&chain.hammer_filter)?;
+                    source.start_with_when(start_time)?;
+                    buffer_sources.push(source);
+                }
+            },
+        }
+
+        chain.vcf.q().set_value(patch.resonance as f32);
+        set_envelope(&chain.vcf.frequency(), &vcf_envelope(patch, note.velocity(), freq as f64, start_time, end_time, offset))?;
+        set_envelope(&chain.vca.gain(), &vca_envelope)?;
+
+        Ok(SoundSource {
+            vcos,
+            partial_gains,
+            buffer_sources,
+            chain: Some(chain),
+            note: *note,
+            start_time,
+            vca_envelope,
+            end_time,
+            released: false,
+        })
+    }
+
+    // SoundFontのサンプルで鳴らす (VCFは通さずに、ゾーンのエンベロープをVCAにかける)
+    // ステレオの左右などで複数のゾーンが当たったときは混ぜて鳴らす
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_samples(context: &AudioContext, chain: VoiceChain, samples: &[&SampleBuffer], note: &Note, start_time: f64, end_time: f64, offset: f64, playback_rate: f64) -> Result<SoundSource, JsValue> {
+        let vca_envelope = samples[0].zone.vca_envelope(note.key(), 1.0, start_time, end_time, offset, playback_rate);
+        let stop_time = vca_envelope.last().map(|point| point.0).unwrap_or(end_time);
+
+        let mut partial_gains = Vec::new();
+        let mut buffer_sources = Vec::new();
+        for sample in samples.iter(){
+            let zone = &sample.zone;
+            let ratio = zone.pitch_ratio(note.key());
+            // 鳴らし直すときに、ループしないサンプルがもう終わっていたら鳴らさない
+            let Some(position) = zone.position(note.key(), offset) else{
+                continue;
+            };
+            let source = context.create_buffer_source()?;
+            source.set_buffer(Some(&sample.buffer));
+            source.playback_rate().set_value(ratio as f32);
+            if zone.looping{
+                source.set_loop(true);
+                source.set_loop_start((zone.loop_start - zone.start) as f64 / zone.sample_rate as f64);
+                source.set_loop_end((zone.loop_end - zone.start) as f64 / zone.sample_rate as f64);
+            }
+            let gain = context.create_gain()?;
+            gain.gain().set_value((zone.gain(note.velocity()) / samples.len() as f64) as f32);
+            source.connect_with_audio_node(&gain)?;
+            gain.connect_with_audio_node(&chain.vca)?;
+            source.start_with_when_and_grain_offset(start_time, position / zone.sample_rate as f64)?;
+            AsRef::<AudioScheduledSourceNode>::as_ref(&source).stop_with_when(stop_time)?;
+            buffer_sources.push(source);
+            partial_gains.push(gain);
+        }
+        set_envelope(&chain.vca.gain(), &vca_envelope)?;
+
+        Ok(SoundSource {
+            vcos: Vec::new(),
+            partial_gains,
+            buffer_sources,
+            chain: Some(chain),
+            note: *note,
+            start_time,
+            vca_envelope,
+            end_time: end_time.max(start_time + VCA_RETRIGGER_ATTACK),
+            released: false,
         })
     }
 
-    fn velocity_to_ratio(velocity: u8) -> f32{
-        velocity as f32 / 127.0
+    // 左右の位置 (-1が左端、1が右端) 使い回したチェインなので鳴り始めから切り替える
+    pub fn set_pan(&self, pan: f64) -> Result<(), JsValue>{
+        let param = self.chain().panner.pan();
+        param.cancel_scheduled_values(0.0)?;
+        param.set_value_at_time(pan as f32, self.start_time)?;
+        Ok(())
+    }
+
+    pub fn set_level(&self, level: f64) -> Result<(), JsValue>{
+        let param = self.chain().level.gain();
+        param.cancel_scheduled_values(0.0)?;
+        param.set_value_at_time(level as f32, self.start_time)?;
+        Ok(())
+    }
+
+    // 鳴っている途中で音量を変える (スライダーを動かしてもプツッといわないように少しずつ)
+    pub fn change_level(&self, level: f64, time: f64) -> Result<(), JsValue>{
+        if time <= self.start_time{
+            return self.set_level(level);
+        }
+        let param = self.chain().level.gain();
+        param.cancel_scheduled_values(time)?;
+        param.set_target_at_time(level as f32, time, LEVEL_CHANGE_TIME)?;
+        Ok(())
+    }
+
+    pub fn note(&self) -> &Note{
+        &self.note
     }
 
-    pub fn tick(&mut self, delta_sec: f64) {
-        self.now_time += delta_sec;
+    // 鳴り終わったらオシレーターだけ外してVCFとVCAを返す
+    // 発振器とサンプルは Drop で切り離して、使い回すフィルタとアンプだけ返す
+    pub fn into_chain(mut self) -> VoiceChain {
+        self.chain.take().unwrap()
     }
 
-    pub fn finished(&self) -> bool {
-        self.now_time >= self.end_time && self.vca.gain().value() <= 0.001
+    fn chain(&self) -> &VoiceChain {
+        self.chain.as_ref().unwrap()
     }
 
-    fn midi_key_to_freq(key: u8) -> f32 {
+    // time から短いフェードで止める (まだ鳴り始めていなければ鳴らさない)
+    // 切断は鳴り終わってから update で行うのでプツッといわない
+    pub fn release(&mut self, time: f64) -> Result<(), JsValue>{
+        let fade_end = time + VCA_FADE_OUT;
+        if self.vca_envelope.last().is_some_and(|point| point.0 <= fade_end){
+            self.released = true;
+            return Ok(());
+        }
+        self.vca_envelope = fade_out(&self.vca_envelope, time);
+        set_envelope(&self.chain().vca.gain(), &self.vca_envelope)?;
+        for vco in self.vcos.iter(){
+            vco.stop_with_when(fade_end)?;
+        }
+        for source in self.buffer_sources.iter(){
+            AsRef::<AudioScheduledSourceNode>::as_ref(source).stop_with_when(fade_end)?;
+        }
+        self.end_time = self.end_time.min(fade_end);
+        self.released = true;
+        Ok(())
+    }
+
+    pub fn finished(&self, now_time: f64) -> bool {
+        now_time >= self.end_time && self.chain().vca.gain().value() <= 0.001
+    }
+
+    pub fn midi_key_to_freq(key: u8) -> f32 {
         27.5 * 2f32.powf((key as f32 - 21.0) / 12.0)
     }
 }
 
 impl Drop for SoundSource {
     fn drop(&mut self) {
-        self.vco.disconnect().unwrap();
-        self.vcf.disconnect().unwrap();
-        self.vca.disconnect().unwrap();
+        for vco in self.vcos.iter(){
+            vco.disconnect().unwrap();
+        }
+        for gain in self.partial_gains.iter(){
+            gain.disconnect().unwrap();
+        }
+        for source in self.buffer_sources.iter(){
+            source.disconnect().unwrap();
+        }
     }
-}
\ No newline at end of file
+}
+
+impl VoiceState for SoundSource {
+    fn key(&self) -> u8 {
+        self.note.key()
+    }
+
+    fn start_time(&self) -> f64 {
+        self.start_time
+    }
+
+    fn end_time(&self) -> f64 {
+        self.vca_envelope.last().map(|point| point.0).unwrap_or(self.end_time)
+    }
+
+    fn released(&self) -> bool {
+        self.released
+    }
+
+    fn level(&self, time: f64) -> f64 {
+        interpolate(&self.vca_envelope, time)
+    }
+}
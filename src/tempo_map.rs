@@ -0,0 +1,34 @@
+// 四分音符単位の位置から秒への変換テーブル
+// MusicXMLやABCのように拍位置で書かれている形式を読み込むときに使う
+pub struct TempoMap{
+    // (四分音符位置, BPM) 位置順に並んでいること
+    points: Vec<(f64, f64)>,
+}
+
+impl TempoMap{
+    pub fn new(mut points: Vec<(f64, f64)>) -> Self{
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        if points.first().is_none_or(|point| point.0 > 0.0){
+            points.insert(0, (0.0, 120.0));
+        }
+        TempoMap{
+            points,
+        }
+    }
+
+    pub fn to_sec(&self, quarters: f64) -> f64{
+        let mut sec = 0.0;
+        for (i, &(begin, bpm)) in self.points.iter().enumerate(){
+            let end = match self.points.get(i + 1){
+                Some(next) if next.0 < quarters => next.0,
+                _ => return sec + (quarters - begin) * 60.0 / bpm,
+            };
+            sec += (end - begin) * 60.0 / bpm;
+        }
+        sec
+    }
+
+    pub fn bpm_at(&self, quarters: f64) -> f64{
+        self.points.iter().rev().find(|point| point.0 <= quarters).map(|point| point.1).unwrap_or(120.0)
+    }
+}
@@ -0,0 +1,25 @@
+// トラックごとの設定 (設定していないトラックは T::default())
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PerTrack<T>{
+    tracks: Vec<T>,
+}
+
+impl<T: Clone + Default> PerTrack<T>{
+    pub fn get(&self, track: u8) -> T{
+        self.tracks.get(track as usize).cloned().unwrap_or_default()
+    }
+
+    pub fn set(&mut self, track: u8, value: T){
+        let track = track as usize;
+        if self.tracks.len() <= track{
+            self.tracks.resize(track + 1, T::default());
+        }
+        self.tracks[track] = value;
+    }
+
+    // 設定したトラックの値 (設定していないトラックは含まない)
+    pub fn iter(&self) -> impl Iterator<Item = &T>{
+        self.tracks.iter()
+    }
+}
@@ -0,0 +1,60 @@
+use crate::clock::{MAX_PLAYBACK_RATE, MIN_PLAYBACK_RATE};
+use wasm_bindgen::prelude::*;
+
+// ループするたびに再生速度を step ずつ変えて target_rate まで近づける練習
+// limit 回弾いたらループを抜けてそのまま先に進む
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SpeedTrainer{
+    pub start_rate: f64,
+    // マイナスなら遅くしていく
+    pub step: f64,
+    pub target_rate: f64,
+    pub limit: Option<u32>,
+}
+
+impl SpeedTrainer{
+    // iteration 回折り返した後の再生速度
+    pub fn rate(&self, iteration: u32) -> f64{
+        let rate = self.start_rate + self.step * iteration as f64;
+        let rate = if self.step >= 0.0 { rate.min(self.target_rate) } else { rate.max(self.target_rate) };
+        rate.clamp(MIN_PLAYBACK_RATE, MAX_PLAYBACK_RATE)
+    }
+}
+
+// ループを折り返した回数
+// スケジューラだけが数えて、再生位置はスケジューラが折り返したところに合わせる
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LoopCounter{
+    iteration: u32,
+    trainer: Option<SpeedTrainer>,
+}
+
+impl LoopCounter{
+    pub fn new(trainer: Option<SpeedTrainer>) -> Self{
+        LoopCounter{
+            iteration: 0,
+            trainer,
+        }
+    }
+
+    pub fn iteration(&self) -> u32{
+        self.iteration
+    }
+
+    pub fn trainer(&self) -> Option<SpeedTrainer>{
+        self.trainer
+    }
+
+    // まだ折り返すか (回数の上限まで弾いたらループを抜ける)
+    pub fn repeats(&self) -> bool{
+        self.trainer.and_then(|trainer| trainer.limit).is_none_or(|limit| self.iteration + 1 < limit)
+    }
+
+    // 折り返して、次の回の再生速度を返す (速さを変えないときは None)
+    pub fn advance(&mut self) -> Option<f64>{
+        self.iteration += 1;
+        self.trainer.map(|trainer| trainer.rate(self.iteration))
+    }
+}
@@ -27,9 +27,13 @@ impl<'a> Drop for Timer<'a> {
 }
 */
 // A macro to provide `println!(..)`-style syntax for `console.log` logging.
+// Falls back to stderr on native targets where there is no console to log to.
 #[macro_export]
 macro_rules! log {
     ( $( $t:tt )* ) => {
+        #[cfg(target_arch = "wasm32")]
         web_sys::console::log_1(&format!( $( $t )* ).into());
+        #[cfg(not(target_arch = "wasm32"))]
+        eprintln!( $( $t )* );
     }
 }
\ No newline at end of file
@@ -0,0 +1,106 @@
+use crate::note::Note;
+use crate::track::PerTrack;
+
+// 曲線の曲がり具合 (大きいほど弱い音が持ち上がる / 沈む)
+const CURVE_STRENGTH: f64 = 20.0;
+
+pub const CURVE_NAMES: [&str; 3] = ["linear", "log", "exp"];
+
+// MIDIのベロシティを鳴らす強さに変換する曲線
+// 音量にも、ピアノの倍音やフィルタの明るさにも、画面のノートの明るさにも変換した後の値を使う
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "VelocityCurveJson"))]
+pub enum VelocityCurve{
+    // そのまま
+    #[default]
+    Linear,
+    // 弱い音を持ち上げる (小さく録音されたMIDI向け)
+    Logarithmic,
+    // 弱い音をより弱くする
+    Exponential,
+    // 強さに関係なく同じ強さ
+    Fixed(u8),
+    // 0から127までの変換表
+    Custom(Vec<u8>),
+}
+
+// 読み込んだ変換表の長さを確かめてから VelocityCurve にする
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+enum VelocityCurveJson{
+    Linear,
+    Logarithmic,
+    Exponential,
+    Fixed(u8),
+    Custom(Vec<u8>),
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<VelocityCurveJson> for VelocityCurve{
+    type Error = String;
+
+    fn try_from(curve: VelocityCurveJson) -> Result<VelocityCurve, String>{
+        Ok(match curve{
+            VelocityCurveJson::Linear => VelocityCurve::Linear,
+            VelocityCurveJson::Logarithmic => VelocityCurve::Logarithmic,
+            VelocityCurveJson::Exponential => VelocityCurve::Exponential,
+            VelocityCurveJson::Fixed(velocity) => VelocityCurve::Fixed(velocity),
+            VelocityCurveJson::Custom(table) => VelocityCurve::custom(&table)?,
+        })
+    }
+}
+
+impl VelocityCurve{
+    // "linear", "log", "exp" の名前から
+    pub fn from_name(name: &str) -> Option<VelocityCurve>{
+        match name{
+            "linear" => Some(VelocityCurve::Linear),
+            "log" => Some(VelocityCurve::Logarithmic),
+            "exp" => Some(VelocityCurve::Exponential),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str{
+        match self{
+            VelocityCurve::Linear => "linear",
+            VelocityCurve::Logarithmic => "log",
+            VelocityCurve::Exponential => "exp",
+            VelocityCurve::Fixed(_) => "fixed",
+            VelocityCurve::Custom(_) => "custom",
+        }
+    }
+
+    // 変換表は128個で、値は127まで
+    pub fn custom(table: &[u8]) -> Result<VelocityCurve, String>{
+        if table.len() != 128{
+            return Err(format!("変換表は128個必要です: {}個", table.len()));
+        }
+        Ok(VelocityCurve::Custom(table.iter().map(|&velocity| velocity.min(127)).collect()))
+    }
+
+    pub fn apply(&self, velocity: u8) -> u8{
+        let velocity = velocity.min(127);
+        let x = velocity as f64 / 127.0;
+        let y = match self{
+            VelocityCurve::Linear => return velocity,
+            VelocityCurve::Logarithmic => (1.0 + CURVE_STRENGTH * x).ln() / (1.0 + CURVE_STRENGTH).ln(),
+            VelocityCurve::Exponential => ((1.0 + CURVE_STRENGTH).powf(x) - 1.0) / CURVE_STRENGTH,
+            VelocityCurve::Fixed(fixed) => return (*fixed).min(127),
+            VelocityCurve::Custom(table) => return table.get(velocity as usize).copied().unwrap_or(velocity),
+        };
+        // 鳴っていた音が消えないように1は残す
+        ((y * 127.0).round() as u8).clamp(velocity.min(1), 127)
+    }
+}
+
+// トラックごとのベロシティ曲線
+pub type TrackVelocityCurves = PerTrack<VelocityCurve>;
+
+// 曲線をかけた強さのノート
+pub fn apply_velocity_curve(note: &Note, curves: &TrackVelocityCurves) -> Note{
+    let mut note = *note;
+    note.set_velocity(curves.get(note.track()).apply(note.velocity()));
+    note
+}
@@ -0,0 +1,141 @@
+use crate::dsp::OfflineEngine;
+use crate::engine::AudioEngine;
+use crate::patch::TrackPatches;
+use crate::raster::RasterBackend;
+use crate::rectangle::Rectangle;
+use crate::render::{render_scene, RenderOptions};
+use crate::song::Song;
+use crate::effects::MasterEffects;
+use crate::mixer::Mixer;
+use crate::stereo::StereoSettings;
+use crate::velocity::TrackVelocityCurves;
+use crate::wav::encode_wav;
+
+// 練習動画を作るための連番フレーム書き出し
+// フレームの時刻は start_time + i / fps で決まるので何度やっても同じ結果になる
+
+#[derive(Clone, Debug)]
+pub struct VideoSettings{
+    pub width: usize,
+    pub height: usize,
+    pub fps: f64,
+    pub start_time: f64,
+    pub end_time: f64,
+    pub display_range_sec: f64,
+    pub sample_rate: u32,
+    pub volume: f32,
+    pub patches: TrackPatches,
+    pub velocity_curves: TrackVelocityCurves,
+    pub stereo: StereoSettings,
+    pub mixer: Mixer,
+    pub effects: MasterEffects,
+    // ミュートされたノートを暗くするか
+    pub dim_muted: bool,
+    // 拍のランプを描くか
+    pub beat_flash: bool,
+}
+
+impl VideoSettings{
+    pub fn for_song(song: &Song) -> Self{
+        VideoSettings{
+            width: 1280,
+            height: 720,
+            fps: 30.0,
+            start_time: 0.0,
+            end_time: song.bars().last().map(|bar| bar.end_time()).unwrap_or(0.0),
+            display_range_sec: 3.0,
+            sample_rate: 44100,
+            volume: 1.0,
+            patches: TrackPatches::default(),
+            velocity_curves: TrackVelocityCurves::default(),
+            stereo: StereoSettings::default(),
+            mixer: Mixer::default(),
+            effects: MasterEffects::default(),
+            dim_muted: true,
+            beat_flash: false,
+        }
+    }
+
+    pub fn num_frames(&self) -> usize{
+        ((self.end_time - self.start_time).max(0.0) * self.fps).ceil() as usize
+    }
+
+    pub fn frame_time(&self, frame: usize) -> f64{
+        self.start_time + frame as f64 / self.fps
+    }
+}
+
+// 1フレーム分のRGBA (width * height * 4バイト)
+pub fn render_frame(song: &Song, time: f64, display_range_sec: f64, width: usize, height: usize) -> Vec<u8>{
+    render_frame_with_options(song, &RenderOptions{ current_time: time, display_range_sec, ..Default::default() }, width, height)
+}
+
+fn render_frame_with_options(song: &Song, options: &RenderOptions, width: usize, height: usize) -> Vec<u8>{
+    let mut backend = RasterBackend::new(width, height);
+    let Ok(()) = render_scene(&mut backend, song, options, &Rectangle::new(0.0, 0.0, width as f64, height as f64));
+    backend.into_pixels()
+}
+
+pub struct Frames<'a>{
+    song: &'a Song,
+    settings: &'a VideoSettings,
+    next: usize,
+}
+
+impl Iterator for Frames<'_>{
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>>{
+        if self.next >= self.settings.num_frames(){
+            return None;
+        }
+        let time = self.settings.frame_time(self.next);
+        self.next += 1;
+        let options = RenderOptions{
+            current_time: time,
+            display_range_sec: self.settings.display_range_sec,
+            velocity_curves: self.settings.velocity_curves.clone(),
+            mixer: self.settings.mixer.clone(),
+            dim_muted: self.settings.dim_muted,
+            beat_flash: self.settings.beat_flash,
+        };
+        Some(render_frame_with_options(self.song, &options, self.settings.width, self.settings.height))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>){
+        let remain = self.settings.num_frames().saturating_sub(self.next);
+        (remain, Some(remain))
+    }
+}
+
+// フレームは必要になったときに1枚ずつ作るのでそのままエンコーダーに流せる
+pub fn render_frames<'a>(song: &'a Song, settings: &'a VideoSettings) -> Frames<'a>{
+    Frames{
+        song,
+        settings,
+        next: 0,
+    }
+}
+
+// フレームと同じ範囲の音声 (モノラルのWAV)
+pub fn render_video_audio(song: &Song, settings: &VideoSettings) -> Vec<u8>{
+    let end_time = settings.frame_time(settings.num_frames());
+    encode_wav(&video_engine(settings).render_song(song, settings.start_time, end_time), 1, settings.sample_rate)
+}
+
+// フレームと同じ範囲の音声 (ステレオのWAV)
+pub fn render_video_audio_stereo(song: &Song, settings: &VideoSettings) -> Vec<u8>{
+    let end_time = settings.frame_time(settings.num_frames());
+    encode_wav(&video_engine(settings).render_song_stereo(song, settings.start_time, end_time), 2, settings.sample_rate)
+}
+
+fn video_engine(settings: &VideoSettings) -> OfflineEngine{
+    let mut engine = OfflineEngine::new(settings.sample_rate);
+    engine.set_volume(settings.volume);
+    engine.set_patches(settings.patches.clone());
+    engine.set_velocity_curves(settings.velocity_curves.clone());
+    engine.set_stereo(settings.stereo.clone());
+    engine.set_mixer(settings.mixer.clone());
+    engine.set_effects(settings.effects);
+    engine
+}
@@ -0,0 +1,23 @@
+// 16bit PCMのWAVファイルを作る
+// samplesはチャンネルごとにインターリーブされた -1.0..1.0 の値
+pub fn encode_wav(samples: &[f32], channels: u16, sample_rate: u32) -> Vec<u8>{
+    let data_size = (samples.len() * 2) as u32;
+    let block_align = channels * 2;
+    let mut out = Vec::with_capacity(44 + data_size as usize);
+    out.extend(b"RIFF");
+    out.extend((36 + data_size).to_le_bytes());
+    out.extend(b"WAVEfmt ");
+    out.extend(16u32.to_le_bytes());
+    out.extend(1u16.to_le_bytes());
+    out.extend(channels.to_le_bytes());
+    out.extend(sample_rate.to_le_bytes());
+    out.extend((sample_rate * block_align as u32).to_le_bytes());
+    out.extend(block_align.to_le_bytes());
+    out.extend(16u16.to_le_bytes());
+    out.extend(b"data");
+    out.extend(data_size.to_le_bytes());
+    for sample in samples.iter(){
+        out.extend(((sample.clamp(-1.0, 1.0) * 32767.0).round() as i16).to_le_bytes());
+    }
+    out
+}